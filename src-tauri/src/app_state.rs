@@ -1,19 +1,58 @@
+use std::collections::HashMap;
 use std::fmt;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use crate::encrypt::{DataKey, Locked, AES};
+use secrecy::ExposeSecret;
 use crate::file_system::FileSystem;
+use crate::session::{Clock, Session, SessionPolicy, SystemClock};
+use crate::yubikey::{Devices, PivBackend};
+
+/// Called when an expired session locks the vault.
+pub type LockListener = Box<dyn Fn() + Send>;
 
-#[derive(Default)]
 pub struct ProductionState {
-    master_password: Option<String>,
+    master_key: Option<Locked<AES>>,
     authenticated: bool,
+    yubikey_authenticated: bool,
+    vault_keys: HashMap<String, Locked<DataKey>>,
     fs: FileSystem,
+    piv: Devices,
+    session: Option<Session>,
+    session_policy: SessionPolicy,
+    clock: Arc<dyn Clock>,
+    on_lock: Option<LockListener>,
+}
+
+impl Default for ProductionState {
+    fn default() -> Self {
+        let fs = FileSystem::default();
+        Self {
+            master_key: None,
+            authenticated: false,
+            yubikey_authenticated: false,
+            vault_keys: HashMap::new(),
+            session_policy: SessionPolicy::load(&fs),
+            fs,
+            piv: Devices::default(),
+            session: None,
+            clock: Arc::new(SystemClock),
+            on_lock: None,
+        }
+    }
 }
 
 #[cfg(test)]
 pub struct TestState {
-    master_password: Option<String>,
+    master_key: Option<Locked<AES>>,
     authenticated: bool,
+    yubikey_authenticated: bool,
+    vault_keys: HashMap<String, Locked<DataKey>>,
     fs: FileSystem,
+    piv: crate::yubikey::VirtualBackend,
+    session: Option<Session>,
+    session_policy: SessionPolicy,
+    clock: Arc<dyn Clock>,
+    on_lock: Option<LockListener>,
     _temp_dir: tempfile::TempDir, // Keep temp_dir alive for test duration
 }
 
@@ -30,37 +69,56 @@ impl Default for AppState {
 }
 
 impl AppState {
+    #[cfg(test)]
+    pub const TEST_YUBIKEY_SERIAL: u32 = 10_000_001;
+
     #[cfg(test)]
     pub fn new_test(password: &str) -> Self {
-        use std::fs;
         use crate::encrypt::MasterPassword;
         // Initialize the empty state
         let mut state = Self::new_unauthenticated_test();
         // Save the master password and set authenticated to true
         MasterPassword::save(&mut state, password, None).unwrap();
         
-        // Yubikey default settings
-        let mut yubikey = crate::yubikey::YubiKeyInfo::default();
-        
-        fs::read("tests/fixtures/pubkey.pem").map(|key| {
-            yubikey.set_pub_key(String::from_utf8_lossy(&key).to_string());
-        }).unwrap();
-        yubikey.save(&state).unwrap();
+        // Yubikey default settings, from the virtual card seeded in `new_unauthenticated_test`
+        use crate::yubikey::{EnrolledKey, KeyAlgorithm, YubiKeySettings};
+        let yubikey = crate::yubikey::list_yubikeys(state.piv()).unwrap().remove(0);
+        let pub_key = yubikey.pub_key.unwrap();
+        let mut settings = YubiKeySettings::default();
+        settings.add(EnrolledKey::new(
+            yubikey.name,
+            Self::TEST_YUBIKEY_SERIAL,
+            KeyAlgorithm::from_pem(&pub_key).unwrap(),
+            pub_key,
+            yubikey.auth_pub_key,
+        ));
+        settings.save(&state).unwrap();
         state
     }
     
     #[cfg(test)]
     pub fn new_unauthenticated_test() -> Self {
         use std::fs;
+        use crate::yubikey::{VirtualBackend, VirtualCard};
         // Initialize a temp directory for testing
         let temp_dir = tempfile::TempDir::new().unwrap();
         let fs = FileSystem::new_test(temp_dir.path().to_path_buf());
         fs::create_dir_all(fs.root()).unwrap();
+        // A simulated YubiKey with the keys in tests/fixtures/piv
+        let piv = VirtualBackend::default();
+        piv.insert(VirtualCard::from_fixtures(Self::TEST_YUBIKEY_SERIAL, "tests/fixtures/piv").unwrap());
         // Initialize the empty state
         AppState::Test(TestState {
-            master_password: None,
+            master_key: None,
             authenticated: false,
+            yubikey_authenticated: false,
+            vault_keys: HashMap::new(),
             fs,
+            piv,
+            session: None,
+            session_policy: SessionPolicy::default(),
+            clock: Arc::new(crate::session::ManualClock::new()),
+            on_lock: None,
             _temp_dir: temp_dir,
         })
     }
@@ -71,27 +129,38 @@ impl AppState {
         Mutex::new(state)
     }
 
-    pub fn master_password(&self) -> Option<String> {
+    /// Key derived from the master password, the password itself is never kept.
+    pub fn master_key(&self) -> Option<&AES> {
+        let key = match self {
+            AppState::Production(state) => &state.master_key,
+            #[cfg(test)]
+            AppState::Test(state) => &state.master_key,
+        };
+        key.as_ref().map(|key| key.expose_secret())
+    }
+
+    pub fn has_master_key(&self) -> bool {
         match self {
-            AppState::Production(state) => state.master_password.clone(),
+            AppState::Production(state) => state.master_key.is_some(),
             #[cfg(test)]
-            AppState::Test(state) => state.master_password.clone(),
+            AppState::Test(state) => state.master_key.is_some(),
         }
     }
 
-    pub fn set_master_password(&mut self, password: String) {
+    pub fn set_master_key(&mut self, key: AES) {
         match self {
-            AppState::Production(state) => state.master_password = Some(password),
+            AppState::Production(state) => state.master_key = Some(Locked::new(key)),
             #[cfg(test)]
-            AppState::Test(state) => state.master_password = Some(password),
+            AppState::Test(state) => state.master_key = Some(Locked::new(key)),
         }
     }
 
-    pub fn unset_master_password(&mut self) {
+    /// Drops the master key, wiping it from memory.
+    pub fn unset_master_key(&mut self) {
         match self {
-            AppState::Production(state) => state.master_password = None,
+            AppState::Production(state) => state.master_key = None,
             #[cfg(test)]
-            AppState::Test(state) => state.master_password = None,
+            AppState::Test(state) => state.master_key = None,
         }
     }
 
@@ -103,11 +172,154 @@ impl AppState {
         }
     }
 
+    /// Unlocking starts a session, see `touch_session`.
     pub fn set_authenticated(&mut self, authenticated: bool) {
+        let now = self.now();
+        let session = authenticated.then(|| self.session().unwrap_or(Session::start(now)));
+        match self {
+            AppState::Production(state) => {
+                state.authenticated = authenticated;
+                state.session = session;
+            }
+            #[cfg(test)]
+            AppState::Test(state) => {
+                state.authenticated = authenticated;
+                state.session = session;
+            }
+        }
+    }
+
+    fn session(&self) -> Option<Session> {
+        match self {
+            AppState::Production(state) => state.session,
+            #[cfg(test)]
+            AppState::Test(state) => state.session,
+        }
+    }
+
+    fn session_mut(&mut self) -> Option<&mut Session> {
+        match self {
+            AppState::Production(state) => state.session.as_mut(),
+            #[cfg(test)]
+            AppState::Test(state) => state.session.as_mut(),
+        }
+    }
+
+    pub fn session_policy(&self) -> SessionPolicy {
+        match self {
+            AppState::Production(state) => state.session_policy,
+            #[cfg(test)]
+            AppState::Test(state) => state.session_policy,
+        }
+    }
+
+    pub fn set_session_policy(&mut self, policy: SessionPolicy) -> crate::Result<()> {
+        policy.save(self.file_system())?;
+        match self {
+            AppState::Production(state) => state.session_policy = policy,
+            #[cfg(test)]
+            AppState::Test(state) => state.session_policy = policy,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn now(&self) -> std::time::SystemTime {
+        match self {
+            AppState::Production(state) => state.clock.now(),
+            #[cfg(test)]
+            AppState::Test(state) => state.clock.now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        match self {
+            AppState::Production(state) => state.clock = clock,
+            AppState::Test(state) => state.clock = clock,
+        }
+    }
+
+    pub fn set_lock_listener(&mut self, listener: LockListener) {
+        match self {
+            AppState::Production(state) => state.on_lock = Some(listener),
+            #[cfg(test)]
+            AppState::Test(state) => state.on_lock = Some(listener),
+        }
+    }
+
+    /// Records activity in the session, unless it already expired: then the
+    /// vault is locked and false returned.
+    pub fn touch_session(&mut self) -> bool {
+        if self.expire_session() {
+            return false;
+        }
+        let now = self.now();
+        if let Some(session) = self.session_mut() {
+            session.touch(now);
+        }
+        true
+    }
+
+    /// Locks the vault and notifies the lock listener when the session is past
+    /// its idle timeout or lifetime. Returns true if it did.
+    pub fn expire_session(&mut self) -> bool {
+        let now = self.now();
+        let policy = self.session_policy();
+        if !self.session().is_some_and(|session| session.is_expired(&policy, now)) {
+            return false;
+        }
+        self.log_out();
+        let listener = match self {
+            AppState::Production(state) => &state.on_lock,
+            #[cfg(test)]
+            AppState::Test(state) => &state.on_lock,
+        };
+        if let Some(listener) = listener {
+            listener();
+        }
+        true
+    }
+
+    /// True once the enrolled YubiKey signed a challenge in this session.
+    pub fn is_yubikey_authenticated(&self) -> bool {
+        match self {
+            AppState::Production(state) => state.yubikey_authenticated,
+            #[cfg(test)]
+            AppState::Test(state) => state.yubikey_authenticated,
+        }
+    }
+
+    pub fn set_yubikey_authenticated(&mut self, authenticated: bool) {
+        match self {
+            AppState::Production(state) => state.yubikey_authenticated = authenticated,
+            #[cfg(test)]
+            AppState::Test(state) => state.yubikey_authenticated = authenticated,
+        }
+    }
+
+    /// Data key unwrapped by the YubiKey for a vault that opens without the password.
+    pub fn vault_key(&self, id: &str) -> Option<&DataKey> {
+        match self {
+            AppState::Production(state) => state.vault_keys.get(id).map(|key| key.expose_secret()),
+            #[cfg(test)]
+            AppState::Test(state) => state.vault_keys.get(id).map(|key| key.expose_secret()),
+        }
+    }
+
+    pub fn add_vault_key(&mut self, key: DataKey) {
         match self {
-            AppState::Production(state) => state.authenticated = authenticated,
+            AppState::Production(state) => state.vault_keys.insert(key.id().to_string(), Locked::new(key)),
             #[cfg(test)]
-            AppState::Test(state) => state.authenticated = authenticated,
+            AppState::Test(state) => state.vault_keys.insert(key.id().to_string(), Locked::new(key)),
+        };
+    }
+
+    // Dropping the keys wipes them from memory
+    fn clear_vault_keys(&mut self) {
+        match self {
+            AppState::Production(state) => state.vault_keys.clear(),
+            #[cfg(test)]
+            AppState::Test(state) => state.vault_keys.clear(),
         }
     }
 
@@ -119,9 +331,28 @@ impl AppState {
         }
     }
 
+    /// Where YubiKeys are found, the virtual card in tests.
+    pub fn piv(&self) -> &dyn PivBackend {
+        match self {
+            AppState::Production(state) => &state.piv,
+            #[cfg(test)]
+            AppState::Test(state) => &state.piv,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn virtual_piv(&self) -> &crate::yubikey::VirtualBackend {
+        match self {
+            AppState::Test(state) => &state.piv,
+            AppState::Production(_) => panic!("Production state has no virtual PIV cards"),
+        }
+    }
+
     pub fn log_out(&mut self) {
         self.set_authenticated(false);
-        self.unset_master_password();
+        self.unset_master_key();
+        self.set_yubikey_authenticated(false);
+        self.clear_vault_keys();
     }
 }
 
@@ -129,13 +360,17 @@ impl fmt::Debug for AppState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AppState::Production(state) => f.debug_struct("ProductionState")
-                .field("master_password", &state.master_password)
+                .field("master_key", &state.master_key)
                 .field("authenticated", &state.authenticated)
+                .field("yubikey_authenticated", &state.yubikey_authenticated)
+                .field("vault_keys", &state.vault_keys.len())
                 .finish(),
             #[cfg(test)]
             AppState::Test(state) => f.debug_struct("TestState")
-                .field("master_password", &state.master_password)
+                .field("master_key", &state.master_key)
                 .field("authenticated", &state.authenticated)
+                .field("yubikey_authenticated", &state.yubikey_authenticated)
+                .field("vault_keys", &state.vault_keys.len())
                 .finish(),
         }
     }
@@ -151,14 +386,15 @@ mod tests {
     fn test_production_state() {
         let state = AppState::default();
         assert!(!state.is_authenticated());
-        assert!(state.master_password().is_none());
+        assert!(!state.has_master_key());
     }
 
     #[test]
     fn test_test_state() {
         let state = AppState::new_test("test-password");
         assert!(state.is_authenticated());
-        assert_eq!(state.master_password().unwrap(), "test-password");
+        let master_pk = std::fs::read_to_string(state.file_system().master_pk()).unwrap();
+        assert!(state.master_key().unwrap().decrypt(&master_pk).is_ok());
     }
 
     #[test]
@@ -169,7 +405,76 @@ mod tests {
         state.set_authenticated(true);
         assert!(state.is_authenticated());
         
-        state.set_master_password("password".to_string());
-        assert_eq!(state.master_password().unwrap(), "password");
+        let key = AES::new("password").unwrap();
+        let encrypted = key.encrypt(b"data").unwrap();
+        state.set_master_key(key);
+        assert_eq!(state.master_key().unwrap().decrypt(&encrypted).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_debug_and_log_out_hide_the_key() {
+        let mut state = AppState::new_test("test-password");
+        let debug = format!("{:?}", state);
+        assert!(debug.contains("REDACTED"));
+        assert!(!debug.contains("test-password"));
+
+        state.log_out();
+        assert!(!state.has_master_key());
+        assert!(state.master_key().is_none());
+    }
+
+    #[test]
+    fn test_idle_session_locks() {
+        use crate::session::ManualClock;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::new());
+        let mut state = AppState::new_unauthenticated_test();
+        state.set_clock(clock.clone());
+        let locked = Arc::new(AtomicUsize::new(0));
+        let counter = locked.clone();
+        state.set_lock_listener(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        crate::MasterPassword::save(&mut state, "password", None).unwrap();
+        let idle = Duration::from_secs(state.session_policy().idle_timeout_secs);
+
+        clock.advance(idle - Duration::from_secs(1));
+        assert!(state.touch_session());
+        clock.advance(idle - Duration::from_secs(1));
+        assert!(!state.expire_session());
+        assert!(state.is_authenticated());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!state.touch_session());
+        assert!(!state.is_authenticated());
+        assert!(!state.has_master_key());
+        assert_eq!(locked.load(Ordering::SeqCst), 1);
+        // A locked vault has no session left to expire
+        assert!(state.touch_session());
+        assert_eq!(locked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_session_lifetime_is_absolute() {
+        use crate::session::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::new());
+        let mut state = AppState::new_unauthenticated_test();
+        state.set_clock(clock.clone());
+        crate::MasterPassword::save(&mut state, "password", None).unwrap();
+        let policy = SessionPolicy { idle_timeout_secs: 60, max_lifetime_secs: 120 };
+        state.set_session_policy(policy).unwrap();
+        assert_eq!(SessionPolicy::load(state.file_system()), policy);
+
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(40));
+            // Unlocking again does not extend the session
+            crate::MasterPassword::verify(&mut state, "password").unwrap();
+        }
+        assert!(!state.touch_session());
+        assert!(!state.is_authenticated());
     }
 }
@@ -1,50 +1,94 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use rand::{rngs::OsRng, RngCore};
-use crate::encrypt::{Error, Result};
-use crypto::digest::Digest;
-use crypto::sha3::Sha3;
+use crate::encrypt::{Encrypt, Error, Result, Kdf, Envelope, Cipher};
 use rand::thread_rng;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroize;
 
-#[derive(Debug)]
+// Key id recorded in the envelope of data encrypted with the master password key
+const MASTER_KEY_ID: &str = "master";
+
+/// AES-256-GCM key, wiped from memory when dropped.
+#[allow(clippy::upper_case_acronyms)]
 pub struct AES {
     key: [u8; 32],
-    salt: [u8; 16],
+    salt: Vec<u8>,
+    kdf: Option<Kdf>,
+    key_id: String,
 }
 
 impl AES {
-    pub fn new(password: &str) -> Self {
+    pub fn new(password: &str) -> Result<Self> {
+        Self::with_kdf(password, Kdf::default())
+    }
+
+    pub fn with_kdf(password: &str, kdf: Kdf) -> Result<Self> {
         let salt = Self::generate_salt();
-        let key = Self::derive_key(password, &salt);
-        Self { key, salt }
+        Self::from_salt(password, &salt, kdf)
+    }
+
+    pub fn from_salt(password: &str, salt: &[u8], kdf: Kdf) -> Result<Self> {
+        if salt.len() != 16 {
+            return Err(Error::Envelope(format!("Invalid salt length {}", salt.len())));
+        }
+        let key = kdf.derive_key(password, salt)?;
+        Ok(Self {
+            key,
+            salt: salt.to_vec(),
+            kdf: Some(kdf),
+            key_id: MASTER_KEY_ID.to_string(),
+        })
     }
 
-    pub fn from_salt(password: &str, salt: &[u8]) -> Self {
-        let mut salt_array = [0u8; 16];
-        salt_array.copy_from_slice(salt);
-        let key = Self::derive_key(password, &salt_array);
+    /// Uses a random key as is, e.g. a vault data key, recording `key_id` in every envelope.
+    pub fn from_key(key: [u8; 32], key_id: &str) -> Self {
         Self {
             key,
-            salt: salt_array,
+            salt: vec![],
+            kdf: None,
+            key_id: key_id.to_string(),
         }
     }
 
     pub fn from_encrypted(password: &str, encrypted: &str) -> Result<Self> {
-        let data = BASE64.decode(encrypted.as_bytes())?;
-        if data.len() < 16 {
-            return Err(Error::Base64(
-                "Invalid encrypted data length".to_string(),
-            ));
-        }
+        let envelope = Envelope::decode(encrypted)?;
+        let kdf = envelope.kdf.ok_or(Error::Envelope(
+            "Data was not encrypted with a password derived key".to_string(),
+        ))?;
+        Self::from_salt(password, &envelope.salt, kdf)
+    }
+
+    /// Independent key for `purpose`, derived from this one with HKDF-SHA256.
+    pub fn derive_key(&self, purpose: &str) -> Result<[u8; 32]> {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&self.salt), &self.key)
+            .expand(purpose.as_bytes(), &mut key)
+            .map_err(|e| Error::Kdf(e.to_string()))?;
+        Ok(key)
+    }
+
+    // Raw key, for the data key to wrap it
+    pub(super) fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    pub(super) fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// KDF the key was derived with, `None` for random keys.
+    pub fn kdf(&self) -> Option<Kdf> {
+        self.kdf
+    }
 
-        // Split into salt and encrypted data
-        let (salt, _) = data.split_at(16);
-        let mut salt_array = [0u8; 16];
-        salt_array.copy_from_slice(salt);
-        Ok(Self::from_salt(password, &salt_array))
+    /// Salt the key was derived with, empty for random keys.
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
     }
 
     fn generate_salt() -> [u8; 16] {
@@ -53,16 +97,24 @@ impl AES {
         salt
     }
 
-    fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
-        let mut hasher = Sha3::sha3_256();
-        hasher.input(password.as_bytes());
-        hasher.input(salt);
-        let mut key = [0u8; 32];
-        hasher.result(&mut key);
-        key
+    pub fn encrypt(&self, data: &[u8]) -> Result<String> {
+        self.seal(data)?.encode()
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<String> {
+    pub fn encrypt_string(&self, data: &str) -> Result<String> {
+        self.encrypt(data.as_bytes())
+    }
+
+    pub fn decrypt_string(&self, encoded: &str) -> Result<String> {
+        let data = self.decrypt(encoded)?;
+        Ok(String::from_utf8(data)?)
+    }
+
+    pub fn decrypt(&self, encoded: &str) -> Result<Vec<u8>> {
+        self.open(&Envelope::decode(encoded)?)
+    }
+
+    fn seal(&self, data: &[u8]) -> Result<Envelope> {
         let cipher = Aes256Gcm::new_from_slice(&self.key)
             .map_err(|e| Error::EncryptPassword(e.to_string()))?;
 
@@ -71,48 +123,26 @@ impl AES {
         OsRng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
 
-        // Encrypt the data
+        // Encrypt the data, authenticating the envelope header
+        let mut envelope = Envelope::new(Cipher::Aes256Gcm, self.kdf, &self.salt, &self.key_id);
         let ciphertext = cipher
-            .encrypt(nonce, data)
+            .encrypt(nonce, Payload { msg: data, aad: &envelope.aad() })
             .map_err(|e| Error::EncryptPassword(e.to_string()))?;
 
-        // Combine nonce and ciphertext and encode as base64
+        // Combine nonce and ciphertext
         let mut combined = nonce.to_vec();
         combined.extend_from_slice(&ciphertext);
-        let encrypted = combined;
-
-        // Combine salt and encrypted data
-        let mut combined = Vec::with_capacity(16 + encrypted.len());
-        combined.extend_from_slice(&self.salt);
-        combined.extend_from_slice(&encrypted);
-        Ok(BASE64.encode(combined))
-    }
-
-    pub fn encrypt_string(&self, data: &str) -> Result<String> {
-        self.encrypt(data.as_bytes())
+        envelope.payload = combined;
+        Ok(envelope)
     }
 
-    pub fn decrypt_string(&self, encoded: &str) -> Result<String> {
-        let data = self.decrypt(encoded)?;
-        Ok(String::from_utf8(data)?)
-    }
-
-    pub fn decrypt(&self, encoded: &str) -> Result<Vec<u8>> {
-        let data = BASE64.decode(encoded.as_bytes())?;
-        if data.len() < 16 {
-            return Err(Error::DecryptPassword(
-                "Invalid encrypted data length".to_string(),
-            ));
+    fn open(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        if envelope.cipher != Cipher::Aes256Gcm {
+            return Err(Error::Envelope(format!("Unexpected cipher {:?}", envelope.cipher)));
         }
+        let cipher = Aes256Gcm::new_from_slice(&self.key).map_err(|e| Error::EncryptPassword(e.to_string()))?;
 
-        // Split into salt and encrypted data
-        let (salt, encrypted) = data.split_at(16);
-        let mut salt_array = [0u8; 16];
-        salt_array.copy_from_slice(salt);
-        let key = self.key;
-
-        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|e| Error::EncryptPassword(e.to_string()))?;
-
+        let encrypted = &envelope.payload;
         if encrypted.len() < 12 {
             return Err(Error::DecryptPassword(
                 "Invalid encrypted data length".to_string(),
@@ -125,19 +155,53 @@ impl AES {
 
         // Decrypt the data
         let plaintext = cipher
-            .decrypt(nonce, ciphertext)
+            .decrypt(nonce, Payload { msg: ciphertext, aad: &envelope.aad() })
             .map_err(|e| Error::DecryptPassword(e.to_string()))?;
         Ok(plaintext)
     }
 }
 
+impl Zeroize for AES {
+    fn zeroize(&mut self) {
+        self.key.zeroize();
+        self.salt.zeroize();
+    }
+}
+
+impl Drop for AES {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl fmt::Debug for AES {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AES")
+            .field("key", &"[REDACTED]")
+            .field("kdf", &self.kdf)
+            .field("key_id", &self.key_id)
+            .finish()
+    }
+}
+
+impl Encrypt for AES {
+    fn encrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.seal(data)?.to_bytes()
+    }
+
+    fn decrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.open(&Envelope::from_bytes(data)?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 
     #[test]
     fn test_encrypt_decrypt_cycle() {
-        let encryptor = AES::new("test-password");
+        let encryptor = AES::new("test-password").unwrap();
         let original_data = b"Hello, World!";
 
         let encrypted = encryptor.encrypt(original_data).unwrap();
@@ -148,8 +212,8 @@ mod tests {
 
     #[test]
     fn test_different_passwords_produce_different_results() {
-        let encryptor1 = AES::new("password1");
-        let encryptor2 = AES::new("password2");
+        let encryptor1 = AES::new("password1").unwrap();
+        let encryptor2 = AES::new("password2").unwrap();
         let data = b"test data";
 
         let encrypted1 = encryptor1.encrypt(data).unwrap();
@@ -160,8 +224,8 @@ mod tests {
 
     #[test]
     fn test_wrong_password_fails_decryption() {
-        let encryptor1 = AES::new("correct-password");
-        let encryptor2 = AES::new("wrong-password");
+        let encryptor1 = AES::new("correct-password").unwrap();
+        let encryptor2 = AES::new("wrong-password").unwrap();
 
         let data = b"sensitive information";
         let encrypted = encryptor1.encrypt(data).unwrap();
@@ -171,7 +235,7 @@ mod tests {
 
     #[test]
     fn test_corrupted_data() {
-        let encryptor = AES::new("password");
+        let encryptor = AES::new("password").unwrap();
         let data = b"test data";
 
         let encrypted = encryptor.encrypt(data).unwrap();
@@ -182,7 +246,7 @@ mod tests {
 
     #[test]
     fn test_invalid_base64() {
-        let encryptor = AES::new("password");
+        let encryptor = AES::new("password").unwrap();
         assert!(matches!(
             encryptor.decrypt("not-base64!@#$"),
             Err(Error::Base64(_))
@@ -191,7 +255,7 @@ mod tests {
 
     #[test]
     fn test_same_data_different_encryption() {
-        let encryptor = AES::new("password");
+        let encryptor = AES::new("password").unwrap();
         let data = b"test data";
 
         let encrypted1 = encryptor.encrypt(data).unwrap();
@@ -203,8 +267,8 @@ mod tests {
     fn test_verify_password() {
         let good_password = "pasword";
         let wrong_password = "wrong-password";
-        let good_encryptor = AES::new(good_password);
-        let bad_encryptor = AES::new(wrong_password);
+        let good_encryptor = AES::new(good_password).unwrap();
+        let bad_encryptor = AES::new(wrong_password).unwrap();
         let good_encrypted = good_encryptor.encrypt(b"password").unwrap();
 
         assert!(bad_encryptor.decrypt(&good_encrypted).is_err())
@@ -213,7 +277,7 @@ mod tests {
     #[test]
     fn test_good_password_is_successfuly_decrypted() {
         let good_password = "pasword";
-        let good_encryptor = AES::new(good_password);
+        let good_encryptor = AES::new(good_password).unwrap();
         let encrypted = good_encryptor.encrypt(good_password.as_bytes()).unwrap();
         let decrypted = good_encryptor.decrypt(&encrypted).unwrap();
         assert_eq!(good_password.as_bytes(), decrypted);
@@ -222,10 +286,70 @@ mod tests {
     #[test]
     fn test_good_password_is_decripted_from_different_decryptor() {
         let good_password = "pasword";
-        let good_encryptor = AES::new(good_password);
+        let good_encryptor = AES::new(good_password).unwrap();
         let encrypted = good_encryptor.encrypt(good_password.as_bytes()).unwrap();
         let good_encryptor2 = AES::from_encrypted(good_password, &encrypted).unwrap();
         let decrypted = good_encryptor2.decrypt(&encrypted).unwrap();
         assert_eq!(good_password.as_bytes(), decrypted);
     }
+
+    #[test]
+    fn test_kdf_is_stored_with_ciphertext() {
+        let kdf = Kdf::Argon2id(crate::encrypt::kdf::Argon2Params {
+            memory_kib: 20 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        });
+        let encryptor = AES::with_kdf("password", kdf).unwrap();
+        let encrypted = encryptor.encrypt(b"data").unwrap();
+        let decryptor = AES::from_encrypted("password", &encrypted).unwrap();
+        assert_eq!(decryptor.kdf(), Some(kdf));
+        assert_eq!(decryptor.decrypt(&encrypted).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_reads_legacy_sha3_ciphertext() {
+        // Legacy blobs are `base64(salt || nonce || ciphertext)` keyed with SHA3(password || salt)
+        let salt = [7u8; 16];
+        let legacy = AES::from_salt("password", &salt, Kdf::Sha3).unwrap();
+        let cipher = Aes256Gcm::new_from_slice(&legacy.key).unwrap();
+        let nonce = [9u8; 12];
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), b"old data".as_ref()).unwrap();
+        let legacy_blob = BASE64.encode([salt.as_slice(), &nonce, &ciphertext].concat());
+
+        let decryptor = AES::from_encrypted("password", &legacy_blob).unwrap();
+        assert_eq!(decryptor.kdf(), Some(Kdf::Sha3));
+        assert_eq!(decryptor.decrypt(&legacy_blob).unwrap(), b"old data");
+    }
+
+    #[test]
+    fn test_tampered_header_fails_decryption() {
+        let encryptor = AES::new("password").unwrap();
+        let mut envelope = Envelope::decode(&encryptor.encrypt(b"data").unwrap()).unwrap();
+        envelope.key_id = "other".to_string();
+        assert!(encryptor.decrypt(&envelope.encode().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_random_key() {
+        let encryptor = AES::from_key([4u8; 32], "data-key");
+        let encrypted = encryptor.encrypt(b"data").unwrap();
+        let envelope = Envelope::decode(&encrypted).unwrap();
+        assert_eq!(envelope.kdf, None);
+        assert_eq!(envelope.key_id, "data-key");
+        assert_eq!(encryptor.decrypt(&encrypted).unwrap(), b"data");
+        assert!(AES::from_key([5u8; 32], "data-key").decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_trait_matches_envelope_encoding() {
+        let encryptor = AES::from_key([4u8; 32], "data-key");
+        let cipher: &dyn Encrypt = &encryptor;
+        let raw = cipher.encrypt_u8(b"data").unwrap();
+        assert_eq!(encryptor.decrypt(&BASE64.encode(&raw)).unwrap(), b"data");
+
+        let encoded = cipher.encrypt("text").unwrap();
+        assert_eq!(encryptor.decrypt_string(&encoded).unwrap(), "text");
+        assert_eq!(cipher.decrypt(&encoded).unwrap(), "text");
+    }
 }
@@ -1,6 +1,7 @@
+use crate::encrypt::{key_id, Cipher, Encrypt, Envelope, Error, Result};
 use aes_gcm::{
     Aes256Gcm, Key, Nonce,
-    aead::{Aead, KeyInit},
+    aead::{Aead, AeadCore, KeyInit, Payload},
 };
 use hkdf::Hkdf;
 use p256::PublicKey as P256PublicKey;
@@ -8,6 +9,7 @@ use p256::SecretKey as P256SecretKey;
 use p256::ecdh::EphemeralSecret as P256EphemeralSecret;
 use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
 use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::{self, EncodePublicKey};
 use p384::PublicKey as P384PublicKey;
 use p384::SecretKey as P384SecretKey;
 use p384::ecdh::EphemeralSecret as P384EphemeralSecret;
@@ -16,23 +18,24 @@ use p384::ecdsa::{
 };
 use rand::rngs::OsRng;
 use sha2::{Digest, Sha256, Sha384};
-use signature::{Signer, Verifier};
+use signature::{hazmat::PrehashVerifier, Signer, Verifier};
+use rsa::pkcs8::spki::{der::DecodePem, ObjectIdentifier, SubjectPublicKeyInfoOwned};
 use yubikey::piv as piv_card;
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EccAlgorithm {
     P256,
     P384,
 }
 
 impl TryFrom<piv_card::AlgorithmId> for EccAlgorithm {
-    type Error = &'static str;
-    
-    fn try_from(value: piv_card::AlgorithmId) -> Result<Self, Self::Error> {
+    type Error = Error;
+
+    fn try_from(value: piv_card::AlgorithmId) -> Result<Self> {
         match value {
             piv_card::AlgorithmId::EccP256 => Ok(EccAlgorithm::P256),
             piv_card::AlgorithmId::EccP384 => Ok(EccAlgorithm::P384),
-            _ => Err("Unsupported PIV AlgorithmId for ECC"),
+            _ => Err(Error::Ecc("Unsupported PIV AlgorithmId for ECC".to_string())),
         }
     }
 }
@@ -43,21 +46,78 @@ pub struct PublicKey {
 }
 
 impl PublicKey {
+    // id-ecPublicKey and the named curves a PIV slot can hold
+    const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+    const P256_CURVE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+    const P384_CURVE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.34");
+
     pub fn new(algorithm: EccAlgorithm, public_key: Vec<u8>) -> Self {
         Self {
             algorithm,
             bytes: public_key,
         }
     }
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    /// Reads a `PUBLIC KEY` PEM, as exported from a PIV slot certificate.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let spki = SubjectPublicKeyInfoOwned::from_pem(pem)
+            .map_err(|e| Error::Ecc(format!("Failed to parse public key from PEM: {}", e)))?;
+        if spki.algorithm.oid != Self::EC_PUBLIC_KEY {
+            return Err(Error::Ecc("Public key is not an ECC key".to_string()));
+        }
+        let curve: ObjectIdentifier = spki
+            .algorithm
+            .parameters
+            .ok_or(Error::Ecc("ECC public key without a named curve".to_string()))?
+            .decode_as()
+            .map_err(|e| Error::Ecc(format!("Invalid ECC curve parameter: {}", e)))?;
+        let algorithm = match curve {
+            Self::P256_CURVE => EccAlgorithm::P256,
+            Self::P384_CURVE => EccAlgorithm::P384,
+            curve => return Err(Error::Ecc(format!("Unsupported ECC curve {}", curve))),
+        };
+        let bytes = spki
+            .subject_public_key
+            .as_bytes()
+            .ok_or(Error::Ecc("Invalid ECC public key bits".to_string()))?
+            .to_vec();
+        Ok(Self::new(algorithm, bytes))
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
         encrypt(data, &self.bytes, self.algorithm)
     }
 
-    pub fn verify(&self, data: &[u8], signature_bytes: &[u8]) -> Result<bool, &'static str> {
-        verify(data, signature_bytes, &self.bytes, self.algorithm)
+    /// Verifies a DER encoded ECDSA signature of a digest, the form PIV cards return.
+    pub fn verify_prehash(&self, digest: &[u8], der_signature: &[u8]) -> Result<()> {
+        let invalid = |_| Error::Ecc("Signature verification failed".to_string());
+        match self.algorithm {
+            EccAlgorithm::P256 => {
+                let verifying_key = VerifyingKey::from_sec1_bytes(&self.bytes)
+                    .map_err(|_| Error::Ecc("Invalid P256 public key for verification".to_string()))?;
+                let signature = Signature::from_der(der_signature).map_err(invalid)?;
+                verifying_key.verify_prehash(digest, &signature).map_err(invalid)
+            }
+            EccAlgorithm::P384 => {
+                let verifying_key = P384VerifyingKey::from_sec1_bytes(&self.bytes)
+                    .map_err(|_| Error::Ecc("Invalid P384 public key for verification".to_string()))?;
+                let signature = P384Signature::from_der(der_signature).map_err(invalid)?;
+                verifying_key.verify_prehash(digest, &signature).map_err(invalid)
+            }
+        }
     }
 }
 
+impl Encrypt for PublicKey {
+    fn encrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>> {
+        PublicKey::encrypt(self, data)
+    }
+
+    fn decrypt_u8(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::Unsupported("An ECC public key can not decrypt".to_string()))
+    }
+}
+
+#[allow(dead_code)]
 pub fn generate_key_pair(algorithm: EccAlgorithm) -> (Vec<u8>, Vec<u8>) {
     match algorithm {
         EccAlgorithm::P256 => {
@@ -79,15 +139,16 @@ pub fn generate_key_pair(algorithm: EccAlgorithm) -> (Vec<u8>, Vec<u8>) {
     }
 }
 
+#[allow(dead_code)]
 pub fn sign(
     data: &[u8],
     private_key_bytes: &[u8],
     algorithm: EccAlgorithm,
-) -> Result<Vec<u8>, &'static str> {
+) -> Result<Vec<u8>> {
     match algorithm {
         EccAlgorithm::P256 => {
             let secret_key = P256SecretKey::from_slice(private_key_bytes)
-                .map_err(|_| "Invalid P256 private key slice for signing")?;
+                .map_err(|_| Error::Ecc("Invalid P256 private key slice for signing".to_string()))?;
             let signing_key = SigningKey::from(&secret_key);
             // Use Digest::digest() and Signer trait
             let digest = Sha256::digest(data);
@@ -96,7 +157,7 @@ pub fn sign(
         }
         EccAlgorithm::P384 => {
             let secret_key = P384SecretKey::from_slice(private_key_bytes)
-                .map_err(|_| "Invalid P384 private key slice for signing")?;
+                .map_err(|_| Error::Ecc("Invalid P384 private key slice for signing".to_string()))?;
             let signing_key = P384SigningKey::from(&secret_key);
             // Use Digest::digest() and Signer trait
             let digest = Sha384::digest(data);
@@ -106,27 +167,28 @@ pub fn sign(
     }
 }
 
+#[allow(dead_code)]
 pub fn verify(
     data: &[u8],
     signature_bytes: &[u8],
     public_key_bytes: &[u8],
     algorithm: EccAlgorithm,
-) -> Result<bool, &'static str> {
+) -> Result<bool> {
     match algorithm {
         EccAlgorithm::P256 => {
             let verifying_key = VerifyingKey::from_sec1_bytes(public_key_bytes)
-                .map_err(|_| "Invalid P256 public key for verification")?;
+                .map_err(|_| Error::Ecc("Invalid P256 public key for verification".to_string()))?;
             let signature = Signature::try_from(signature_bytes)
-                .map_err(|_| "Invalid P256 signature format")?;
+                .map_err(|_| Error::Ecc("Invalid P256 signature format".to_string()))?;
             // Use Digest::digest() and Verifier trait
             let digest = Sha256::digest(data);
             Ok(verifying_key.verify(&digest, &signature).is_ok())
         }
         EccAlgorithm::P384 => {
             let verifying_key = P384VerifyingKey::from_sec1_bytes(public_key_bytes)
-                .map_err(|_| "Invalid P384 public key for verification")?;
+                .map_err(|_| Error::Ecc("Invalid P384 public key for verification".to_string()))?;
             let signature = P384Signature::try_from(signature_bytes)
-                .map_err(|_| "Invalid P384 signature format")?;
+                .map_err(|_| Error::Ecc("Invalid P384 signature format".to_string()))?;
             // Use Digest::digest() and Verifier trait
             let digest = Sha384::digest(data);
             Ok(verifying_key.verify(&digest, &signature).is_ok())
@@ -136,158 +198,151 @@ pub fn verify(
 
 // Encryption and decryption using ECIES with AES-GCM and HKDF
 
+/// ECIES to the public key, in an envelope naming the key (`encrypt::key_id`).
+/// The envelope header is authenticated by AES-GCM.
 pub fn encrypt(
     data: &[u8],
     public_key_bytes: &[u8],
     algorithm: EccAlgorithm,
-) -> Result<Vec<u8>, &'static str> {
-    match algorithm {
+) -> Result<Vec<u8>> {
+    let (ephemeral_public_key, shared_secret, key_id) = match algorithm {
         EccAlgorithm::P256 => {
             let recipient_public_key = P256PublicKey::from_sec1_bytes(public_key_bytes)
-                .map_err(|_| "Invalid P256 public key for encryption")?;
+                .map_err(|_| Error::Ecc("Invalid P256 public key for encryption".to_string()))?;
             let ephemeral_secret = P256EphemeralSecret::random(&mut OsRng);
-            let ephemeral_public_key_point = ephemeral_secret.public_key();
             let shared_secret = ephemeral_secret.diffie_hellman(&recipient_public_key);
-
-            let hk = Hkdf::<Sha256>::new(None, shared_secret.raw_secret_bytes().as_ref());
-            let mut okm = [0u8; 44];
-            hk.expand(b"aes-256-gcm-key-nonce", &mut okm)
-                .map_err(|_| "HKDF expansion failed")?;
-            let (key_bytes, nonce_bytes) = okm.split_at(32);
-
-            let key = Key::<Aes256Gcm>::from_slice(key_bytes);
-            let nonce = Nonce::from_slice(nonce_bytes);
-
-            let cipher = Aes256Gcm::new(key);
-            let ciphertext = cipher
-                .encrypt(nonce, data)
-                .map_err(|_| "AES-GCM encryption failed")?;
-
-            let mut result = Vec::new();
-            result.extend_from_slice(
-                ephemeral_public_key_point
-                    .to_encoded_point(false)
-                    .as_bytes(),
-            );
-            result.extend_from_slice(&ciphertext);
-            Ok(result)
+            (
+                ephemeral_secret.public_key().to_encoded_point(false).as_bytes().to_vec(),
+                shared_secret.raw_secret_bytes().to_vec(),
+                spki_key_id(recipient_public_key.to_public_key_der())?,
+            )
         }
         EccAlgorithm::P384 => {
             let recipient_public_key = P384PublicKey::from_sec1_bytes(public_key_bytes)
-                .map_err(|_| "Invalid P384 public key for encryption")?;
+                .map_err(|_| Error::Ecc("Invalid P384 public key for encryption".to_string()))?;
             let ephemeral_secret = P384EphemeralSecret::random(&mut OsRng);
-            let ephemeral_public_key_point = ephemeral_secret.public_key();
             let shared_secret = ephemeral_secret.diffie_hellman(&recipient_public_key);
-
-            let hk = Hkdf::<Sha384>::new(None, shared_secret.raw_secret_bytes().as_ref());
-            let mut okm = [0u8; 44];
-            hk.expand(b"aes-256-gcm-key-nonce", &mut okm)
-                .map_err(|_| "HKDF expansion failed")?;
-            let (key_bytes, nonce_bytes) = okm.split_at(32);
-
-            let key = Key::<Aes256Gcm>::from_slice(key_bytes);
-            let nonce = Nonce::from_slice(nonce_bytes);
-
-            let cipher = Aes256Gcm::new(key);
-            let ciphertext = cipher
-                .encrypt(nonce, data)
-                .map_err(|_| "AES-GCM encryption failed")?;
-
-            let mut result = Vec::new();
-            result.extend_from_slice(
-                ephemeral_public_key_point
-                    .to_encoded_point(false)
-                    .as_bytes(),
-            );
-            result.extend_from_slice(&ciphertext);
-            Ok(result)
+            (
+                ephemeral_secret.public_key().to_encoded_point(false).as_bytes().to_vec(),
+                shared_secret.raw_secret_bytes().to_vec(),
+                spki_key_id(recipient_public_key.to_public_key_der())?,
+            )
         }
-    }
+    };
+    let mut envelope = Envelope::new(Cipher::Ecies, None, &[], &key_id);
+    let ciphertext = content_cipher(&shared_secret, algorithm, |cipher, nonce| {
+        cipher
+            .encrypt(nonce, Payload { msg: data, aad: &envelope.aad() })
+            .map_err(|_| Error::Ecc("AES-GCM encryption failed".to_string()))
+    })?;
+    envelope.payload = ephemeral_public_key;
+    envelope.payload.extend_from_slice(&ciphertext);
+    envelope.to_bytes()
+}
+
+fn spki_key_id(der: pkcs8::spki::Result<pkcs8::Document>) -> Result<String> {
+    let der = der.map_err(|e| Error::Ecc(format!("Failed to encode public key: {}", e)))?;
+    Ok(key_id(der.as_bytes()))
+}
+
+// AES-256-GCM key and nonce derived from the ECDH shared secret
+fn content_cipher<T>(
+    shared_secret: &[u8],
+    algorithm: EccAlgorithm,
+    run: impl FnOnce(Aes256Gcm, &Nonce<<Aes256Gcm as AeadCore>::NonceSize>) -> Result<T>,
+) -> Result<T> {
+    let mut okm = [0u8; 44];
+    let expanded = match algorithm {
+        EccAlgorithm::P256 => Hkdf::<Sha256>::new(None, shared_secret).expand(b"aes-256-gcm-key-nonce", &mut okm),
+        EccAlgorithm::P384 => Hkdf::<Sha384>::new(None, shared_secret).expand(b"aes-256-gcm-key-nonce", &mut okm),
+    };
+    expanded.map_err(|_| Error::Ecc("HKDF expansion failed".to_string()))?;
+    let (key_bytes, nonce_bytes) = okm.split_at(32);
+    run(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key_bytes)), Nonce::from_slice(nonce_bytes))
 }
 
+#[allow(dead_code)]
 pub fn decrypt(
     encrypted_data_with_key: &[u8],
     private_key_bytes: &[u8],
     algorithm: EccAlgorithm,
-) -> Result<Vec<u8>, &'static str> {
+) -> Result<Vec<u8>> {
     match algorithm {
         EccAlgorithm::P256 => {
             let local_secret_key = P256SecretKey::from_slice(private_key_bytes)
-                .map_err(|_| "Invalid P256 private key for decryption")?;
-
-            let ephemeral_pk_size = 1 + 32 + 32;
-            if encrypted_data_with_key.len() < ephemeral_pk_size {
-                return Err("Encrypted data too short to contain P256 ephemeral public key");
-            }
-            let (ephemeral_public_key_bytes, ciphertext) =
-                encrypted_data_with_key.split_at(ephemeral_pk_size);
-
-            let ephemeral_public_key = P256PublicKey::from_sec1_bytes(ephemeral_public_key_bytes)
-                .map_err(|_| "Invalid P256 ephemeral public key")?;
-
-            let shared_secret = p256::ecdh::diffie_hellman(
-                local_secret_key.to_nonzero_scalar(),
-                ephemeral_public_key.as_affine(),
-            );
-
-            let hk = Hkdf::<Sha256>::new(None, shared_secret.raw_secret_bytes().as_ref());
-            let mut okm = [0u8; 44];
-            hk.expand(b"aes-256-gcm-key-nonce", &mut okm)
-                .map_err(|_| "HKDF expansion failed for P256 decryption")?;
-            let (key_bytes, nonce_bytes) = okm.split_at(32);
-
-            let key = Key::<Aes256Gcm>::from_slice(key_bytes);
-            let nonce = Nonce::from_slice(nonce_bytes);
-            let cipher = Aes256Gcm::new(key);
-
-            cipher
-                .decrypt(nonce, ciphertext)
-                .map_err(|_| "AES-GCM P256 decryption failed")
+                .map_err(|_| Error::Ecc("Invalid P256 private key for decryption".to_string()))?;
+            decrypt_with(encrypted_data_with_key, algorithm, |ephemeral_public_key_bytes| {
+                let ephemeral_public_key = P256PublicKey::from_sec1_bytes(ephemeral_public_key_bytes)
+                    .map_err(|_| Error::Ecc("Invalid P256 ephemeral public key".to_string()))?;
+                let shared_secret = p256::ecdh::diffie_hellman(
+                    local_secret_key.to_nonzero_scalar(),
+                    ephemeral_public_key.as_affine(),
+                );
+                Ok(shared_secret.raw_secret_bytes().to_vec())
+            })
         }
         EccAlgorithm::P384 => {
             let local_secret_key = P384SecretKey::from_slice(private_key_bytes)
-                .map_err(|_| "Invalid P384 private key for decryption")?;
+                .map_err(|_| Error::Ecc("Invalid P384 private key for decryption".to_string()))?;
+            decrypt_with(encrypted_data_with_key, algorithm, |ephemeral_public_key_bytes| {
+                let ephemeral_public_key = P384PublicKey::from_sec1_bytes(ephemeral_public_key_bytes)
+                    .map_err(|_| Error::Ecc("Invalid P384 ephemeral public key".to_string()))?;
+                let shared_secret = p384::ecdh::diffie_hellman(
+                    local_secret_key.to_nonzero_scalar(),
+                    ephemeral_public_key.as_affine(),
+                );
+                Ok(shared_secret.raw_secret_bytes().to_vec())
+            })
+        }
+    }
+}
 
-            let ephemeral_pk_size = 1 + 48 + 48;
-            if encrypted_data_with_key.len() < ephemeral_pk_size {
-                return Err("Encrypted data too short to contain P384 ephemeral public key");
-            }
-            let (ephemeral_public_key_bytes, ciphertext) =
-                encrypted_data_with_key.split_at(ephemeral_pk_size);
-
-            let ephemeral_public_key = P384PublicKey::from_sec1_bytes(ephemeral_public_key_bytes)
-                .map_err(|_| "Invalid P384 ephemeral public key")?;
-
-            let shared_secret = p384::ecdh::diffie_hellman(
-                local_secret_key.to_nonzero_scalar(),
-                ephemeral_public_key.as_affine(),
-            );
-
-            let hk = Hkdf::<Sha384>::new(None, shared_secret.raw_secret_bytes().as_ref());
-            let mut okm = [0u8; 44];
-            hk.expand(b"aes-256-gcm-key-nonce", &mut okm)
-                .map_err(|_| "HKDF expansion failed for P384 decryption")?;
-            let (key_bytes, nonce_bytes) = okm.split_at(32);
-
-            let key = Key::<Aes256Gcm>::from_slice(key_bytes);
-            let nonce = Nonce::from_slice(nonce_bytes);
-            let cipher = Aes256Gcm::new(key);
-
-            cipher
-                .decrypt(nonce, ciphertext)
-                .map_err(|_| "AES-GCM P384 decryption failed")
+/// Decrypts with the ECDH step done by `ecdh`, e.g. on a smart card. It gets the
+/// uncompressed ephemeral public key and returns the raw shared secret (x coordinate).
+/// Ciphertext from before envelopes is the bare `ephemeral public key || ciphertext`.
+pub fn decrypt_with(
+    encrypted_data_with_key: &[u8],
+    algorithm: EccAlgorithm,
+    ecdh: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let (aad, payload) = if Envelope::has_header(encrypted_data_with_key) {
+        let envelope = Envelope::from_bytes(encrypted_data_with_key)?;
+        if envelope.cipher != Cipher::Ecies {
+            return Err(Error::Envelope(format!("Unexpected cipher {:?}", envelope.cipher)));
         }
+        (envelope.aad(), envelope.payload)
+    } else {
+        (vec![], encrypted_data_with_key.to_vec())
+    };
+    let (curve, ephemeral_pk_size) = match algorithm {
+        EccAlgorithm::P256 => ("P256", 1 + 32 + 32),
+        EccAlgorithm::P384 => ("P384", 1 + 48 + 48),
+    };
+    if payload.len() < ephemeral_pk_size {
+        return Err(Error::Ecc(format!(
+            "Encrypted data too short to contain {} ephemeral public key",
+            curve
+        )));
     }
+    let (ephemeral_public_key_bytes, ciphertext) = payload.split_at(ephemeral_pk_size);
+    let shared_secret = ecdh(ephemeral_public_key_bytes)?;
+
+    content_cipher(&shared_secret, algorithm, |cipher, nonce| {
+        cipher
+            .decrypt(nonce, Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| Error::Ecc(format!("AES-GCM {} decryption failed", curve)))
+    })
 }
 
 // Helper to map piv::AlgorithmId to EccAlgorithm
+#[allow(dead_code)]
 pub fn from_piv_algorithm_id(
     piv_alg_id: piv_card::AlgorithmId,
-) -> Result<EccAlgorithm, &'static str> {
+) -> Result<EccAlgorithm> {
     match piv_alg_id {
         piv_card::AlgorithmId::EccP256 => Ok(EccAlgorithm::P256),
         piv_card::AlgorithmId::EccP384 => Ok(EccAlgorithm::P384),
-        _ => Err("Unsupported PIV AlgorithmId for ECC"),
+        _ => Err(Error::Ecc("Unsupported PIV AlgorithmId for ECC".to_string())),
     }
 }
 
@@ -412,10 +467,10 @@ mod tests {
             decryption_result.is_err(),
             "Decryption with wrong P256 key should fail"
         );
-        assert_eq!(
-            decryption_result.unwrap_err(),
-            "AES-GCM P256 decryption failed"
-        );
+        assert!(matches!(
+            decryption_result,
+            Err(Error::Ecc(message)) if message == "AES-GCM P256 decryption failed"
+        ));
     }
 
     #[test]
@@ -437,10 +492,83 @@ mod tests {
             decryption_result.is_err(),
             "Decryption with wrong P384 key should fail"
         );
-        assert_eq!(
-            decryption_result.unwrap_err(),
-            "AES-GCM P384 decryption failed"
-        );
+        assert!(matches!(
+            decryption_result,
+            Err(Error::Ecc(message)) if message == "AES-GCM P384 decryption failed"
+        ));
+    }
+
+    // PEM as exported from a PIV slot certificate
+    fn public_key_pem(public_key: &[u8], curve: ObjectIdentifier) -> String {
+        use rsa::pkcs8::spki::der::{asn1::BitString, Any, EncodePem, pem::LineEnding};
+        use rsa::pkcs8::spki::AlgorithmIdentifierOwned;
+        SubjectPublicKeyInfoOwned {
+            algorithm: AlgorithmIdentifierOwned {
+                oid: PublicKey::EC_PUBLIC_KEY,
+                parameters: Some(Any::encode_from(&curve).unwrap()),
+            },
+            subject_public_key: BitString::from_bytes(public_key).unwrap(),
+        }
+        .to_pem(LineEnding::LF)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_public_key_from_pem() {
+        for (algorithm, curve) in [
+            (EccAlgorithm::P256, PublicKey::P256_CURVE),
+            (EccAlgorithm::P384, PublicKey::P384_CURVE),
+        ] {
+            let (private_key, public_key) = generate_key_pair(algorithm);
+            let parsed = PublicKey::from_pem(&public_key_pem(&public_key, curve)).unwrap();
+            assert_eq!(parsed.algorithm, algorithm);
+            assert_eq!(parsed.bytes, public_key);
+
+            let pem = public_key_pem(&public_key, curve);
+            let encrypted = parsed.encrypt(b"card secret").unwrap();
+            assert_eq!(decrypt(&encrypted, &private_key, algorithm).unwrap(), b"card secret");
+            assert_eq!(crate::encrypt::ciphertext_key_id(&encrypted), Some(crate::encrypt::key_id_from_pem(&pem).unwrap()));
+        }
+        let rsa_pem = crate::encrypt::RsaKeyPair::new().unwrap().public_key_pem().unwrap();
+        assert!(matches!(PublicKey::from_pem(&rsa_pem), Err(Error::Ecc(_))));
+    }
+
+    #[test]
+    fn test_decrypt_with_external_ecdh() {
+        // The card only returns the shared secret for the ephemeral key it is given
+        let (private_key, public_key) = generate_key_pair(EccAlgorithm::P256);
+        let encrypted = encrypt(b"secret", &public_key, EccAlgorithm::P256).unwrap();
+        let secret_key = P256SecretKey::from_slice(&private_key).unwrap();
+        let decrypted = decrypt_with(&encrypted, EccAlgorithm::P256, |ephemeral| {
+            assert_eq!(ephemeral.len(), 65);
+            let ephemeral = P256PublicKey::from_sec1_bytes(ephemeral).unwrap();
+            let shared = p256::ecdh::diffie_hellman(secret_key.to_nonzero_scalar(), ephemeral.as_affine());
+            Ok(shared.raw_secret_bytes().to_vec())
+        });
+        assert_eq!(decrypted.unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_encryptor_from_pem_picks_key_type() {
+        let (private_key, public_key) = generate_key_pair(EccAlgorithm::P256);
+        let encryptor = crate::encrypt::encryptor_from_pem(&public_key_pem(&public_key, PublicKey::P256_CURVE)).unwrap();
+        let encrypted = encryptor.encrypt_u8(b"data").unwrap();
+        assert_eq!(decrypt(&encrypted, &private_key, EccAlgorithm::P256).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_verify_prehash_from_pem() {
+        use signature::hazmat::PrehashSigner;
+        let signing_key = P384SigningKey::random(&mut OsRng);
+        let public_key = P384VerifyingKey::from(&signing_key).to_encoded_point(false).as_bytes().to_vec();
+        let pem = public_key_pem(&public_key, PublicKey::P384_CURVE);
+        let digest = Sha384::digest(b"challenge");
+        let signature: P384Signature = signing_key.sign_prehash(&digest).unwrap();
+        let der = signature.to_der();
+
+        assert!(crate::encrypt::verify_prehash_from_pem(&pem, &digest, der.as_bytes()).is_ok());
+        let other = Sha384::digest(b"other");
+        assert!(crate::encrypt::verify_prehash_from_pem(&pem, &other, der.as_bytes()).is_err());
     }
 
     #[test]
@@ -0,0 +1,249 @@
+use crate::encrypt::{Error, Result, Kdf};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+
+// `VLTE || version || cipher || kdf || salt_len || salt || key_id_len || key_id || payload`
+const MAGIC: &[u8; 4] = b"VLTE";
+// Interim format: `VKDF || kdf || salt || nonce || ciphertext`
+const KDF_MAGIC: &[u8; 4] = b"VKDF";
+// Envelope KDF id meaning the key was not derived from a password
+const NO_KDF_ID: u8 = 0xFF;
+const KDF_LEN: usize = 13;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// Payload is `nonce (12 bytes) || ciphertext`, the header is authenticated as AAD.
+    Aes256Gcm,
+    /// Payload is an AES-256-GCM content key wrapped by an RSA key with PKCS#1 v1.5
+    /// padding followed by `nonce || ciphertext`, see `hybrid`. Read only.
+    RsaPkcs1,
+    /// Same as `RsaPkcs1` with the content key wrapped using RSA-OAEP (SHA-256).
+    RsaOaep,
+    /// ECIES, the payload is `ephemeral public key || ciphertext`, see `ecc::encrypt`.
+    Ecies,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::RsaPkcs1 => 2,
+            Cipher::RsaOaep => 3,
+            Cipher::Ecies => 4,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            2 => Ok(Cipher::RsaPkcs1),
+            3 => Ok(Cipher::RsaOaep),
+            4 => Ok(Cipher::Ecies),
+            id => Err(Error::Envelope(format!("Unknown cipher id {}", id))),
+        }
+    }
+}
+
+/// Self-describing header wrapped around every `.enc` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub version: u8,
+    pub cipher: Cipher,
+    pub kdf: Option<Kdf>,
+    pub salt: Vec<u8>,
+    pub key_id: String,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Version written by this build. Version 0 stands for the headerless formats.
+    pub const VERSION: u8 = 1;
+
+    pub fn new(cipher: Cipher, kdf: Option<Kdf>, salt: &[u8], key_id: &str) -> Self {
+        Self {
+            version: Self::VERSION,
+            cipher,
+            kdf,
+            salt: salt.to_vec(),
+            key_id: key_id.to_string(),
+            payload: vec![],
+        }
+    }
+
+    /// True when `data` starts with a versioned header. Headerless AES blobs and
+    /// raw ECIES ciphertext (an uncompressed point starts with 0x04) do not.
+    pub fn has_header(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version < Self::VERSION
+    }
+
+    /// Bytes authenticated alongside the payload, empty for legacy envelopes.
+    pub fn aad(&self) -> Vec<u8> {
+        if self.is_legacy() {
+            return vec![];
+        }
+        let mut out = MAGIC.to_vec();
+        out.push(self.version);
+        out.push(self.cipher.id());
+        match self.kdf {
+            Some(kdf) => out.extend_from_slice(&kdf.to_bytes()),
+            None => {
+                out.push(NO_KDF_ID);
+                out.extend_from_slice(&[0u8; KDF_LEN - 1]);
+            }
+        }
+        out.push(self.salt.len() as u8);
+        out.extend_from_slice(&self.salt);
+        out.push(self.key_id.len() as u8);
+        out.extend_from_slice(self.key_id.as_bytes());
+        out
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.is_legacy() {
+            return Err(Error::Envelope("Legacy envelopes are read only".to_string()));
+        }
+        if self.salt.len() > u8::MAX as usize || self.key_id.len() > u8::MAX as usize {
+            return Err(Error::Envelope("Salt or key id too long".to_string()));
+        }
+        let mut out = self.aad();
+        out.extend_from_slice(&self.payload);
+        Ok(out)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if let Some(rest) = data.strip_prefix(MAGIC) {
+            return Self::parse_versioned(rest);
+        }
+        // Headerless blobs are always AES-GCM under a password derived key
+        let (kdf, rest) = match data.strip_prefix(KDF_MAGIC) {
+            Some(rest) => Kdf::from_bytes(rest)?,
+            None => (Kdf::Sha3, data),
+        };
+        if rest.len() < SALT_LEN {
+            return Err(Error::Envelope("Invalid encrypted data length".to_string()));
+        }
+        let (salt, payload) = rest.split_at(SALT_LEN);
+        Ok(Self {
+            version: 0,
+            cipher: Cipher::Aes256Gcm,
+            kdf: Some(kdf),
+            salt: salt.to_vec(),
+            key_id: String::new(),
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn parse_versioned(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let version = reader.take(1)?[0];
+        if version != Self::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let cipher = Cipher::from_id(reader.take(1)?[0])?;
+        let kdf = reader.take(KDF_LEN)?;
+        let kdf = match kdf[0] {
+            NO_KDF_ID => None,
+            _ => Some(Kdf::from_bytes(kdf)?.0),
+        };
+        let salt_len = reader.take(1)?[0] as usize;
+        let salt = reader.take(salt_len)?.to_vec();
+        let key_id_len = reader.take(1)?[0] as usize;
+        let key_id = String::from_utf8(reader.take(key_id_len)?.to_vec())?;
+        Ok(Self {
+            version,
+            cipher,
+            kdf,
+            salt,
+            key_id,
+            payload: reader.0.to_vec(),
+        })
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        Ok(BASE64.encode(self.to_bytes()?))
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let data = BASE64.decode(encoded.trim().as_bytes())?;
+        Self::from_bytes(&data)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::Envelope("Truncated envelope header".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        let mut envelope = Envelope::new(Cipher::Aes256Gcm, Some(Kdf::default()), &[3u8; 16], "master");
+        envelope.payload = b"payload".to_vec();
+        envelope
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let envelope = envelope();
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(decoded, envelope);
+        assert!(!decoded.is_legacy());
+        assert!(Envelope::has_header(&envelope.to_bytes().unwrap()));
+    }
+
+    #[test]
+    fn test_round_trip_without_kdf() {
+        let mut envelope = envelope();
+        envelope.kdf = None;
+        envelope.salt = vec![];
+        let decoded = Envelope::decode(&envelope.encode().unwrap()).unwrap();
+        assert_eq!(decoded, envelope);
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes[MAGIC.len()] = 9;
+        assert!(matches!(Envelope::from_bytes(&bytes), Err(Error::UnsupportedVersion(9))));
+    }
+
+    #[test]
+    fn test_rejects_unknown_cipher() {
+        let mut bytes = envelope().to_bytes().unwrap();
+        bytes[MAGIC.len() + 1] = 200;
+        assert!(matches!(Envelope::from_bytes(&bytes), Err(Error::Envelope(_))));
+    }
+
+    #[test]
+    fn test_rejects_truncated_header() {
+        let bytes = envelope().to_bytes().unwrap();
+        assert!(matches!(Envelope::from_bytes(&bytes[..10]), Err(Error::Envelope(_))));
+    }
+
+    #[test]
+    fn test_reads_headerless_blob() {
+        let mut data = vec![5u8; 16];
+        data.extend_from_slice(b"nonce+ciphertext");
+        let envelope = Envelope::from_bytes(&data).unwrap();
+        assert!(envelope.is_legacy());
+        assert_eq!(envelope.kdf, Some(Kdf::Sha3));
+        assert_eq!(envelope.salt, vec![5u8; 16]);
+        assert_eq!(envelope.payload, b"nonce+ciphertext");
+        assert!(envelope.aad().is_empty());
+        assert!(!Envelope::has_header(&data));
+    }
+}
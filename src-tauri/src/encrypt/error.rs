@@ -12,7 +12,18 @@ pub enum Error {
     Custom(String),
     EncryptPassword(String),
     DecryptPassword(String),
-    WrongPassword(String)
+    WrongPassword(String),
+    Kdf(String),
+    Envelope(String),
+    UnsupportedVersion(u8),
+    Keyring(String),
+    Json(String),
+    Ecc(String),
+    YubiKey(String),
+    Unsupported(String),
+    Tampered(String),
+    WrongPin(u8),
+    PinBlocked(String),
 }
 
 // --- Rsa errors
@@ -39,6 +50,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e.to_string())
+    }
+}
+
 impl From<std::string::FromUtf8Error> for Error {
     fn from(e: std::string::FromUtf8Error) -> Self {
         Error::BadUTF8(e.to_string())
@@ -0,0 +1,137 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use crate::encrypt::{Cipher, Envelope, Error, Result};
+
+// Payload of a hybrid envelope:
+// `wrapped_len (u16 BE) || wrapped content key || nonce || ciphertext`
+const NONCE_LEN: usize = 12;
+
+/// RSA padding the content key was wrapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// Only found in data written before OAEP.
+    Pkcs1v15,
+    /// RSA-OAEP with SHA-256 and MGF1-SHA-256.
+    Oaep,
+}
+
+/// Encrypts `data` with a random AES-256-GCM content key and lets `wrap`
+/// encrypt that key with the asymmetric key using RSA-OAEP. `key_id` names
+/// that key in the header, see `encrypt::key_id`.
+pub fn seal(data: &[u8], key_id: &str, wrap: impl FnOnce(&[u8]) -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+    let mut content_key = [0u8; 32];
+    OsRng.fill_bytes(&mut content_key);
+    let wrapped = wrap(&content_key)?;
+    let wrapped_len = u16::try_from(wrapped.len())
+        .map_err(|_| Error::Envelope("Wrapped content key too long".to_string()))?;
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let mut envelope = Envelope::new(Cipher::RsaOaep, None, &[], key_id);
+    let ciphertext = Aes256Gcm::new_from_slice(&content_key)
+        .map_err(|e| Error::EncryptPassword(e.to_string()))?
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: data, aad: &envelope.aad() })
+        .map_err(|e| Error::EncryptPassword(e.to_string()))?;
+
+    let mut payload = wrapped_len.to_be_bytes().to_vec();
+    payload.extend_from_slice(&wrapped);
+    payload.extend_from_slice(&nonce_bytes);
+    payload.extend_from_slice(&ciphertext);
+    envelope.payload = payload;
+    envelope.to_bytes()
+}
+
+/// Reverses `seal`, `unwrap` decrypts the content key with the private key
+/// using the given padding.
+///
+/// Data no longer than one block of the key (`key_size` bytes) was encrypted
+/// with the RSA key directly, before hybrid encryption, and goes to `unwrap` as is.
+pub fn open(
+    data: &[u8],
+    key_size: usize,
+    unwrap: impl FnOnce(&[u8], Padding) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    if data.len() <= key_size {
+        return unwrap(data, Padding::Pkcs1v15);
+    }
+    let envelope = Envelope::from_bytes(data)?;
+    let padding = match envelope.cipher {
+        Cipher::RsaPkcs1 => Padding::Pkcs1v15,
+        Cipher::RsaOaep => Padding::Oaep,
+        cipher => return Err(Error::Envelope(format!("Unexpected cipher {:?}", cipher))),
+    };
+    let payload = &envelope.payload;
+    if payload.len() < 2 {
+        return Err(Error::Envelope("Truncated hybrid payload".to_string()));
+    }
+    let wrapped_len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+    if payload.len() < 2 + wrapped_len + NONCE_LEN {
+        return Err(Error::Envelope("Truncated hybrid payload".to_string()));
+    }
+    let (wrapped, rest) = payload[2..].split_at(wrapped_len);
+    let (nonce_bytes, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let content_key = unwrap(wrapped, padding)?;
+    Aes256Gcm::new_from_slice(&content_key)
+        .map_err(|e| Error::DecryptPassword(e.to_string()))?
+        .decrypt(Nonce::from_slice(nonce_bytes), Payload { msg: ciphertext, aad: &envelope.aad() })
+        .map_err(|e| Error::DecryptPassword(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stand-in for an asymmetric key, XORs the content key
+    fn xor(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.iter().map(|b| b ^ 0x5A).collect())
+    }
+
+    fn unxor(data: &[u8], padding: Padding) -> Result<Vec<u8>> {
+        assert_eq!(padding, Padding::Oaep);
+        xor(data)
+    }
+
+    #[test]
+    fn test_round_trip_large_payload() {
+        let data = vec![7u8; 10_000];
+        let sealed = seal(&data, "key", xor).unwrap();
+        assert_eq!(open(&sealed, 32, unxor).unwrap(), data);
+        assert_eq!(Envelope::from_bytes(&sealed).unwrap().key_id, "key");
+    }
+
+    #[test]
+    fn test_short_data_is_unwrapped_directly() {
+        let legacy = xor(b"raw").unwrap();
+        let opened = open(&legacy, 32, |data, padding| {
+            assert_eq!(padding, Padding::Pkcs1v15);
+            xor(data)
+        });
+        assert_eq!(opened.unwrap(), b"raw");
+    }
+
+    #[test]
+    fn test_pkcs1v15_header_is_legacy() {
+        let sealed = seal(b"data", "key", xor).unwrap();
+        let mut envelope = Envelope::from_bytes(&sealed).unwrap();
+        envelope.cipher = Cipher::RsaPkcs1;
+        let legacy = envelope.to_bytes().unwrap();
+        // The header is authenticated, only a real v1.5 envelope decrypts
+        let opened = open(&legacy, 32, |data, padding| {
+            assert_eq!(padding, Padding::Pkcs1v15);
+            xor(data)
+        });
+        assert!(opened.is_err());
+    }
+
+    #[test]
+    fn test_tampered_payload_fails() {
+        let mut sealed = seal(b"data", "key", xor).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open(&sealed, 32, unxor).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use crate::encrypt::{Error, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+/// Tunable Argon2id cost parameters, stored next to every ciphertext so they can be raised later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    // Upper bounds for parameters read back from disk, so a tampered header
+    // can not make the unlock run out of memory or hang
+    const MAX_MEMORY_KIB: u32 = 256 * 1024;
+    const MAX_ITERATIONS: u32 = 16;
+    const MAX_PARALLELISM: u32 = 8;
+
+    /// Fails unless every parameter is between the defaults and the caps.
+    pub fn validate(&self) -> Result<()> {
+        let min = Self::default();
+        let in_range = (min.memory_kib..=Self::MAX_MEMORY_KIB).contains(&self.memory_kib)
+            && (min.iterations..=Self::MAX_ITERATIONS).contains(&self.iterations)
+            && (min.parallelism..=Self::MAX_PARALLELISM).contains(&self.parallelism);
+        if !in_range {
+            return Err(Error::Kdf(format!("Argon2 parameters out of range: {:?}", self)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        // OWASP recommended minimum for Argon2id
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// Single SHA3-256 of `password || salt`, only kept to read old vaults.
+    Sha3,
+    Argon2id(Argon2Params),
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Argon2id(Argon2Params::default())
+    }
+}
+
+impl Kdf {
+    const SHA3_ID: u8 = 0;
+    const ARGON2ID_ID: u8 = 1;
+    const ENCODED_LEN: usize = 13;
+
+    pub fn derive_key(&self, password: &str, salt: &[u8]) -> Result<[u8; 32]> {
+        let mut key = [0u8; 32];
+        match self {
+            Kdf::Sha3 => {
+                let mut hasher = Sha3::sha3_256();
+                hasher.input(password.as_bytes());
+                hasher.input(salt);
+                hasher.result(&mut key);
+            }
+            Kdf::Argon2id(params) => {
+                let params = Params::new(
+                    params.memory_kib,
+                    params.iterations,
+                    params.parallelism,
+                    Some(key.len()),
+                )
+                .map_err(|e| Error::Kdf(e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .map_err(|e| Error::Kdf(e.to_string()))?;
+            }
+        }
+        Ok(key)
+    }
+
+    /// True when data derived with this KDF should be re-encrypted with the current defaults.
+    pub fn is_outdated(&self) -> bool {
+        match (self, Kdf::default()) {
+            (Kdf::Argon2id(current), Kdf::Argon2id(wanted)) => {
+                current.memory_kib < wanted.memory_kib
+                    || current.iterations < wanted.iterations
+                    || current.parallelism < wanted.parallelism
+            }
+            _ => true,
+        }
+    }
+
+    /// id (1 byte) followed by memory, iterations and parallelism as big endian u32.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::ENCODED_LEN);
+        match self {
+            Kdf::Sha3 => {
+                out.push(Self::SHA3_ID);
+                out.extend_from_slice(&[0u8; 12]);
+            }
+            Kdf::Argon2id(params) => {
+                out.push(Self::ARGON2ID_ID);
+                out.extend_from_slice(&params.memory_kib.to_be_bytes());
+                out.extend_from_slice(&params.iterations.to_be_bytes());
+                out.extend_from_slice(&params.parallelism.to_be_bytes());
+            }
+        }
+        out
+    }
+
+    /// Parses the encoded KDF and returns it along with the remaining bytes.
+    /// Argon2 parameters outside `Argon2Params::validate` are refused.
+    pub fn from_bytes(data: &[u8]) -> Result<(Self, &[u8])> {
+        if data.len() < Self::ENCODED_LEN {
+            return Err(Error::Kdf("KDF header too short".to_string()));
+        }
+        let (header, rest) = data.split_at(Self::ENCODED_LEN);
+        let read_u32 = |at: usize| u32::from_be_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+        let kdf = match header[0] {
+            Self::SHA3_ID => Kdf::Sha3,
+            Self::ARGON2ID_ID => {
+                let params = Argon2Params {
+                    memory_kib: read_u32(1),
+                    iterations: read_u32(5),
+                    parallelism: read_u32(9),
+                };
+                params.validate()?;
+                Kdf::Argon2id(params)
+            }
+            id => return Err(Error::Kdf(format!("Unknown KDF id {}", id))),
+        };
+        Ok((kdf, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let kdf = Kdf::Argon2id(Argon2Params {
+            memory_kib: 32 * 1024,
+            iterations: 3,
+            parallelism: 2,
+        });
+        let mut bytes = kdf.to_bytes();
+        bytes.extend_from_slice(b"rest");
+        let (parsed, rest) = Kdf::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, kdf);
+        assert_eq!(rest, b"rest");
+    }
+
+    #[test]
+    fn test_unknown_kdf_id() {
+        let mut bytes = Kdf::default().to_bytes();
+        bytes[0] = 42;
+        assert!(matches!(Kdf::from_bytes(&bytes), Err(Error::Kdf(_))));
+    }
+
+    #[test]
+    fn test_params_out_of_range() {
+        let encode = |params: Argon2Params| Kdf::Argon2id(params).to_bytes();
+        let huge = Argon2Params { memory_kib: u32::MAX, ..Argon2Params::default() };
+        let slow = Argon2Params { iterations: 1_000_000, ..Argon2Params::default() };
+        let wide = Argon2Params { parallelism: 255, ..Argon2Params::default() };
+        let weak = Argon2Params { memory_kib: 1024, ..Argon2Params::default() };
+        for params in [huge, slow, wide, weak] {
+            assert!(matches!(Kdf::from_bytes(&encode(params)), Err(Error::Kdf(_))));
+        }
+        Kdf::from_bytes(&Kdf::default().to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_derive_key_depends_on_params() {
+        let salt = [1u8; 16];
+        let weak = Kdf::Argon2id(Argon2Params {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        });
+        let key1 = weak.derive_key("password", &salt).unwrap();
+        let key2 = Kdf::default().derive_key("password", &salt).unwrap();
+        assert_eq!(key1, weak.derive_key("password", &salt).unwrap());
+        assert_ne!(key1, key2);
+        assert_ne!(key1, Kdf::Sha3.derive_key("password", &salt).unwrap());
+    }
+
+    #[test]
+    fn test_is_outdated() {
+        assert!(Kdf::Sha3.is_outdated());
+        assert!(!Kdf::default().is_outdated());
+        let weak = Kdf::Argon2id(Argon2Params {
+            memory_kib: 1024,
+            ..Argon2Params::default()
+        });
+        assert!(weak.is_outdated());
+    }
+}
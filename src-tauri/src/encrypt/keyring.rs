@@ -0,0 +1,522 @@
+use crate::encrypt::{encryptor_from_pem, mac, Encrypt, Error, Result, AES, PublicKey, RsaKeyPair};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rand::{rngs::OsRng, RngCore};
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+use zeroize::Zeroize;
+
+/// Random key encrypting the secrets of one vault. The cipher holding it is
+/// lent out, never copied, and wipes the key when dropped.
+pub struct DataKey {
+    cipher: AES,
+}
+
+impl DataKey {
+    fn generate() -> Self {
+        Self::generate_share(&uuid::Uuid::new_v4().to_string())
+    }
+
+    // Random key under the id `id`, the card share of a vault requiring both factors
+    fn generate_share(id: &str) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self::from_key(id, key)
+    }
+
+    // XOR of both keys, splitting the data key into two shares or joining them again
+    fn combine(&self, other: &DataKey) -> Self {
+        let mut key = *self.key();
+        for (byte, other) in key.iter_mut().zip(other.key()) {
+            *byte ^= other;
+        }
+        Self::from_key(self.id(), key)
+    }
+
+    fn from_bytes(id: &str, bytes: &[u8]) -> Result<Self> {
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| Error::Keyring("Unwrapped data key has an invalid length".to_string()))?;
+        Ok(Self::from_key(id, key))
+    }
+
+    // Takes `key` into the cipher and wipes the caller's copy
+    fn from_key(id: &str, mut key: [u8; 32]) -> Self {
+        let cipher = AES::from_key(key, id);
+        key.zeroize();
+        Self { cipher }
+    }
+
+    fn key(&self) -> &[u8; 32] {
+        self.cipher.key()
+    }
+
+    pub fn id(&self) -> &str {
+        self.cipher.key_id()
+    }
+
+    pub fn cipher(&self) -> &AES {
+        &self.cipher
+    }
+}
+
+impl Zeroize for DataKey {
+    fn zeroize(&mut self) {
+        self.cipher.zeroize();
+    }
+}
+
+/// Data key handed out by `Keyring::open`: the copy the session holds, or one
+/// unwrapped for the caller. Unlike `Cow` it never copies the key.
+pub enum OpenedKey<'a> {
+    Borrowed(&'a DataKey),
+    Owned(DataKey),
+}
+
+impl Deref for OpenedKey<'_> {
+    type Target = DataKey;
+
+    fn deref(&self) -> &DataKey {
+        match self {
+            OpenedKey::Borrowed(key) => key,
+            OpenedKey::Owned(key) => key,
+        }
+    }
+}
+
+/// One copy of the data key, encrypted for a single unlock method.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum WrappedKey {
+    /// Envelope encrypted with the master password key.
+    Password { key: String },
+    /// Base64 ciphertext for the RSA master public key.
+    RsaMaster { key: String },
+    /// Base64 ciphertext for the public key of an enrolled YubiKey.
+    Yubikey { serial: u32, key: String },
+}
+
+/// What a session must have verified before the data key is handed out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlockPolicy {
+    #[default]
+    Password,
+    /// The enrolled YubiKey signs a challenge and unwraps its own copy of the key.
+    Yubikey,
+    PasswordAndYubikey,
+}
+
+impl UnlockPolicy {
+    pub fn needs_password(&self) -> bool {
+        matches!(self, UnlockPolicy::Password | UnlockPolicy::PasswordAndYubikey)
+    }
+
+    pub fn needs_yubikey(&self) -> bool {
+        matches!(self, UnlockPolicy::Yubikey | UnlockPolicy::PasswordAndYubikey)
+    }
+}
+
+/// Every wrapped copy of a vault's data key. Unlock methods are added or
+/// removed here without touching the secret files.
+///
+/// The copies follow the policy: vaults requiring a YubiKey have no copy the
+/// password reaches on its own. Under `Yubikey` only the cards hold the data
+/// key, under `PasswordAndYubikey` the cards hold a random share and the
+/// password and RSA copies the data key XOR that share.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Keyring {
+    id: String,
+    wrapped: Vec<WrappedKey>,
+    #[serde(default)]
+    policy: UnlockPolicy,
+    /// MAC of the rest of the keyring, made with a key derived from the master key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+impl Keyring {
+    /// Creates a new data key wrapped by the password key and the RSA master key.
+    pub fn generate(password_key: &AES, master_pub: &PublicKey) -> Result<(Self, DataKey)> {
+        let data_key = DataKey::generate();
+        let mut keyring = Self {
+            id: data_key.id().to_string(),
+            wrapped: vec![],
+            policy: UnlockPolicy::default(),
+            mac: None,
+        };
+        keyring.wrap_for_password(&data_key, password_key, master_pub)?;
+        Ok((keyring, data_key))
+    }
+
+    /// Id of the data key, the same for every wrapped copy.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn policy(&self) -> UnlockPolicy {
+        self.policy
+    }
+
+    /// Reads the keyring without checking its MAC, see `verify`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Whether the keyring was written with a MAC, keyrings of older versions were not.
+    pub fn is_signed(&self) -> bool {
+        self.mac.is_some()
+    }
+
+    /// Checks the MAC, failing with `Error::Tampered` when the policy or a
+    /// copy was changed outside the app or the MAC is missing.
+    pub fn verify(&self, master: &AES) -> Result<()> {
+        let tag = self
+            .mac
+            .as_deref()
+            .ok_or(Error::Tampered(format!("Keyring {} is not authenticated", self.id)))?;
+        mac::verify(master, mac::VAULT_KEYRING, &self.payload()?, tag)
+    }
+
+    /// Content of the keyring file, authenticated with the master key.
+    pub fn to_json(&self, master: &AES) -> Result<String> {
+        let mut payload = self.payload()?;
+        let tag = mac::tag(master, mac::VAULT_KEYRING, &payload)?;
+        payload["mac"] = tag.into();
+        Ok(serde_json::to_string_pretty(&payload)?)
+    }
+
+    pub fn save(&self, path: &Path, master: &AES) -> Result<()> {
+        fs::write(path, self.to_json(master)?)?;
+        Ok(())
+    }
+
+    // Everything the MAC covers
+    fn payload(&self) -> Result<serde_json::Value> {
+        let mut payload = serde_json::to_value(self)?;
+        if let Some(fields) = payload.as_object_mut() {
+            fields.remove("mac");
+        }
+        Ok(payload)
+    }
+
+    /// Data key of the vault from what the session holds: the master key and
+    /// the copy a YubiKey unwrapped, see `unlock_with_yubikey`. The policy
+    /// decides which of them are needed.
+    pub fn open<'a>(&self, password_key: Option<&AES>, card_copy: Option<&'a DataKey>) -> Result<OpenedKey<'a>> {
+        let missing = |what: &str| Error::Keyring(format!("Vault key {} needs {}", self.id, what));
+        match (self.policy, password_key, card_copy) {
+            (UnlockPolicy::Password, Some(password_key), _) => Ok(OpenedKey::Owned(self.unlock_with_password(password_key)?)),
+            // The cards of a vault opening with the password hold the data key itself
+            (UnlockPolicy::Password | UnlockPolicy::Yubikey, _, Some(card_copy)) => Ok(OpenedKey::Borrowed(card_copy)),
+            (UnlockPolicy::PasswordAndYubikey, Some(password_key), Some(card_copy)) => {
+                Ok(OpenedKey::Owned(self.unlock_with_password(password_key)?.combine(card_copy)))
+            }
+            (UnlockPolicy::Password, None, None) => Err(missing("the master password")),
+            (UnlockPolicy::PasswordAndYubikey, None, _) => Err(missing("the master password")),
+            (UnlockPolicy::Yubikey | UnlockPolicy::PasswordAndYubikey, _, None) => Err(missing("the YubiKey")),
+        }
+    }
+
+    /// Copy of the password wrap: the data key, or its XOR with the card
+    /// share under `PasswordAndYubikey`.
+    pub fn unlock_with_password(&self, password_key: &AES) -> Result<DataKey> {
+        let key = self
+            .wrapped
+            .iter()
+            .find_map(|wrapped| match wrapped {
+                WrappedKey::Password { key } => Some(key),
+                _ => None,
+            })
+            .ok_or(Error::Keyring("Vault key is not wrapped by the master password".to_string()))?;
+        DataKey::from_bytes(&self.id, &password_key.decrypt(key)?)
+    }
+
+    fn unlock_with_rsa(&self, master_pk: &RsaKeyPair) -> Result<Option<DataKey>> {
+        self.wrapped
+            .iter()
+            .find_map(|wrapped| match wrapped {
+                WrappedKey::RsaMaster { key } => Some(key),
+                _ => None,
+            })
+            .map(|key| DataKey::from_bytes(&self.id, &master_pk.decrypt(&BASE64.decode(key)?)?))
+            .transpose()
+    }
+
+    /// Unwraps the copy of YubiKey `serial` with the device, which knows the
+    /// PIN: the data key, or the card share under `PasswordAndYubikey`.
+    pub fn unlock_with_yubikey(&self, serial: u32, device: &dyn Encrypt) -> Result<DataKey> {
+        let key = self
+            .yubikey_key(serial)
+            .ok_or(Error::Keyring(format!("Vault key is not wrapped for YubiKey {}", serial)))?;
+        DataKey::from_bytes(&self.id, &device.decrypt_u8(&BASE64.decode(key)?)?)
+    }
+
+    /// What a new YubiKey copy must hold, from the data key the session opened.
+    pub fn card_copy(&self, data_key: &DataKey, password_key: &AES) -> Result<DataKey> {
+        match self.policy {
+            UnlockPolicy::PasswordAndYubikey => Ok(self.unlock_with_password(password_key)?.combine(data_key)),
+            _ => DataKey::from_bytes(&self.id, data_key.key()),
+        }
+    }
+
+    pub fn has_yubikey(&self, serial: u32) -> bool {
+        self.yubikey_key(serial).is_some()
+    }
+
+    fn yubikey_key(&self, serial: u32) -> Option<&String> {
+        self.wrapped.iter().find_map(|wrapped| match wrapped {
+            WrappedKey::Yubikey { serial: s, key } if *s == serial => Some(key),
+            _ => None,
+        })
+    }
+
+    /// Wraps `card_copy` for a YubiKey with an RSA or ECC key, replacing a
+    /// previous wrap for the same serial. See `card_copy` for what it holds.
+    pub fn add_yubikey(&mut self, card_copy: &DataKey, serial: u32, public_key_pem: &str) -> Result<()> {
+        let encrypted = encryptor_from_pem(public_key_pem)?.encrypt_u8(card_copy.key())?;
+        self.remove_yubikey(serial);
+        self.wrapped.push(WrappedKey::Yubikey {
+            serial,
+            key: BASE64.encode(encrypted),
+        });
+        Ok(())
+    }
+
+    pub fn remove_yubikey(&mut self, serial: u32) {
+        self.wrapped.retain(|wrapped| {
+            !matches!(wrapped, WrappedKey::Yubikey { serial: s, .. } if *s == serial)
+        });
+    }
+
+    /// Wraps `data_key` again for `policy`, replacing every copy. `cards` are
+    /// the serial and public key PEM of the YubiKeys that get a copy, at least
+    /// one when the policy needs a YubiKey. Returns the copy the cards hold.
+    pub fn apply_policy(
+        &mut self,
+        data_key: &DataKey,
+        policy: UnlockPolicy,
+        password_key: &AES,
+        master_pub: &PublicKey,
+        cards: &[(u32, &str)],
+    ) -> Result<DataKey> {
+        if policy.needs_yubikey() && cards.is_empty() {
+            return Err(Error::Keyring(format!("Vault key {} has no YubiKey to require", self.id)));
+        }
+        let card_copy = match policy {
+            UnlockPolicy::PasswordAndYubikey => DataKey::generate_share(&self.id),
+            _ => DataKey::from_bytes(&self.id, data_key.key())?,
+        };
+        self.wrapped.clear();
+        match policy {
+            UnlockPolicy::Password => self.wrap_for_password(data_key, password_key, master_pub)?,
+            UnlockPolicy::PasswordAndYubikey => {
+                self.wrap_for_password(&data_key.combine(&card_copy), password_key, master_pub)?
+            }
+            UnlockPolicy::Yubikey => {}
+        }
+        for (serial, pem) in cards {
+            self.add_yubikey(&card_copy, *serial, pem)?;
+        }
+        self.policy = policy;
+        Ok(card_copy)
+    }
+
+    fn wrap_for_password(&mut self, key: &DataKey, password_key: &AES, master_pub: &PublicKey) -> Result<()> {
+        self.wrapped.push(WrappedKey::Password {
+            key: password_key.encrypt(key.key())?,
+        });
+        self.wrapped.push(WrappedKey::RsaMaster {
+            key: BASE64.encode(master_pub.encrypt(key.key())?),
+        });
+        Ok(())
+    }
+
+    /// Wraps the RSA copy for the master key `password_key` in place of the
+    /// current password copy, used when the old master key is lost. Vaults
+    /// opening with the YubiKey alone have neither and are left as they are.
+    pub fn recover_password(&mut self, master_pk: &RsaKeyPair, password_key: &AES) -> Result<()> {
+        let Some(key) = self.unlock_with_rsa(master_pk)? else {
+            return Ok(());
+        };
+        let key = password_key.encrypt(key.key())?;
+        self.wrapped.retain(|wrapped| !matches!(wrapped, WrappedKey::Password { .. }));
+        self.wrapped.push(WrappedKey::Password { key });
+        Ok(())
+    }
+
+    /// Moves the password wrap from `old` to `new`, used when the master password changes.
+    pub fn rewrap_password(&mut self, old: &AES, new: &AES) -> Result<()> {
+        for wrapped in self.wrapped.iter_mut() {
+            if let WrappedKey::Password { key } = wrapped {
+                let mut plain = old.decrypt(key)?;
+                *key = new.encrypt(&plain)?;
+                plain.zeroize();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate() -> (Keyring, DataKey, AES, RsaKeyPair) {
+        let password_key = AES::new("password").unwrap();
+        let master = RsaKeyPair::new().unwrap();
+        let (keyring, data_key) = Keyring::generate(&password_key, &master_pub(&master)).unwrap();
+        (keyring, data_key, password_key, master)
+    }
+
+    fn master_pub(master: &RsaKeyPair) -> PublicKey {
+        PublicKey::from_pem(&master.public_key_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_unlock_methods_return_same_key() {
+        let (keyring, data_key, password_key, master) = generate();
+        let encrypted = data_key.cipher().encrypt(b"secret").unwrap();
+
+        let by_password = keyring.unlock_with_password(&password_key).unwrap();
+        let by_rsa = keyring.unlock_with_rsa(&master).unwrap().unwrap();
+        assert_eq!(by_password.cipher().decrypt(&encrypted).unwrap(), b"secret");
+        assert_eq!(by_rsa.cipher().decrypt(&encrypted).unwrap(), b"secret");
+        assert!(keyring.unlock_with_password(&AES::new("wrong").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_add_and_remove_yubikey() {
+        let (mut keyring, data_key, _, _) = generate();
+        let device_key = RsaKeyPair::new().unwrap();
+        let pem = device_key.public_key_pem().unwrap();
+        keyring.add_yubikey(&data_key, 42, &pem).unwrap();
+        keyring.add_yubikey(&data_key, 42, &pem).unwrap();
+        assert_eq!(keyring.wrapped.len(), 3);
+
+        let wrapped = keyring
+            .wrapped
+            .iter()
+            .find_map(|w| match w {
+                WrappedKey::Yubikey { serial: 42, key } => Some(key.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let unwrapped = device_key.decrypt(&BASE64.decode(wrapped).unwrap()).unwrap();
+        assert_eq!(DataKey::from_bytes(&keyring.id, &unwrapped).unwrap().key(), data_key.key());
+
+        keyring.remove_yubikey(42);
+        assert_eq!(keyring.wrapped.len(), 2);
+    }
+
+    #[test]
+    fn test_unlock_with_yubikey() {
+        let (mut keyring, data_key, _, _) = generate();
+        let device_key = RsaKeyPair::new().unwrap();
+        assert!(!keyring.has_yubikey(42));
+        keyring.add_yubikey(&data_key, 42, &device_key.public_key_pem().unwrap()).unwrap();
+        assert!(keyring.has_yubikey(42));
+
+        let unlocked = keyring.unlock_with_yubikey(42, &device_key).unwrap();
+        assert_eq!(unlocked.key(), data_key.key());
+        assert_eq!(unlocked.id(), keyring.id());
+        assert!(keyring.unlock_with_yubikey(7, &device_key).is_err());
+    }
+
+    #[test]
+    fn test_policy_defaults_to_password() {
+        let (keyring, _, password_key, _) = generate();
+        let mut json: serde_json::Value = serde_json::from_str(&keyring.to_json(&password_key).unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("policy");
+        let loaded: Keyring = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.policy(), UnlockPolicy::Password);
+        assert!(!loaded.policy().needs_yubikey());
+    }
+
+    #[test]
+    fn test_policy_is_authenticated() {
+        let (mut keyring, data_key, password_key, master) = generate();
+        let device_key = RsaKeyPair::new().unwrap();
+        let device_pem = device_key.public_key_pem().unwrap();
+        let cards = [(42, device_pem.as_str())];
+        keyring
+            .apply_policy(&data_key, UnlockPolicy::Yubikey, &password_key, &master_pub(&master), &cards)
+            .unwrap();
+        let json = keyring.to_json(&password_key).unwrap();
+        let loaded: Keyring = serde_json::from_str(&json).unwrap();
+        loaded.verify(&password_key).unwrap();
+        assert!(matches!(loaded.verify(&AES::new("password").unwrap()), Err(Error::Tampered(_))));
+
+        let downgraded: Keyring = serde_json::from_str(&json.replace("\"yubikey\"", "\"password\"")).unwrap();
+        assert_eq!(downgraded.policy(), UnlockPolicy::Password);
+        assert!(matches!(downgraded.verify(&password_key), Err(Error::Tampered(_))));
+        let mut unsigned: serde_json::Value = serde_json::from_str(&json).unwrap();
+        unsigned.as_object_mut().unwrap().remove("mac");
+        let unsigned: Keyring = serde_json::from_value(unsigned).unwrap();
+        assert!(!unsigned.is_signed());
+        assert!(matches!(unsigned.verify(&password_key), Err(Error::Tampered(_))));
+    }
+
+    #[test]
+    fn test_yubikey_policy_needs_the_card() {
+        let (mut keyring, data_key, password_key, master) = generate();
+        let device_key = RsaKeyPair::new().unwrap();
+        let device_pem = device_key.public_key_pem().unwrap();
+        let cards = [(42, device_pem.as_str())];
+        let master_pub = master_pub(&master);
+        assert!(keyring.apply_policy(&data_key, UnlockPolicy::Yubikey, &password_key, &master_pub, &[]).is_err());
+
+        keyring.apply_policy(&data_key, UnlockPolicy::Yubikey, &password_key, &master_pub, &cards).unwrap();
+        // Neither the password nor the RSA master key reach the data key
+        assert!(keyring.unlock_with_password(&password_key).is_err());
+        assert!(keyring.unlock_with_rsa(&master).unwrap().is_none());
+        assert!(keyring.open(Some(&password_key), None).is_err());
+        let card_copy = keyring.unlock_with_yubikey(42, &device_key).unwrap();
+        assert_eq!(keyring.open(None, Some(&card_copy)).unwrap().key(), data_key.key());
+
+        keyring.apply_policy(&data_key, UnlockPolicy::Password, &password_key, &master_pub, &cards).unwrap();
+        assert_eq!(keyring.open(Some(&password_key), None).unwrap().key(), data_key.key());
+        assert_eq!(keyring.unlock_with_rsa(&master).unwrap().unwrap().key(), data_key.key());
+    }
+
+    #[test]
+    fn test_password_and_yubikey_policy_needs_both() {
+        let (mut keyring, data_key, password_key, master) = generate();
+        let device_key = RsaKeyPair::new().unwrap();
+        let device_pem = device_key.public_key_pem().unwrap();
+        let cards = [(42, device_pem.as_str())];
+        let share = keyring
+            .apply_policy(&data_key, UnlockPolicy::PasswordAndYubikey, &password_key, &master_pub(&master), &cards)
+            .unwrap();
+        let card_copy = keyring.unlock_with_yubikey(42, &device_key).unwrap();
+        assert_eq!(card_copy.key(), share.key());
+        // Each factor alone holds a random share, not the data key
+        assert_ne!(card_copy.key(), data_key.key());
+        assert_ne!(keyring.unlock_with_password(&password_key).unwrap().key(), data_key.key());
+        assert_ne!(keyring.unlock_with_rsa(&master).unwrap().unwrap().key(), data_key.key());
+        assert!(keyring.open(Some(&password_key), None).is_err());
+        assert!(keyring.open(None, Some(&card_copy)).is_err());
+        assert_eq!(keyring.open(Some(&password_key), Some(&card_copy)).unwrap().key(), data_key.key());
+        // A backup card gets the same share
+        assert_eq!(keyring.card_copy(&data_key, &password_key).unwrap().key(), share.key());
+    }
+
+    #[test]
+    fn test_recover_password() {
+        let (mut keyring, data_key, _, master) = generate();
+        let new = AES::new("new").unwrap();
+        keyring.recover_password(&master, &new).unwrap();
+        assert_eq!(keyring.unlock_with_password(&new).unwrap().key(), data_key.key());
+        assert_eq!(keyring.wrapped.len(), 2);
+    }
+
+    #[test]
+    fn test_rewrap_password() {
+        let (mut keyring, data_key, old, _) = generate();
+        let new = AES::new("new").unwrap();
+        keyring.rewrap_password(&old, &new).unwrap();
+        assert!(keyring.unlock_with_password(&old).is_err());
+        assert_eq!(keyring.unlock_with_password(&new).unwrap().key(), data_key.key());
+    }
+}
@@ -0,0 +1,71 @@
+use secrecy::{ExposeSecret, SecretBox};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Key material kept in memory for the session.
+///
+/// The secret lives in a `SecretBox` so it never moves, its pages are locked out
+/// of swap where the OS allows it, and it is zeroized when dropped. `Debug`
+/// never prints it.
+pub struct Locked<S: Zeroize>(SecretBox<S>);
+
+impl<S: Zeroize> Locked<S> {
+    pub fn new(secret: S) -> Self {
+        let locked = Self(SecretBox::new(Box::new(secret)));
+        // Best effort, locking fails past RLIMIT_MEMLOCK and the key is still usable
+        #[cfg(unix)]
+        unsafe {
+            libc::mlock(locked.address(), std::mem::size_of::<S>());
+        }
+        locked
+    }
+
+    #[cfg(unix)]
+    fn address(&self) -> *const libc::c_void {
+        self.0.expose_secret() as *const S as *const libc::c_void
+    }
+}
+
+impl<S: Zeroize> ExposeSecret<S> for Locked<S> {
+    fn expose_secret(&self) -> &S {
+        self.0.expose_secret()
+    }
+}
+
+impl<S: Zeroize> Drop for Locked<S> {
+    fn drop(&mut self) {
+        // `SecretBox` zeroizes again when dropped, the pages must be wiped before they are unlocked
+        self.0.zeroize();
+        #[cfg(unix)]
+        unsafe {
+            libc::munlock(self.address(), std::mem::size_of::<S>());
+        }
+    }
+}
+
+impl<S: Zeroize> fmt::Debug for Locked<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Locked([REDACTED {}])", std::any::type_name::<S>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let locked = Locked::new([7u8; 32]);
+        assert_eq!(locked.expose_secret(), &[7u8; 32]);
+        let debug = format!("{:?}", locked);
+        assert!(debug.contains("REDACTED"));
+        assert!(!debug.contains('7'));
+    }
+
+    #[test]
+    fn test_zeroize_wipes_the_secret() {
+        let mut locked = Locked::new([7u8; 32]);
+        locked.0.zeroize();
+        assert_eq!(locked.expose_secret(), &[0u8; 32]);
+    }
+}
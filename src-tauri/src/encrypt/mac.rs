@@ -0,0 +1,87 @@
+use crate::encrypt::{Error, Result, AES};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+/// Purpose of the key authenticating `yubikey_settings.json`.
+pub const YUBIKEY_SETTINGS: &str = "yubikey-settings";
+/// Purpose of the key authenticating the `keyring.json` of every vault.
+pub const VAULT_KEYRING: &str = "vault-keyring";
+
+// Form of an authenticated file on disk, the MAC covers the serialized payload
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Signed {
+    payload: Value,
+    mac: String,
+}
+
+fn hmac(master: &AES, purpose: &str, payload: &Value) -> Result<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&master.derive_key(purpose)?)
+        .map_err(|e| Error::Custom(e.to_string()))?;
+    // `Value` keeps object keys sorted, signing and checking see the same bytes
+    mac.update(&serde_json::to_vec(payload)?);
+    Ok(mac)
+}
+
+/// Base64 MAC of `payload` made with a key derived from the master key for `purpose`.
+pub fn tag(master: &AES, purpose: &str, payload: &Value) -> Result<String> {
+    Ok(BASE64.encode(hmac(master, purpose, payload)?.finalize().into_bytes()))
+}
+
+/// Checks a MAC made by `tag`, failing with `Error::Tampered` when it does not match.
+pub fn verify(master: &AES, purpose: &str, payload: &Value, mac: &str) -> Result<()> {
+    let tag = BASE64
+        .decode(mac)
+        .map_err(|_| Error::Tampered(format!("Invalid MAC for {}", purpose)))?;
+    hmac(master, purpose, payload)?
+        .verify_slice(&tag)
+        .map_err(|_| Error::Tampered(format!("{} was modified outside the app", purpose)))
+}
+
+/// Serializes `payload` with a MAC made with a key derived from the master key for `purpose`.
+pub fn sign(master: &AES, purpose: &str, payload: Value) -> Result<String> {
+    let mac = tag(master, purpose, &payload)?;
+    Ok(serde_json::to_string(&Signed { payload, mac })?)
+}
+
+/// Reads a file written by `sign`, failing with `Error::Tampered` when the MAC
+/// is missing or does not match.
+pub fn open(master: &AES, purpose: &str, content: &str) -> Result<Value> {
+    let signed: Signed = serde_json::from_str(content)
+        .map_err(|_| Error::Tampered(format!("{} is not authenticated", purpose)))?;
+    verify(master, purpose, &signed.payload, &signed.mac)?;
+    Ok(signed.payload)
+}
+
+/// Payload of a file written by `sign`, without checking the MAC.
+pub fn read_unverified(content: &str) -> Result<Value> {
+    let signed: Signed = serde_json::from_str(content)?;
+    Ok(signed.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sign_and_open() {
+        let master = AES::new("password").unwrap();
+        let signed = sign(&master, YUBIKEY_SETTINGS, json!({"keys": [{"serial": 1}]})).unwrap();
+        assert_eq!(open(&master, YUBIKEY_SETTINGS, &signed).unwrap(), json!({"keys": [{"serial": 1}]}));
+        // Keys derived for another purpose or from another password do not match
+        assert!(matches!(open(&master, "other", &signed), Err(Error::Tampered(_))));
+        assert!(matches!(open(&AES::new("password").unwrap(), YUBIKEY_SETTINGS, &signed), Err(Error::Tampered(_))));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let master = AES::new("password").unwrap();
+        let signed = sign(&master, YUBIKEY_SETTINGS, json!({"keys": [{"serial": 1}]})).unwrap();
+        let swapped = signed.replace("\"serial\":1", "\"serial\":2");
+        assert_ne!(swapped, signed);
+        assert!(matches!(open(&master, YUBIKEY_SETTINGS, &swapped), Err(Error::Tampered(_))));
+        assert!(matches!(open(&master, YUBIKEY_SETTINGS, r#"{"keys": []}"#), Err(Error::Tampered(_))));
+    }
+}
@@ -1,8 +1,12 @@
 use crate::{AppState, FileSystem};
+use crate::file_system::Transaction;
 use crate::encrypt::error::{Error, Result};
-use crate::encrypt::{AES, RsaKeyPair};
+use crate::encrypt::{mac, AES, Envelope, Keyring, PublicKey, Recovery, RsaKeyPair, Verifier};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+// File name of the vault keyrings, see `FileSystem::vault_keyring`
+const KEYRING: &str = "keyring.json";
 
 pub struct MasterPassword;
 
@@ -13,30 +17,33 @@ impl MasterPassword {
         private_key: Option<&str>,
     ) -> Result<String> {
         let fs = state.file_system();
+        // Setting up again would leave the vault keys wrapped for the old key
+        if fs.master_verifier().exists() || fs.legacy_master_password().exists() {
+            return Err(Error::Custom("The master password is already set, change it instead".to_string()));
+        }
         let encryptor = Self::store_master_password(fs, password)?;
         let pk = match private_key {
             Some(pk) => RsaKeyPair::from_string(pk)?,
             None => RsaKeyPair::new()?,
         };
-        Self::store_pk(fs, pk, encryptor)?;
-        state.set_master_password(password.to_string());
+        Self::store_pk(fs, pk, &encryptor)?;
+        state.set_master_key(encryptor);
         state.set_authenticated(true);
 
         Ok("Master password saved".to_string())
     }
 
+    // Only a key check record is written, never the password
     fn store_master_password(fs: &FileSystem, password: &str) -> Result<AES> {
-        let encryptor = AES::new(password);
-        let encrypted = encryptor.encrypt(password.as_bytes())?;
-        let path = fs.master_password();
-        fs::write(path, encrypted)?;
+        let encryptor = AES::new(password)?;
+        fs::write(fs.master_verifier(), Verifier::new(&encryptor)?.to_json()?)?;
         Ok(encryptor)
     }
 
     fn store_pk(
         fs: &FileSystem,
         pk: RsaKeyPair,
-        password_encryptor: AES,
+        password_encryptor: &AES,
     ) -> Result<()> {
         let master_pk = fs.master_pk();
         let pk_for_default_path = Path::new(&master_pk);
@@ -51,10 +58,15 @@ impl MasterPassword {
     }
     pub fn verify(state: &mut AppState, password: &str) -> Result<String> {
         let fs = state.file_system();
-        println!("Verifying master password {}", password);
         match Self::do_verify_password(fs, password) {
-            Ok(_) => {
-                state.set_master_password(password.to_string());
+            Ok(encryptor) => {
+                // Re-encrypt under the current KDF defaults and envelope version
+                let encryptor = if Self::needs_upgrade(fs, &encryptor)? {
+                    Self::reencrypt_all(fs, &encryptor, password)?
+                } else {
+                    encryptor
+                };
+                state.set_master_key(encryptor);
                 state.set_authenticated(true);
                 Ok("Master password correct".to_string())
             }
@@ -65,25 +77,196 @@ impl MasterPassword {
     }
 
     fn do_verify_password(fs: &FileSystem, password: &str) -> Result<AES> {
-        let path = fs.master_password();
-        let encoded = fs::read_to_string(path)?;
-    
+        if let Some(verifier) = Self::verifier(fs)? {
+            return verifier.unlock(password);
+        }
+        // Vaults from older versions store the password encrypted with itself
+        let encoded = fs::read_to_string(fs.legacy_master_password())?;
         let encryptor = AES::from_encrypted(password, &encoded)?;
-    
         encryptor.decrypt(&encoded)?;
         Ok(encryptor)
     }
 
-    pub fn from_state(state: &AppState) -> Result<AES> {
-        Self::get_encryptor(state)
+    // None for vaults of older versions, with the legacy password file
+    fn verifier(fs: &FileSystem) -> Result<Option<Verifier>> {
+        let path = fs.master_verifier();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Verifier::from_json(&fs::read_to_string(path)?)?))
+    }
+
+    /// True once signed YubiKey settings were written, see `Verifier::signs_settings`.
+    pub fn signs_settings(fs: &FileSystem) -> Result<bool> {
+        Ok(Self::verifier(fs)?.is_some_and(|verifier| verifier.signs_settings()))
+    }
+
+    /// Records that the YubiKey settings are signed from now on, their
+    /// migration is over.
+    pub fn record_signed_settings(state: &AppState) -> Result<()> {
+        let fs = state.file_system();
+        if Self::signs_settings(fs)? {
+            return Ok(());
+        }
+        fs::write(fs.master_verifier(), Verifier::new(Self::from_state(state)?)?.to_json()?)?;
+        Ok(())
+    }
+
+    // The legacy password file goes away with the re-encryption
+    fn needs_upgrade(fs: &FileSystem, encryptor: &AES) -> Result<bool> {
+        let envelope = Envelope::decode(&fs::read_to_string(fs.master_pk())?)?;
+        Ok(encryptor.kdf().map(|kdf| kdf.is_outdated()).unwrap_or(false)
+            || envelope.is_legacy()
+            || fs.legacy_master_password().exists())
     }
 
-    pub fn get_encryptor(state: &AppState) -> Result<AES> {
+    /// Moves the master key, the RSA private key and every secret to a new
+    /// password. It does not unlock the session, see `lockout::change_master_password`.
+    pub fn change(state: &mut AppState, old_password: &str, new_password: &str) -> Result<String> {
         let fs = state.file_system();
-        let password = state.master_password().ok_or(Error::Custom(
-            "NoMasterPassword in master_password".to_string(),
-        ))?;
-        Self::do_verify_password(fs, &password)
+        let old = Self::do_verify_password(fs, old_password)
+            .map_err(|_| Error::WrongPassword("Master password incorrect".to_string()))?;
+        let new = Self::reencrypt_all(fs, &old, new_password)?;
+        state.set_master_key(new);
+        Ok("Master password changed".to_string())
+    }
+
+    /// Writes a new recovery key for the RSA master private key and returns
+    /// it, to be shown once. A previous recovery key stops working.
+    pub fn create_recovery_key(state: &AppState) -> Result<String> {
+        let master_pk = RsaKeyPair::try_from(state)?;
+        let recovery_key = Recovery::generate_key();
+        Recovery::new(&recovery_key, &master_pk)?.save(state.file_system())?;
+        Ok(recovery_key)
+    }
+
+    /// Sets a new master password with the recovery key, when the old one is
+    /// lost. Vault keys are unwrapped with the RSA master private key the
+    /// recovery key opens, then wrapped for the new password. The YubiKey
+    /// settings are dropped: vaults requiring a YubiKey keep their policy and
+    /// open again once the card is enrolled again.
+    pub fn recover(state: &mut AppState, recovery_key: &str, new_password: &str) -> Result<String> {
+        let fs = state.file_system();
+        let recovery = Recovery::load(fs)?
+            .ok_or(Error::Custom("The vault was set up without a recovery key".to_string()))?;
+        let master_pk = recovery.open(recovery_key)?;
+        let new = AES::new(new_password)?;
+        let mut transaction = Transaction::new(fs)?;
+        transaction.stage(&fs.master_verifier(), Verifier::new(&new)?.to_json()?)?;
+        transaction.stage(&fs.master_pk(), new.encrypt(master_pk.private_key_pem()?.as_bytes())?)?;
+        let master_pub = PublicKey::from_pem(&fs::read_to_string(fs.master_pub())?)?;
+        for folder in Self::vault_folders(fs)? {
+            let path = folder.join(KEYRING);
+            let keyring = if path.exists() {
+                // Signed with the lost master key, the keyring is signed again with the new one
+                let mut keyring = Keyring::load(&path)?;
+                keyring.recover_password(&master_pk, &new)?;
+                keyring
+            } else if Self::secret_files(&folder)?.is_empty() {
+                Keyring::generate(&new, &master_pub)?.0
+            } else {
+                // Only the lost master key opens the secrets of a vault without a data key
+                return Err(Error::Custom(format!(
+                    "{} was never unlocked with a data key, the recovery key can not open it",
+                    folder.display()
+                )));
+            };
+            transaction.stage(&path, keyring.to_json(&new)?)?;
+        }
+        transaction.commit()?;
+        // Authenticated with the lost master key, the settings can not be
+        // trusted anymore: YubiKeys are enrolled again
+        for stale in [fs.yubikey_settings(), fs.legacy_master_password()] {
+            if stale.exists() {
+                fs::remove_file(stale)?;
+            }
+        }
+        state.log_out();
+        state.set_master_key(new);
+        state.set_authenticated(true);
+        Ok("Master password recovered".to_string())
+    }
+
+    // Stages every file and vault keyring under the new key and swaps them in a single transaction
+    fn reencrypt_all(fs: &FileSystem, old: &AES, new_password: &str) -> Result<AES> {
+        let new = AES::new(new_password)?;
+        let verifier = match Self::verifier(fs)? {
+            Some(verifier) => verifier.rekey(&new)?,
+            None => Verifier::with_unsigned_settings(&new)?,
+        };
+        let mut transaction = Transaction::new(fs)?;
+        transaction.stage(&fs.master_verifier(), verifier.to_json()?)?;
+        for path in Self::encrypted_files(fs)? {
+            let plain = old.decrypt(&fs::read_to_string(&path)?)?;
+            transaction.stage(&path, new.encrypt(&plain)?)?;
+        }
+        for path in Self::vault_folders(fs)? {
+            let path = path.join(KEYRING);
+            if path.exists() {
+                let mut keyring = Keyring::load(&path)?;
+                keyring.verify(old)?;
+                keyring.rewrap_password(old, &new)?;
+                transaction.stage(&path, keyring.to_json(&new)?)?;
+            }
+        }
+        // The YubiKey settings MAC key is derived from the master key. Settings
+        // that do not check out are left as they are and stay rejected
+        let settings = fs.yubikey_settings();
+        if settings.exists()
+            && let Ok(payload) = mac::open(old, mac::YUBIKEY_SETTINGS, &fs::read_to_string(&settings)?)
+        {
+            transaction.stage(&settings, mac::sign(&new, mac::YUBIKEY_SETTINGS, payload)?)?;
+        }
+        transaction.commit()?;
+        // Left behind by a crash here, it is removed again on the next login
+        let legacy = fs.legacy_master_password();
+        if legacy.exists() {
+            fs::remove_file(legacy)?;
+        }
+        Ok(new)
+    }
+
+    /// Every file encrypted with the master password key. Secrets are only
+    /// included for vaults not yet migrated to a data key.
+    pub(crate) fn encrypted_files(fs: &FileSystem) -> Result<Vec<PathBuf>> {
+        let mut files = vec![fs.master_pk()];
+        for vault in Self::vault_folders(fs)? {
+            if !vault.join(KEYRING).exists() {
+                files.extend(Self::secret_files(&vault)?);
+            }
+        }
+        Ok(files.into_iter().filter(|path| path.exists()).collect())
+    }
+
+    fn secret_files(vault: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(vault)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().map(|s| s == "enc").unwrap_or(false) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn vault_folders(fs: &FileSystem) -> Result<Vec<PathBuf>> {
+        let mut folders = vec![];
+        for entry in fs::read_dir(fs.vaults_folder())? {
+            let path = entry?.path();
+            if path.is_dir() {
+                folders.push(path);
+            }
+        }
+        Ok(folders)
+    }
+
+    pub fn from_state(state: &AppState) -> Result<&AES> {
+        Self::get_encryptor(state)
+    }
+
+    /// Master key unlocked in this session.
+    pub fn get_encryptor(state: &AppState) -> Result<&AES> {
+        state.master_key().ok_or(Error::NoMasterPassword)
     }
 }
 
@@ -99,12 +282,29 @@ mod tests {
         let mut app_state = AppState::new_unauthenticated_test();
         let password = "secret";
         MasterPassword::save(&mut app_state, password, None).unwrap();
-        assert_eq!(app_state.master_password().unwrap(), password);
+        assert!(app_state.has_master_key());
         assert!(app_state.is_authenticated());
         assert!(app_state.file_system().master_pk().exists());
         assert!(app_state.file_system().master_pub().exists());
     }
 
+    #[test]
+    fn test_save_refuses_existing_password() {
+        let mut app_state = AppState::new_test("secret");
+        let fs = app_state.file_system().clone();
+        let verifier = fs::read_to_string(fs.master_verifier()).unwrap();
+        let master_pk = fs::read_to_string(fs.master_pk()).unwrap();
+        assert!(matches!(MasterPassword::save(&mut app_state, "other", None), Err(Error::Custom(_))));
+        assert_eq!(fs::read_to_string(fs.master_verifier()).unwrap(), verifier);
+        assert_eq!(fs::read_to_string(fs.master_pk()).unwrap(), master_pk);
+
+        // Vaults of older versions only have the legacy password file
+        fs::remove_file(fs.master_verifier()).unwrap();
+        fs::write(fs.legacy_master_password(), "legacy").unwrap();
+        assert!(MasterPassword::save(&mut app_state, "other", None).is_err());
+        assert!(!fs.master_verifier().exists());
+    }
+
     #[test]
     fn test_verify() {
         let password = "secret";
@@ -112,16 +312,18 @@ mod tests {
         assert!(app_state.file_system().master_pk().exists());
         assert!(app_state.file_system().master_pub().exists());
         MasterPassword::verify(&mut app_state, password).unwrap();
-        assert_eq!(app_state.master_password().unwrap(), password);
+        assert!(app_state.has_master_key());
         assert!(app_state.is_authenticated());
     }
 
     #[test]
     fn test_get_encryptor() {
         let password = "secret";
-        let app_state = AppState::new_test(password);
+        let mut app_state = AppState::new_test(password);
         let encryptor = MasterPassword::get_encryptor(&app_state);
         assert!(encryptor.is_ok());
+        app_state.log_out();
+        assert!(matches!(MasterPassword::get_encryptor(&app_state), Err(Error::NoMasterPassword)));
     }
 
     fn long_text() -> String {
@@ -160,4 +362,213 @@ mod tests {
         let decrypted = encryptor.decrypt_string(&encrypted).unwrap();
         assert_eq!(long_text(), decrypted);
     }
+
+    #[test]
+    fn test_verify_upgrades_legacy_kdf() {
+        use crate::encrypt::Kdf;
+        use crate::secrets::Secret;
+        use crate::vaults::DEFAULT_VAULT;
+
+        let password = "secret";
+        let mut app_state = AppState::new_test(password);
+        let fs = app_state.file_system().clone();
+        let current = MasterPassword::get_encryptor(&app_state).unwrap();
+        let secret_path = fs.secret_path(DEFAULT_VAULT, "legacy");
+        let legacy = AES::with_kdf(password, Kdf::Sha3).unwrap();
+        for path in MasterPassword::encrypted_files(&fs).unwrap() {
+            let plain = current.decrypt(&fs::read_to_string(&path).unwrap()).unwrap();
+            fs::write(&path, legacy.encrypt(&plain).unwrap()).unwrap();
+        }
+        let secret = r#"{"id":"legacy","kind":"k","name":"n","encryption":"AES","value":"v"}"#;
+        fs::write(&secret_path, legacy.encrypt(secret.as_bytes()).unwrap()).unwrap();
+        // Older versions kept the password encrypted with itself instead of a verifier
+        fs::write(fs.legacy_master_password(), legacy.encrypt(password.as_bytes()).unwrap()).unwrap();
+        fs::remove_file(fs.master_verifier()).unwrap();
+
+        MasterPassword::verify(&mut app_state, password).unwrap();
+
+        assert!(!fs.legacy_master_password().exists());
+        assert!(fs.master_verifier().exists());
+        let upgraded = MasterPassword::get_encryptor(&app_state).unwrap();
+        assert_eq!(upgraded.kdf(), Some(Kdf::default()));
+        assert!(!Envelope::decode(&fs::read_to_string(&secret_path).unwrap()).unwrap().is_legacy());
+        assert!(upgraded.decrypt(&fs::read_to_string(&secret_path).unwrap()).is_ok());
+        assert!(upgraded.decrypt(&fs::read_to_string(fs.master_pk()).unwrap()).is_ok());
+        assert_eq!(Secret::all(&app_state, DEFAULT_VAULT).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_change_master_password() {
+        use crate::secrets::Secret;
+        use crate::vaults::{Vault, DEFAULT_VAULT};
+
+        let mut app_state = AppState::new_test("old");
+        Vault::create(&app_state, "work").unwrap();
+        let form = r#"{"kind":"k","name":"n","encryption":"AES","value":"v"}"#;
+        let secret = Secret::from(serde_json::from_str::<crate::secrets::NewSecretForm>(form).unwrap());
+        secret.save(&app_state, "work").unwrap();
+        let data_key_id = Vault::find(&app_state, "work").unwrap().data_key(&app_state).unwrap().id().to_string();
+        let secret_id = serde_json::to_value(&secret).unwrap()["id"].as_str().unwrap().to_string();
+        let secret_path = app_state.file_system().secret_path("work", &secret_id);
+        let encrypted_secret = fs::read_to_string(&secret_path).unwrap();
+
+        assert!(matches!(
+            MasterPassword::change(&mut app_state, "wrong", "new"),
+            Err(Error::WrongPassword(_))
+        ));
+        MasterPassword::change(&mut app_state, "old", "new").unwrap();
+        assert!(RsaKeyPair::try_from(&app_state as &AppState).is_ok());
+
+        app_state.log_out();
+        assert!(MasterPassword::verify(&mut app_state, "old").is_err());
+        MasterPassword::verify(&mut app_state, "new").unwrap();
+        assert_eq!(Secret::all(&app_state, "work").unwrap().len(), 1);
+        assert!(Secret::all(&app_state, DEFAULT_VAULT).unwrap().is_empty());
+        // Only the wrapped data key changed
+        assert_eq!(fs::read_to_string(&secret_path).unwrap(), encrypted_secret);
+        let new_data_key = Vault::find(&app_state, "work").unwrap().data_key(&app_state).unwrap();
+        assert_eq!(new_data_key.id(), data_key_id);
+        assert!(RsaKeyPair::try_from(&app_state as &AppState).is_ok());
+        // The YubiKey settings are authenticated with the new master key
+        assert_eq!(crate::yubikey::YubiKeySettings::load(&app_state).unwrap().keys().len(), 1);
+        assert!(!app_state.file_system().pending_folder().exists());
+    }
+
+    #[test]
+    fn test_interrupted_change_keeps_old_password() {
+        let mut app_state = AppState::new_test("old");
+        let fs = app_state.file_system().clone();
+        let old = MasterPassword::get_encryptor(&app_state).unwrap();
+        let new = AES::new("new").unwrap();
+
+        // Crash before the commit marker is written
+        let mut transaction = Transaction::new(&fs).unwrap();
+        transaction.stage(&fs.master_verifier(), Verifier::new(&new).unwrap().to_json().unwrap()).unwrap();
+        for path in MasterPassword::encrypted_files(&fs).unwrap() {
+            let plain = old.decrypt(&fs::read_to_string(&path).unwrap()).unwrap();
+            transaction.stage(&path, new.encrypt(&plain).unwrap()).unwrap();
+        }
+        drop(transaction);
+        fs.init().unwrap();
+
+        app_state.log_out();
+        MasterPassword::verify(&mut app_state, "old").unwrap();
+        assert!(RsaKeyPair::try_from(&app_state as &AppState).is_ok());
+    }
+
+    #[test]
+    fn test_password_is_never_written() {
+        fn files(folder: &Path, out: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(folder).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    files(&path, out);
+                } else {
+                    out.push(path);
+                }
+            }
+        }
+        let password = "correct horse battery staple";
+        let mut app_state = AppState::new_test("old");
+        MasterPassword::change(&mut app_state, "old", password).unwrap();
+        app_state.log_out();
+        MasterPassword::verify(&mut app_state, password).unwrap();
+
+        let master = MasterPassword::get_encryptor(&app_state).unwrap();
+        let mut paths = vec![];
+        files(&app_state.file_system().root(), &mut paths);
+        assert!(!paths.is_empty());
+        for path in paths {
+            let content = fs::read(&path).unwrap();
+            assert!(!content.windows(password.len()).any(|w| w == password.as_bytes()), "{:?}", path);
+            let decrypted = String::from_utf8(content).ok().and_then(|text| master.decrypt(&text).ok());
+            assert_ne!(decrypted.as_deref(), Some(password.as_bytes()), "{:?}", path);
+        }
+        assert!(!app_state.file_system().legacy_master_password().exists());
+    }
+
+    #[test]
+    fn test_recover_with_recovery_key() {
+        use crate::secrets::Secret;
+        use crate::vaults::Vault;
+
+        let mut app_state = AppState::new_test("lost");
+        let recovery_key = MasterPassword::create_recovery_key(&app_state).unwrap();
+        Vault::create(&app_state, "work").unwrap();
+        let form = r#"{"kind":"k","name":"n","encryption":"AES","value":"v"}"#;
+        let secret = Secret::from(serde_json::from_str::<crate::secrets::NewSecretForm>(form).unwrap());
+        secret.save(&app_state, "work").unwrap();
+        // The recovery key outlives password changes
+        MasterPassword::change(&mut app_state, "lost", "also lost").unwrap();
+        app_state.log_out();
+
+        let wrong = crate::encrypt::Recovery::generate_key();
+        assert!(matches!(MasterPassword::recover(&mut app_state, &wrong, "new"), Err(Error::WrongPassword(_))));
+        assert!(!app_state.is_authenticated());
+        MasterPassword::recover(&mut app_state, &recovery_key, "new").unwrap();
+        assert!(app_state.is_authenticated());
+
+        app_state.log_out();
+        assert!(MasterPassword::verify(&mut app_state, "also lost").is_err());
+        MasterPassword::verify(&mut app_state, "new").unwrap();
+        assert_eq!(Secret::all(&app_state, "work").unwrap().len(), 1);
+        assert!(RsaKeyPair::try_from(&app_state as &AppState).is_ok());
+        // Settings signed with the lost key are dropped, not trusted
+        assert!(!app_state.file_system().yubikey_settings().exists());
+        assert!(crate::yubikey::YubiKeySettings::load(&app_state).unwrap().keys().is_empty());
+    }
+
+    #[test]
+    fn test_recover_keeps_yubikey_vaults_bound_to_the_card() {
+        use crate::encrypt::UnlockPolicy;
+        use crate::vaults::Vault;
+
+        let mut app_state = AppState::new_test("lost");
+        let recovery_key = MasterPassword::create_recovery_key(&app_state).unwrap();
+        let work = Vault::create(&app_state, "work").unwrap();
+        let info = crate::yubikey::list_yubikeys(app_state.piv()).unwrap().remove(0);
+        let (serial, pub_key) = (info.serial.unwrap(), info.pub_key.unwrap());
+        crate::yubikey::enroll(&app_state, serial, None, pub_key.clone()).unwrap();
+        crate::yubikey::unlock(&mut app_state, "123456", None).unwrap();
+        work.set_policy(&mut app_state, UnlockPolicy::Yubikey).unwrap();
+        let key_id = work.data_key(&app_state).unwrap().id().to_string();
+        // Left by an older version, without a data key yet
+        let legacy = app_state.file_system().vault_folder("legacy");
+        fs::create_dir_all(&legacy).unwrap();
+        app_state.log_out();
+
+        MasterPassword::recover(&mut app_state, &recovery_key, "new").unwrap();
+        assert!(legacy.join(KEYRING).exists());
+        Vault::find(&app_state, "legacy").unwrap().data_key(&app_state).unwrap();
+        // The recovery key reaches what the password reaches, not the card
+        assert_eq!(work.policy(&app_state).unwrap(), UnlockPolicy::Yubikey);
+        assert!(work.data_key(&app_state).is_err());
+        crate::yubikey::enroll(&app_state, serial, None, pub_key).unwrap();
+        crate::yubikey::unlock(&mut app_state, "123456", None).unwrap();
+        assert_eq!(work.data_key(&app_state).unwrap().id(), key_id);
+    }
+
+    #[test]
+    fn test_recover_refuses_vaults_only_the_lost_key_opens() {
+        let mut app_state = AppState::new_test("lost");
+        let recovery_key = MasterPassword::create_recovery_key(&app_state).unwrap();
+        let legacy = app_state.file_system().vault_folder("legacy");
+        fs::create_dir_all(&legacy).unwrap();
+        let sealed = MasterPassword::get_encryptor(&app_state).unwrap().encrypt(b"secret").unwrap();
+        fs::write(legacy.join("secret.enc"), sealed).unwrap();
+        app_state.log_out();
+
+        assert!(matches!(MasterPassword::recover(&mut app_state, &recovery_key, "new"), Err(Error::Custom(_))));
+        assert!(!app_state.is_authenticated());
+        MasterPassword::verify(&mut app_state, "lost").unwrap();
+    }
+
+    #[test]
+    fn test_recover_without_recovery_key() {
+        let mut app_state = AppState::new_test("password");
+        app_state.log_out();
+        let key = crate::encrypt::Recovery::generate_key();
+        assert!(MasterPassword::recover(&mut app_state, &key, "new").is_err());
+        MasterPassword::verify(&mut app_state, "password").unwrap();
+    }
 }
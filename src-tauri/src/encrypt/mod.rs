@@ -1,17 +1,87 @@
 pub mod error;
 mod rsa;
 mod aes;
-mod ecc;
+mod kdf;
+mod envelope;
+pub mod hybrid;
+pub mod padding;
+pub mod mac;
+mod keyring;
+pub mod ecc;
 mod master_password;
+mod verifier;
+mod locked;
+mod recovery;
 pub use error::{Error, Result};
 pub use aes::AES;
+pub use kdf::Kdf;
+pub use envelope::{Envelope, Cipher};
+pub use keyring::{DataKey, Keyring, OpenedKey, UnlockPolicy};
 pub use rsa::{RsaKeyPair, PublicKey};
+pub use ecc::PublicKey as EccPublicKey;
 pub use master_password::MasterPassword;
+pub use verifier::Verifier;
+pub use locked::Locked;
+pub use recovery::Recovery;
 
-// TODO: Implement Encrypt trait
-// pub trait Encrypt {
-//     fn encrypt(&self, data: impl Into<String>) -> Result<String>;
-//     fn decrypt(&self, data: impl Into<String>) -> Result<String>;
-//     fn encrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>>;
-//     fn decrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>>;
-// }
\ No newline at end of file
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ::rsa::pkcs8::spki::{der::{DecodePem, Encode}, SubjectPublicKeyInfoOwned};
+use sha2::{Digest, Sha256};
+
+/// Common interface of every cipher. Byte methods work on raw ciphertext,
+/// string methods on base64 text.
+pub trait Encrypt {
+    fn encrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>>;
+    fn decrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    fn encrypt(&self, data: &str) -> Result<String> {
+        Ok(BASE64.encode(self.encrypt_u8(data.as_bytes())?))
+    }
+
+    fn decrypt(&self, data: &str) -> Result<String> {
+        let decoded = BASE64.decode(data.trim())?;
+        Ok(String::from_utf8(self.decrypt_u8(&decoded)?)?)
+    }
+}
+
+/// Encryptor for a `PUBLIC KEY` PEM holding either an RSA or an ECC key.
+pub fn encryptor_from_pem(pem: &str) -> Result<Box<dyn Encrypt>> {
+    match PublicKey::from_pem(pem) {
+        Ok(key) => Ok(Box::new(key)),
+        Err(_) => Ok(Box::new(EccPublicKey::from_pem(pem)?)),
+    }
+}
+
+/// Short id of a public key: the first 8 bytes of the SHA-256 of its DER
+/// `SubjectPublicKeyInfo`, in hex. Asymmetric ciphertext records it in its
+/// envelope so a card holding several keys knows which one to use.
+pub fn key_id(spki_der: &[u8]) -> String {
+    Sha256::digest(spki_der)[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `key_id` of an RSA or ECC `PUBLIC KEY` PEM.
+pub fn key_id_from_pem(pem: &str) -> Result<String> {
+    let invalid = |e: String| Error::Envelope(format!("Invalid public key PEM: {}", e));
+    let der = SubjectPublicKeyInfoOwned::from_pem(pem)
+        .map_err(|e| invalid(e.to_string()))?
+        .to_der()
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(key_id(&der))
+}
+
+/// Key id recorded in asymmetric ciphertext, None for data written before ids.
+pub fn ciphertext_key_id(data: &[u8]) -> Option<String> {
+    if !Envelope::has_header(data) {
+        return None;
+    }
+    Envelope::from_bytes(data).ok().map(|envelope| envelope.key_id).filter(|id| !id.is_empty())
+}
+
+/// Checks a signature of a SHA-256 or SHA-384 `digest`, as made by a smart card,
+/// with an RSA or ECC `PUBLIC KEY` PEM.
+pub fn verify_prehash_from_pem(pem: &str, digest: &[u8], signature: &[u8]) -> Result<()> {
+    match PublicKey::from_pem(pem) {
+        Ok(key) => key.verify_prehash(digest, signature),
+        Err(_) => EccPublicKey::from_pem(pem)?.verify_prehash(digest, signature),
+    }
+}
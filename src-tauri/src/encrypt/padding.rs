@@ -0,0 +1,185 @@
+use crate::encrypt::{Error, Result};
+use sha2::{Digest, Sha256};
+
+// Removes RSA encryption padding from blocks decrypted raw, e.g. on a smart card.
+// Every failure returns the same error so callers can not be used as a padding oracle.
+
+const HASH_LEN: usize = 32;
+
+fn decryption_error() -> Error {
+    Error::Rsa("Decryption error".to_string())
+}
+
+// Left pads the block to the key size, cards may strip leading zeros
+fn full_block(block: &[u8], key_size: usize) -> Result<Vec<u8>> {
+    if block.len() > key_size {
+        return Err(decryption_error());
+    }
+    let mut out = vec![0u8; key_size - block.len()];
+    out.extend_from_slice(block);
+    Ok(out)
+}
+
+fn mgf1_sha256(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + HASH_LEN);
+    let mut counter: u32 = 0;
+    while out.len() < len {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        hasher.update(counter.to_be_bytes());
+        out.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+/// EME-OAEP decoding (RFC 8017, 7.1.2) with SHA-256, MGF1-SHA-256 and an empty label.
+pub fn oaep_sha256_unpad(block: &[u8], key_size: usize) -> Result<Vec<u8>> {
+    if key_size < 2 * HASH_LEN + 2 {
+        return Err(decryption_error());
+    }
+    let em = full_block(block, key_size)?;
+    let (masked_seed, masked_db) = em[1..].split_at(HASH_LEN);
+    let seed: Vec<u8> = masked_seed
+        .iter()
+        .zip(mgf1_sha256(masked_db, HASH_LEN))
+        .map(|(a, b)| a ^ b)
+        .collect();
+    let db: Vec<u8> = masked_db
+        .iter()
+        .zip(mgf1_sha256(&seed, masked_db.len()))
+        .map(|(a, b)| a ^ b)
+        .collect();
+
+    let label_hash = Sha256::digest([]);
+    let mut invalid = em[0];
+    for (a, b) in db[..HASH_LEN].iter().zip(label_hash.iter()) {
+        invalid |= a ^ b;
+    }
+    // Find the 0x01 separator after the zero padding without branching on secret data
+    let mut looking = 1u8;
+    let mut index = 0usize;
+    for (i, &byte) in db[HASH_LEN..].iter().enumerate() {
+        let is_zero = (byte == 0) as u8;
+        let is_one = (byte == 1) as u8;
+        let found = looking & is_one;
+        index |= i * found as usize;
+        invalid |= looking & (is_zero ^ 1) & (is_one ^ 1);
+        looking &= is_one ^ 1;
+    }
+    invalid |= looking;
+    if invalid != 0 {
+        return Err(decryption_error());
+    }
+    Ok(db[HASH_LEN + index + 1..].to_vec())
+}
+
+/// EME-PKCS1-v1_5 decoding (RFC 8017, 7.2.2), only kept to read old ciphertexts.
+pub fn pkcs1v15_unpad(block: &[u8], key_size: usize) -> Result<Vec<u8>> {
+    if key_size < 11 {
+        return Err(decryption_error());
+    }
+    let em = full_block(block, key_size)?;
+    let mut invalid = em[0] | (em[1] ^ 2);
+    let mut looking = 1u8;
+    let mut index = 0usize;
+    for (i, &byte) in em[2..].iter().enumerate() {
+        let found = looking & (byte == 0) as u8;
+        index |= i * found as usize;
+        looking &= found ^ 1;
+    }
+    invalid |= looking;
+    // At least 8 bytes of random padding
+    invalid |= (index < 8) as u8;
+    if invalid != 0 {
+        return Err(decryption_error());
+    }
+    Ok(em[2 + index + 1..].to_vec())
+}
+
+// DER encoded DigestInfo prefix of a SHA-256 hash
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+
+/// EMSA-PKCS1-v1_5 encoding (RFC 8017, 9.2) of a SHA-256 digest, for cards signing raw blocks.
+pub fn pkcs1v15_sign_pad(digest: &[u8], key_size: usize) -> Result<Vec<u8>> {
+    let t_len = SHA256_DIGEST_INFO.len() + HASH_LEN;
+    if digest.len() != HASH_LEN || key_size < t_len + 11 {
+        return Err(Error::Rsa("Invalid digest or key size".to_string()));
+    }
+    let mut block = vec![0xff; key_size];
+    block[0] = 0x00;
+    block[1] = 0x01;
+    block[key_size - t_len - 1] = 0x00;
+    block[key_size - t_len..key_size - HASH_LEN].copy_from_slice(&SHA256_DIGEST_INFO);
+    block[key_size - HASH_LEN..].copy_from_slice(digest);
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+    use rsa::{BigUint, Oaep, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+
+    // What a card returns: the RSA primitive without any unpadding
+    fn raw_decrypt(key: &RsaPrivateKey, ciphertext: &[u8]) -> Vec<u8> {
+        let block = BigUint::from_bytes_be(ciphertext)
+            .modpow(key.d(), key.n())
+            .to_bytes_be();
+        full_block(&block, key.size()).unwrap()
+    }
+
+    fn key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut OsRng, 1024).unwrap()
+    }
+
+    #[test]
+    fn test_oaep_unpad() {
+        let key = key();
+        let public = RsaPublicKey::from(&key);
+        let ciphertext = public.encrypt(&mut OsRng, Oaep::new::<Sha256>(), b"content key").unwrap();
+        let block = raw_decrypt(&key, &ciphertext);
+        assert_eq!(oaep_sha256_unpad(&block, key.size()).unwrap(), b"content key");
+    }
+
+    #[test]
+    fn test_pkcs1v15_unpad() {
+        let key = key();
+        let public = RsaPublicKey::from(&key);
+        let ciphertext = public.encrypt(&mut OsRng, Pkcs1v15Encrypt, b"content key").unwrap();
+        let block = raw_decrypt(&key, &ciphertext);
+        assert_eq!(pkcs1v15_unpad(&block, key.size()).unwrap(), b"content key");
+    }
+
+    #[test]
+    fn test_pkcs1v15_sign_pad() {
+        use rsa::Pkcs1v15Sign;
+        let key = key();
+        let digest = Sha256::digest(b"challenge");
+        let signature = raw_decrypt(&key, &pkcs1v15_sign_pad(&digest, key.size()).unwrap());
+        RsaPublicKey::from(&key)
+            .verify(Pkcs1v15Sign::new::<Sha256>(), &digest, &signature)
+            .unwrap();
+        assert!(pkcs1v15_sign_pad(b"short", key.size()).is_err());
+    }
+
+    #[test]
+    fn test_wrong_padding_gives_one_error() {
+        let key = key();
+        let public = RsaPublicKey::from(&key);
+        let pkcs1 = public.encrypt(&mut OsRng, Pkcs1v15Encrypt, b"data").unwrap();
+        let errors = [
+            oaep_sha256_unpad(&raw_decrypt(&key, &pkcs1), key.size()).unwrap_err(),
+            pkcs1v15_unpad(&[1u8; 128], key.size()).unwrap_err(),
+            oaep_sha256_unpad(&[0u8; 200], key.size()).unwrap_err(),
+        ];
+        for error in errors {
+            assert_eq!(error.to_string(), decryption_error().to_string());
+        }
+    }
+}
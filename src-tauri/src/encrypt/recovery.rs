@@ -0,0 +1,142 @@
+use crate::encrypt::{Error, Result, RsaKeyPair, Verifier, AES};
+use crate::FileSystem;
+use rand::{rngs::OsRng, RngCore};
+use std::fs;
+
+// RFC 4648 base32, it has no 0, 1 or 8 to mistake for O, I or B
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+// 160 bits, 32 base32 characters
+const KEY_BYTES: usize = 20;
+const GROUP: usize = 4;
+
+/// Recovery record in `recovery.json`, written when the vault is set up.
+///
+/// The RSA master private key, which wraps every vault data key, is encrypted
+/// with a key derived from a random recovery key shown once to the user. It
+/// does not depend on the master password, so changing the password keeps it.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Recovery {
+    verifier: Verifier,
+    private_key: String,
+}
+
+impl Recovery {
+    /// Random recovery key, as groups of 4 base32 characters.
+    pub fn generate_key() -> String {
+        let mut bytes = [0u8; KEY_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        let encoded = base32(&bytes);
+        encoded
+            .as_bytes()
+            .chunks(GROUP)
+            .map(|group| String::from_utf8_lossy(group).to_string())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    pub fn new(recovery_key: &str, master_pk: &RsaKeyPair) -> Result<Self> {
+        let key = AES::new(&normalize(recovery_key)?)?;
+        Ok(Self {
+            verifier: Verifier::new(&key)?,
+            private_key: key.encrypt(master_pk.private_key_pem()?.as_bytes())?,
+        })
+    }
+
+    /// RSA master private key, failing with `Error::WrongPassword` for another recovery key.
+    pub fn open(&self, recovery_key: &str) -> Result<RsaKeyPair> {
+        let key = self
+            .verifier
+            .unlock(&normalize(recovery_key)?)
+            .map_err(|_| Error::WrongPassword("Recovery key incorrect".to_string()))?;
+        RsaKeyPair::from_string(&String::from_utf8(key.decrypt(&self.private_key)?)?)
+    }
+
+    /// The saved record, `None` when the vault was set up without a recovery key.
+    pub fn load(fs: &FileSystem) -> Result<Option<Self>> {
+        let path = fs.recovery();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    pub fn save(&self, fs: &FileSystem) -> Result<()> {
+        fs::write(fs.recovery(), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+fn base32(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+// Accepts the key typed back in any case, with or without separators, and
+// with digits read for the letters they look like
+fn normalize(recovery_key: &str) -> Result<String> {
+    let key: String = recovery_key
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| match c.to_ascii_uppercase() {
+            '0' => 'O',
+            '1' => 'I',
+            '8' => 'B',
+            c => c,
+        })
+        .collect();
+    let expected = (KEY_BYTES * 8).div_ceil(5);
+    if key.len() != expected || !key.bytes().all(|c| ALPHABET.contains(&c)) {
+        return Err(Error::WrongPassword("Invalid recovery key".to_string()));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_key() {
+        let key = Recovery::generate_key();
+        assert_eq!(key.len(), 32 + 7);
+        assert_eq!(key.split('-').count(), 8);
+        assert_ne!(key, Recovery::generate_key());
+        assert_eq!(normalize(&key).unwrap(), key.replace('-', ""));
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn test_normalize() {
+        let key = "abcd-efgh-ijkl-mnop-qrst-uvwx-yz23-4567";
+        assert_eq!(normalize(key).unwrap(), "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567");
+        assert_eq!(normalize("0BCD EFGH 1JKL MNOP QRST UVWX YZ23 4567").unwrap(), "OBCDEFGHIJKLMNOPQRSTUVWXYZ234567");
+        assert!(normalize("ABCD").is_err());
+        assert!(normalize("ABCD-EFGH-IJKL-MNOP-QRST-UVWX-YZ23-456!").is_err());
+    }
+
+    #[test]
+    fn test_open() {
+        let master_pk = RsaKeyPair::new().unwrap();
+        let key = Recovery::generate_key();
+        let recovery = Recovery::new(&key, &master_pk).unwrap();
+        let json = serde_json::to_string(&recovery).unwrap();
+        assert!(!json.contains(&normalize(&key).unwrap()));
+
+        let opened = Recovery::open(&serde_json::from_str(&json).unwrap(), &key.to_lowercase()).unwrap();
+        assert_eq!(opened.private_key_pem().unwrap(), master_pk.private_key_pem().unwrap());
+        let other = Recovery::generate_key();
+        assert!(matches!(recovery.open(&other), Err(Error::WrongPassword(_))));
+    }
+}
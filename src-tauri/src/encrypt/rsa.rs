@@ -1,9 +1,10 @@
-use crate::encrypt::{Error, Result};
+use crate::encrypt::{hybrid, hybrid::Padding, key_id, Encrypt, Error, Result};
 use crate::{AppState, MasterPassword};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use rand::rngs::OsRng;
 use rsa::{
-    Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey,
+    Oaep, Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+    traits::PublicKeyParts,
     pkcs1v15::Signature as RsaSignature,
     pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
     sha2::Sha256,
@@ -13,6 +14,18 @@ use std::fs;
 #[cfg(test)]
 use std::path::Path;
 
+fn wrap(key: &RsaPublicKey, content_key: &[u8]) -> Result<Vec<u8>> {
+    key.encrypt(&mut OsRng, Oaep::new::<Sha256>(), content_key)
+        .map_err(|e| Error::Rsa(format!("RSA encryption failed: {}", e)))
+}
+
+fn rsa_key_id(key: &RsaPublicKey) -> Result<String> {
+    let der = key
+        .to_public_key_der()
+        .map_err(|e| Error::Rsa(format!("Failed to encode public key: {}", e)))?;
+    Ok(key_id(der.as_bytes()))
+}
+
 // This struct is for operations involving only the public key.
 pub struct PublicKey {
     key: RsaPublicKey,
@@ -27,16 +40,17 @@ impl PublicKey {
         })
     }
 
+    /// Hybrid encryption, only the random content key goes through RSA.
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Changed return type to Result<Vec<u8>>
-        let mut rng = OsRng;
-        let encrypted_data = self
-            .key
-            .encrypt(&mut rng, Pkcs1v15Encrypt, data)
-            .map_err(|e| Error::Rsa(format!("RSA encryption failed: {}", e)))?;
-        Ok(encrypted_data) // Return raw encrypted bytes
+        hybrid::seal(data, &rsa_key_id(&self.key)?, |content_key| wrap(&self.key, content_key))
     }
 
+    /// Modulus size in bytes.
+    pub fn size(&self) -> usize {
+        self.key.size()
+    }
+
+    #[allow(dead_code)]
     pub fn verify(&self, data: &[u8], signature_bytes: &[u8]) -> Result<()> {
         let signature = RsaSignature::try_from(signature_bytes)
             .map_err(|e| Error::Rsa(format!("Failed to create signature from bytes: {}", e)))?;
@@ -47,6 +61,23 @@ impl PublicKey {
             .verify(data, &signature)
             .map_err(|e| Error::Rsa(format!("Signature verification failed: {}", e)))
     }
+
+    /// Verifies a PKCS#1 v1.5 signature of a SHA-256 digest.
+    pub fn verify_prehash(&self, digest: &[u8], signature_bytes: &[u8]) -> Result<()> {
+        self.key
+            .verify(Pkcs1v15Sign::new::<Sha256>(), digest, signature_bytes)
+            .map_err(|e| Error::Rsa(format!("Signature verification failed: {}", e)))
+    }
+}
+
+impl Encrypt for PublicKey {
+    fn encrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>> {
+        PublicKey::encrypt(self, data)
+    }
+
+    fn decrypt_u8(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::Unsupported("An RSA public key can not decrypt".to_string()))
+    }
 }
 
 // Renamed RSA to RsaKeyPair to better reflect its purpose (holding a key pair)
@@ -107,16 +138,19 @@ impl RsaKeyPair {
 
     #[allow(dead_code)]
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut rng = OsRng;
-        // Use the public_key field of RsaKeyPair
-        let encrypted = self.public_key.encrypt(&mut rng, Pkcs1v15Encrypt, data)?;
-        Ok(encrypted)
+        hybrid::seal(data, &rsa_key_id(&self.public_key)?, |content_key| wrap(&self.public_key, content_key))
     }
 
+    /// Decrypts hybrid ciphertexts as well as data encrypted with the RSA key directly.
     #[allow(dead_code)]
     pub fn decrypt(&self, encrypted_data: &[u8]) -> Result<Vec<u8>> {
-        let decrypted = self.private_key.decrypt(Pkcs1v15Encrypt, encrypted_data)?;
-        Ok(decrypted)
+        hybrid::open(encrypted_data, self.private_key.size(), |wrapped, padding| {
+            let content_key = match padding {
+                Padding::Oaep => self.private_key.decrypt(Oaep::new::<Sha256>(), wrapped)?,
+                Padding::Pkcs1v15 => self.private_key.decrypt(Pkcs1v15Encrypt, wrapped)?,
+            };
+            Ok(content_key)
+        })
     }
 
     #[allow(dead_code)]
@@ -134,6 +168,16 @@ impl RsaKeyPair {
     }
 }
 
+impl Encrypt for RsaKeyPair {
+    fn encrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>> {
+        RsaKeyPair::encrypt(self, data)
+    }
+
+    fn decrypt_u8(&self, data: &[u8]) -> Result<Vec<u8>> {
+        RsaKeyPair::decrypt(self, data)
+    }
+}
+
 impl TryFrom<&AppState> for RsaKeyPair {
     type Error = crate::encrypt::Error;
 
@@ -192,6 +236,35 @@ mod tests {
         assert_eq!(original, decrypted);
     }
 
+    #[test]
+    fn test_encrypt_trait() {
+        let key_pair = RsaKeyPair::new().unwrap();
+        let public_key = PublicKey::from_pem(&key_pair.public_key_pem().unwrap()).unwrap();
+        let encryptor: &dyn Encrypt = &public_key;
+        let decryptor: &dyn Encrypt = &key_pair;
+
+        let encrypted = encryptor.encrypt("test secret").unwrap();
+        assert_eq!(decryptor.decrypt(&encrypted).unwrap(), "test secret");
+        assert!(matches!(encryptor.decrypt(&encrypted), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_encrypts_data_larger_than_the_key() {
+        let key_pair = RsaKeyPair::new().unwrap();
+        let public_key = PublicKey::from_pem(&key_pair.public_key_pem().unwrap()).unwrap();
+        let data = vec![42u8; 4096];
+        let encrypted = public_key.encrypt(&data).unwrap();
+        assert_eq!(key_pair.decrypt(&encrypted).unwrap(), data);
+        assert_eq!(crate::encrypt::ciphertext_key_id(&encrypted), Some(rsa_key_id(&key_pair.public_key).unwrap()));
+    }
+
+    #[test]
+    fn test_decrypts_direct_rsa_ciphertext() {
+        let key_pair = RsaKeyPair::new().unwrap();
+        let legacy = key_pair.public_key.encrypt(&mut OsRng, Pkcs1v15Encrypt, b"old").unwrap();
+        assert_eq!(key_pair.decrypt(&legacy).unwrap(), b"old");
+    }
+
     #[test]
     fn test_public_key_to_pem() {
         let encryptor = RsaKeyPair::new().unwrap();
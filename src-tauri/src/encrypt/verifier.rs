@@ -0,0 +1,150 @@
+use crate::encrypt::{Error, Kdf, Result, AES};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Purpose of the key the check value is made with, see `AES::derive_key`
+const PURPOSE: &str = "master-password-verifier";
+// Constant authenticated by the check value
+const CHECK_INPUT: &[u8] = b"vault master password key check";
+const VERSION: u8 = 2;
+// Written while the YubiKey settings could still be unsigned, see `signs_settings`
+const UNSIGNED_SETTINGS_VERSION: u8 = 1;
+
+/// Key check record of the master password, stored in `master_verifier.json`.
+///
+/// Holds the KDF parameters and salt the master key is derived with, and an
+/// HMAC of a constant under a key derived from it. The password itself is
+/// never written, not even encrypted.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Verifier {
+    version: u8,
+    kdf: String,
+    salt: String,
+    check: String,
+}
+
+impl Verifier {
+    /// Check record of a master key derived from a password.
+    pub fn new(master: &AES) -> Result<Self> {
+        Self::with_version(master, VERSION)
+    }
+
+    /// Check record for a vault whose YubiKey settings may not be signed yet,
+    /// only used when moving from the password file of older versions.
+    pub fn with_unsigned_settings(master: &AES) -> Result<Self> {
+        Self::with_version(master, UNSIGNED_SETTINGS_VERSION)
+    }
+
+    /// Check record of another master key, keeping what this one records.
+    pub fn rekey(&self, master: &AES) -> Result<Self> {
+        Self::with_version(master, self.version)
+    }
+
+    fn with_version(master: &AES, version: u8) -> Result<Self> {
+        let kdf = master.kdf().ok_or(Error::Custom(
+            "The master key must be derived from a password".to_string(),
+        ))?;
+        let check = Self::hmac(master, version)?.finalize().into_bytes();
+        Ok(Self {
+            version,
+            kdf: BASE64.encode(kdf.to_bytes()),
+            salt: BASE64.encode(master.salt()),
+            check: BASE64.encode(check),
+        })
+    }
+
+    /// Derives the master key from `password`, failing with `Error::WrongPassword`
+    /// when it does not match the record.
+    pub fn unlock(&self, password: &str) -> Result<AES> {
+        if self.version != VERSION && self.version != UNSIGNED_SETTINGS_VERSION {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        let master = AES::from_salt(password, &BASE64.decode(&self.salt)?, self.kdf()?)?;
+        Self::hmac(&master, self.version)?
+            .verify_slice(&BASE64.decode(&self.check)?)
+            .map_err(|_| Error::WrongPassword("Master password incorrect".to_string()))?;
+        Ok(master)
+    }
+
+    /// True once signed YubiKey settings were written, from then on settings
+    /// without a MAC are never signed. The check value covers the version, so
+    /// it can not be turned back without the master key.
+    pub fn signs_settings(&self) -> bool {
+        self.version == VERSION
+    }
+
+    pub fn kdf(&self) -> Result<Kdf> {
+        let (kdf, _) = Kdf::from_bytes(&BASE64.decode(&self.kdf)?)?;
+        Ok(kdf)
+    }
+
+    pub fn from_json(content: &str) -> Result<Self> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    fn hmac(master: &AES, version: u8) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&master.derive_key(PURPOSE)?)
+            .map_err(|e| Error::Custom(e.to_string()))?;
+        mac.update(CHECK_INPUT);
+        // Records of the first version checked the constant alone
+        if version != UNSIGNED_SETTINGS_VERSION {
+            mac.update(&[version]);
+        }
+        Ok(mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlock() {
+        let master = AES::new("password").unwrap();
+        let verifier = Verifier::new(&master).unwrap();
+        let json = verifier.to_json().unwrap();
+        let unlocked = Verifier::from_json(&json).unwrap().unlock("password").unwrap();
+        assert_eq!(unlocked.kdf(), Some(Kdf::default()));
+        // Same key: data encrypted before decrypts with the unlocked one
+        assert_eq!(unlocked.decrypt(&master.encrypt(b"data").unwrap()).unwrap(), b"data");
+        assert!(matches!(verifier.unlock("wrong"), Err(Error::WrongPassword(_))));
+    }
+
+    #[test]
+    fn test_keeps_kdf_parameters() {
+        let master = AES::with_kdf("password", Kdf::Sha3).unwrap();
+        let verifier = Verifier::new(&master).unwrap();
+        assert_eq!(verifier.kdf().unwrap(), Kdf::Sha3);
+        assert_eq!(verifier.unlock("password").unwrap().kdf(), Some(Kdf::Sha3));
+    }
+
+    #[test]
+    fn test_version_is_authenticated() {
+        let master = AES::new("password").unwrap();
+        let legacy = Verifier::with_unsigned_settings(&master).unwrap();
+        assert!(!legacy.signs_settings());
+        legacy.unlock("password").unwrap();
+        assert!(!legacy.rekey(&master).unwrap().signs_settings());
+        let mut current = Verifier::new(&master).unwrap();
+        assert!(current.signs_settings());
+        // Turning a record back to the first version breaks its check value
+        current.version = UNSIGNED_SETTINGS_VERSION;
+        assert!(matches!(current.unlock("password"), Err(Error::WrongPassword(_))));
+    }
+
+    #[test]
+    fn test_tampered_record_is_rejected() {
+        let mut verifier = Verifier::new(&AES::new("password").unwrap()).unwrap();
+        verifier.check = BASE64.encode([0u8; 32]);
+        assert!(matches!(verifier.unlock("password"), Err(Error::WrongPassword(_))));
+        verifier.version = 3;
+        assert!(matches!(verifier.unlock("password"), Err(Error::UnsupportedVersion(3))));
+        // Random keys have no password to check
+        assert!(Verifier::new(&AES::from_key([1u8; 32], "data")).is_err());
+    }
+}
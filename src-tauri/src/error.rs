@@ -14,6 +14,22 @@ pub enum Error {
   StateLock(String),
   MasterPassword(String),
   YubiKeyError(String),
+  NotFound(String),
+  Validation(String),
+  AlreadyExists(String),
+  Locked(String),
+  Tampered(String),
+  /// Wrong YubiKey PIN, with the tries left before the PIN blocks.
+  WrongPin(u8),
+  /// Wrong YubiKey PUK, with the tries left before the PUK blocks.
+  WrongPuk(u8),
+  PinBlocked(String),
+  /// A YubiKey key without a valid attestation chain.
+  Attestation(String),
+  /// Too many wrong master passwords, with the seconds to wait before the next try.
+  TooManyAttempts(u64),
+  /// Too many wrong master passwords, the YubiKey must unlock the session first.
+  YubiKeyRequired(String),
 }
 
 impl core::fmt::Display for Error {
@@ -40,18 +56,54 @@ impl From<String> for Error {
 
 impl From<crate::encrypt::Error> for Error {
     fn from(e: crate::encrypt::Error) -> Self {
-        Error::Encryption(e.to_string())
+        match e {
+            crate::encrypt::Error::Tampered(msg) => Error::Tampered(msg),
+            crate::encrypt::Error::WrongPin(tries) => Error::WrongPin(tries),
+            crate::encrypt::Error::PinBlocked(msg) => Error::PinBlocked(msg),
+            e => Error::Encryption(e.to_string()),
+        }
     }
     
 }
 
+// Card errors coming back through the `Encrypt` trait keep the PIN state
+impl From<Error> for crate::encrypt::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::WrongPin(tries) => crate::encrypt::Error::WrongPin(tries),
+            Error::PinBlocked(msg) => crate::encrypt::Error::PinBlocked(msg),
+            e => crate::encrypt::Error::YubiKey(e.to_string()),
+        }
+    }
+}
+
 impl From<crate::secrets::Error> for Error {
     fn from(e: crate::secrets::Error) -> Self {
-        Error::Custom(e.to_string())
+        match e {
+            crate::secrets::Error::NotFound(id) => Error::NotFound(id),
+            crate::secrets::Error::Validation(msg) => Error::Validation(msg),
+            crate::secrets::Error::YubiKey(msg) => Error::YubiKeyError(msg),
+            crate::secrets::Error::WrongPin(tries) => Error::WrongPin(tries),
+            crate::secrets::Error::PinBlocked(msg) => Error::PinBlocked(msg),
+            e => Error::Custom(e.to_string()),
+        }
     }
     
 }
 
+impl From<crate::vaults::Error> for Error {
+    fn from(e: crate::vaults::Error) -> Self {
+        match e {
+            crate::vaults::Error::NotFound(msg) => Error::NotFound(msg),
+            crate::vaults::Error::Validation(msg) => Error::Validation(msg),
+            crate::vaults::Error::AlreadyExists(msg) => Error::AlreadyExists(msg),
+            crate::vaults::Error::Locked(msg) => Error::Locked(msg),
+            crate::vaults::Error::YubiKey(msg) => Error::YubiKeyError(msg),
+            e => Error::Custom(e.to_string()),
+        }
+    }
+}
+
 // // --- RSA errors
 // impl From<rsa::errors::Error> for Error {
 //     fn from(e: rsa::errors::Error) -> Self {
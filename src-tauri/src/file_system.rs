@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug)]
 pub struct FileSystem {
@@ -40,7 +42,19 @@ impl FileSystem {
         self.vault_folder(vault_name).join("private_key")
     }
 
-    pub fn master_password(&self) -> PathBuf {
+    /// Wrapped copies of the data key encrypting the vault's secrets.
+    pub fn vault_keyring(&self, vault_name: &str) -> PathBuf {
+        self.vault_folder(vault_name).join("keyring.json")
+    }
+
+    /// Key check record of the master password, see `encrypt::Verifier`.
+    pub fn master_verifier(&self) -> PathBuf {
+        self.root().join("master_verifier.json")
+    }
+
+    /// Master password encrypted with itself, written by older versions and
+    /// replaced by `master_verifier` on the next login.
+    pub fn legacy_master_password(&self) -> PathBuf {
         self.root().join("master_password.enc")
     }
 
@@ -48,6 +62,26 @@ impl FileSystem {
         self.root().join("rsa_master_pk.enc")
     }
 
+    /// RSA master private key encrypted with the recovery key, see `encrypt::Recovery`.
+    pub fn recovery(&self) -> PathBuf {
+        self.root().join("recovery.json")
+    }
+
+    /// Wrong master passwords in a row, see `lockout::Attempts`.
+    pub fn login_attempts(&self) -> PathBuf {
+        self.app_data_directory().join("login_attempts.json")
+    }
+
+    /// What happens after too many wrong master passwords, see `LockoutPolicy`.
+    pub fn lockout_policy(&self) -> PathBuf {
+        self.root().join("lockout.json")
+    }
+
+    /// Idle timeout and lifetime of unlocked sessions, see `SessionPolicy`.
+    pub fn session_policy(&self) -> PathBuf {
+        self.root().join("session.json")
+    }
+
     pub fn yubikey_settings(&self) -> PathBuf {
         self.root().join("yubikey_settings.json")
     }
@@ -56,6 +90,11 @@ impl FileSystem {
         self.root().join("rsa_master_pub")
     }
 
+    /// Staging area of an in-flight [`Transaction`].
+    pub fn pending_folder(&self) -> PathBuf {
+        self.root().join(".pending")
+    }
+
     pub fn vault_folder(&self, vault_name: &str) -> PathBuf {
         let vault_folder = format!("{}.vault", vault_name);
         self.vaults_folder().join(vault_folder)
@@ -71,10 +110,87 @@ impl FileSystem {
         std::fs::create_dir_all(&app_dir)?;
         let vaults_dir = self.vaults_folder();
         std::fs::create_dir_all(&vaults_dir)?;
-        let default_vault_dir = self.vault_folder("default");
+        let default_vault_dir = self.vault_folder(crate::vaults::DEFAULT_VAULT);
         std::fs::create_dir_all(&default_vault_dir)?;
+        Transaction::recover(self)?;
+        Ok(())
+    }
+}
+
+/// Replaces a set of files all at once, surviving crashes at any point.
+///
+/// New contents are staged next to a manifest, then a commit marker is written
+/// and the staged files are renamed over their targets. On restart
+/// [`Transaction::recover`] rolls a marked transaction forward and drops an
+/// unmarked one, so either every file moves to its new content or none does.
+pub struct Transaction {
+    folder: PathBuf,
+    targets: Vec<PathBuf>,
+}
+
+impl Transaction {
+    const MANIFEST: &'static str = "manifest.json";
+    const COMMITTED: &'static str = "COMMITTED";
+
+    pub fn new(fs: &FileSystem) -> io::Result<Self> {
+        Self::recover(fs)?;
+        let folder = fs.pending_folder();
+        fs::create_dir_all(&folder)?;
+        Ok(Self {
+            folder,
+            targets: vec![],
+        })
+    }
+
+    pub fn stage(&mut self, target: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let staged = self.folder.join(self.targets.len().to_string());
+        write_synced(&staged, contents.as_ref())?;
+        self.targets.push(target.to_path_buf());
         Ok(())
     }
+
+    pub fn commit(self) -> io::Result<()> {
+        self.mark_committed()?;
+        Self::apply(&self.folder)
+    }
+
+    fn mark_committed(&self) -> io::Result<()> {
+        let manifest = serde_json::to_vec(&self.targets)?;
+        write_synced(&self.folder.join(Self::MANIFEST), &manifest)?;
+        write_synced(&self.folder.join(Self::COMMITTED), b"")
+    }
+
+    // Renames every staged file still present over its target, then drops the staging folder
+    fn apply(folder: &Path) -> io::Result<()> {
+        let manifest = fs::read(folder.join(Self::MANIFEST))?;
+        let targets: Vec<PathBuf> = serde_json::from_slice(&manifest)?;
+        for (index, target) in targets.iter().enumerate() {
+            let staged = folder.join(index.to_string());
+            if staged.exists() {
+                fs::rename(staged, target)?;
+            }
+        }
+        fs::remove_dir_all(folder)
+    }
+
+    /// Finishes or rolls back a transaction interrupted by a crash.
+    pub fn recover(fs: &FileSystem) -> io::Result<()> {
+        let folder = fs.pending_folder();
+        if !folder.exists() {
+            return Ok(());
+        }
+        if folder.join(Self::COMMITTED).exists() {
+            Self::apply(&folder)
+        } else {
+            fs::remove_dir_all(folder)
+        }
+    }
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    io::Write::write_all(&mut file, contents)?;
+    file.sync_all()
 }
 
 
@@ -86,6 +202,14 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         FileSystem::new_test(temp_dir.path().to_path_buf())
     }
+
+    // Keeps the temp dir alive for tests touching the disk
+    fn setup_on_disk() -> (TempDir, FileSystem) {
+        let temp_dir = TempDir::new().unwrap();
+        let fs = FileSystem::new_test(temp_dir.path().to_path_buf());
+        (temp_dir, fs)
+    }
+
     #[test]
     fn test_file_system() {
         let fs = setup();
@@ -98,4 +222,52 @@ mod tests {
             temp_dir.join("vaults").join("test.vault")
         );
     }
+
+    #[test]
+    fn test_transaction_commit() {
+        let (_dir, fs) = setup_on_disk();
+        let a = fs.root().join("a");
+        let b = fs.root().join("b");
+        fs::write(&a, "old a").unwrap();
+        let mut transaction = Transaction::new(&fs).unwrap();
+        transaction.stage(&a, "new a").unwrap();
+        transaction.stage(&b, "new b").unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "old a");
+        transaction.commit().unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "new a");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "new b");
+        assert!(!fs.pending_folder().exists());
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_uncommitted() {
+        let (_dir, fs) = setup_on_disk();
+        let a = fs.root().join("a");
+        fs::write(&a, "old a").unwrap();
+        let mut transaction = Transaction::new(&fs).unwrap();
+        transaction.stage(&a, "new a").unwrap();
+        drop(transaction);
+        Transaction::recover(&fs).unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "old a");
+        assert!(!fs.pending_folder().exists());
+    }
+
+    #[test]
+    fn test_transaction_rolls_forward_committed() {
+        let (_dir, fs) = setup_on_disk();
+        let a = fs.root().join("a");
+        let b = fs.root().join("b");
+        fs::write(&a, "old a").unwrap();
+        fs::write(&b, "old b").unwrap();
+        let mut transaction = Transaction::new(&fs).unwrap();
+        transaction.stage(&a, "new a").unwrap();
+        transaction.stage(&b, "new b").unwrap();
+        transaction.mark_committed().unwrap();
+        // Crash after the first rename
+        fs::rename(fs.pending_folder().join("0"), &a).unwrap();
+        fs.init().unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "new a");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "new b");
+        assert!(!fs.pending_folder().exists());
+    }
 }
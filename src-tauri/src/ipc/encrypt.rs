@@ -1,17 +1,62 @@
 use crate::{TauriState, Error, Result, MasterPassword};
+use crate::lockout::{self, LockoutPolicy};
+use crate::vaults::Vault;
+use crate::yubikey::YubiKeySettings;
 
+/// Sets up the vault, returning the recovery key when one is asked for. It is
+/// only shown this once.
 #[tauri::command]
 pub fn save_master_password(
     state: TauriState,
     password: &str,
     private_key: Option<&str>,
-) -> Result<String> {
+    recovery_key: Option<bool>,
+) -> Result<Option<String>> {
     let mut state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
-    MasterPassword::save(&mut state, password, private_key).map_err(|e| Error::MasterPassword(e.to_string()))
+    MasterPassword::save(&mut state, password, private_key).map_err(|e| Error::MasterPassword(e.to_string()))?;
+    // Every vault gets a data key the recovery key can reach
+    for vault in Vault::all(&state)? {
+        vault.ensure_keyring(&state)?;
+    }
+    if !recovery_key.unwrap_or(false) {
+        return Ok(None);
+    }
+    let recovery_key = MasterPassword::create_recovery_key(&state).map_err(|e| Error::MasterPassword(e.to_string()))?;
+    Ok(Some(recovery_key))
+}
+
+#[tauri::command]
+pub fn recover_with_recovery_key(state: TauriState, recovery_key: &str, new_password: &str) -> Result<String> {
+    let mut state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    MasterPassword::recover(&mut state, recovery_key, new_password).map_err(|e| Error::MasterPassword(e.to_string()))
 }
 
 #[tauri::command]
 pub fn verify_master_password(state: TauriState, password: &str) -> Result<String> {
     let mut state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
-    MasterPassword::verify(&mut state, password).map_err(|e| Error::MasterPassword(e.to_string()))
-}
\ No newline at end of file
+    let message = lockout::verify_master_password(&mut state, password)?;
+    YubiKeySettings::migrate(&state)?;
+    Vault::upgrade_all(&state)?;
+    Ok(message)
+}
+
+#[tauri::command]
+pub fn change_master_password(state: TauriState, old_password: &str, new_password: &str) -> Result<String> {
+    let mut state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    lockout::change_master_password(&mut state, old_password, new_password)
+}
+
+#[tauri::command]
+pub fn get_lockout_policy(state: TauriState) -> Result<LockoutPolicy> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    Ok(LockoutPolicy::load(state.file_system()))
+}
+
+#[tauri::command]
+pub fn set_lockout_policy(state: TauriState, policy: LockoutPolicy) -> Result<()> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    if !state.is_authenticated() {
+        return Err(Error::Locked("Unlock the vault before changing the lockout policy".to_string()));
+    }
+    policy.save(&state)
+}
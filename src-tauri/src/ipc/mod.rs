@@ -1,11 +1,16 @@
 mod encrypt;
 mod secrets;
+mod vaults;
 mod yubikey;
 pub use encrypt::*;
 pub use secrets::*;
+pub use vaults::*;
 pub use yubikey::*;
 
-use crate::{TauriState, Result};
+use crate::{AppState, TauriState, Error, Result};
+use crate::session::SessionPolicy;
+use std::sync::Mutex;
+use tauri::{ipc::Invoke, Manager, Runtime};
 
 #[tauri::command]
 pub fn is_authenticated(state: TauriState) -> Result<bool> {
@@ -24,4 +29,41 @@ pub fn log_out(state: TauriState) -> Result<()> {
     let mut state = state.lock()?;
     state.log_out();
     Ok(())
-}
\ No newline at end of file
+}
+
+// Read-only status checks the UI can make on its own, they keep no session alive
+const PASSIVE_COMMANDS: &[&str] = &[
+    "is_authenticated",
+    "get_session_policy",
+    "get_lockout_policy",
+    "list_yubikeys",
+    "yubikey_pin_status",
+];
+
+/// Runs before every command: locks an expired session, or records the
+/// activity unless the command is a status check.
+pub fn touch_session<R: Runtime>(invoke: &Invoke<R>) {
+    let state = invoke.message.webview_ref().state::<Mutex<AppState>>();
+    if let Ok(mut state) = state.lock() {
+        if PASSIVE_COMMANDS.contains(&invoke.message.command()) {
+            state.expire_session();
+        } else {
+            state.touch_session();
+        }
+    }
+}
+
+#[tauri::command]
+pub fn get_session_policy(state: TauriState) -> Result<SessionPolicy> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    Ok(state.session_policy())
+}
+
+#[tauri::command]
+pub fn set_session_policy(state: TauriState, policy: SessionPolicy) -> Result<()> {
+    let mut state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    if !state.is_authenticated() {
+        return Err(Error::Locked("Unlock the vault before changing the auto-lock".to_string()));
+    }
+    state.set_session_policy(policy)
+}
@@ -2,24 +2,37 @@ use crate::{TauriState, Error, Result};
 use crate::secrets::{NewSecretForm, Secret};
 
 #[tauri::command]
-pub fn create_secret(state: TauriState, data: NewSecretForm) -> Result<String> {
-    println!("Received secret: {:?}", data);
+pub fn create_secret(state: TauriState, vault: &str, data: NewSecretForm) -> Result<String> {
     let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
     let secret: Secret = data.into();
-    secret.save(&state)?;
+    secret.save(&state, vault)?;
     Ok("Submitted secret".to_string())
 }
 
 #[tauri::command]
-pub fn get_secrets(state: TauriState) -> Result<Vec<Secret>> {
+pub fn get_secrets(state: TauriState, vault: &str) -> Result<Vec<Secret>> {
     let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
-    let secrets = Secret::all(&state)?;
+    let secrets = Secret::all(&state, vault)?;
     Ok(secrets)
 }
 
 #[tauri::command]
-pub fn get_secret(state: TauriState, id: &str) -> Result<Secret> {
+pub fn get_secret(state: TauriState, vault: &str, id: &str, pin: Option<String>) -> Result<Secret> {
     let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
-    let secret = Secret::find(&state, id)?;
+    let secret = Secret::find(&state, vault, id, pin.as_deref())?;
     Ok(secret)
-}
\ No newline at end of file
+}
+
+#[tauri::command]
+pub fn update_secret(state: TauriState, vault: &str, id: &str, data: NewSecretForm) -> Result<Secret> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    let secret = Secret::update(&state, vault, id, data)?;
+    Ok(secret)
+}
+
+#[tauri::command]
+pub fn delete_secret(state: TauriState, vault: &str, id: &str) -> Result<()> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    Secret::delete(&state, vault, id)?;
+    Ok(())
+}
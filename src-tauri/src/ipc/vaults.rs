@@ -0,0 +1,44 @@
+use crate::{TauriState, Error, Result};
+use crate::encrypt::UnlockPolicy;
+use crate::vaults::Vault;
+
+#[tauri::command]
+pub fn create_vault(state: TauriState, name: &str) -> Result<Vault> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    let vault = Vault::create(&state, name)?;
+    Ok(vault)
+}
+
+#[tauri::command]
+pub fn list_vaults(state: TauriState) -> Result<Vec<Vault>> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    let vaults = Vault::all(&state)?;
+    Ok(vaults)
+}
+
+#[tauri::command]
+pub fn rename_vault(state: TauriState, name: &str, new_name: &str) -> Result<Vault> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    let vault = Vault::rename(&state, name, new_name)?;
+    Ok(vault)
+}
+
+#[tauri::command]
+pub fn delete_vault(state: TauriState, name: &str) -> Result<()> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    Vault::delete(&state, name)?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_vault_policy(state: TauriState, name: &str) -> Result<UnlockPolicy> {
+    let state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    Ok(Vault::find(&state, name)?.policy(&state)?)
+}
+
+#[tauri::command]
+pub fn set_vault_policy(state: TauriState, name: &str, policy: UnlockPolicy) -> Result<()> {
+    let mut state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    Vault::find(&state, name)?.set_policy(&mut state, policy)?;
+    Ok(())
+}
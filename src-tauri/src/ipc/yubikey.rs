@@ -6,24 +6,79 @@
 use crate::{Error, Result, yubikey, TauriState};
 
 #[tauri::command]
-pub fn list_yubikeys() -> Result<Vec<yubikey::YubiKeyInfo>> {
-    yubikey::list_yubikeys().map_err(|e| Error::YubiKeyError(e.to_string()))
+pub fn list_yubikeys(state: TauriState) -> Result<Vec<yubikey::YubiKeyInfo>> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::list_yubikeys(app_state.piv()).map_err(|e| Error::YubiKeyError(e.to_string()))
+}
+#[tauri::command]
+pub fn encrypt_with_yubikey(state: TauriState, yubikey_serial: u32, data: String) -> Result<String> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    let mut device = yubikey::YubiKeyDevice::open(app_state.piv(), yubikey_serial)?;
+    device.encrypt_data(data.into_bytes())
+}
+
+#[tauri::command]
+pub fn save_yubikey_settings(state: TauriState, serial: u32, label: Option<String>, public_key: String) -> Result<()> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::enroll(&app_state, serial, label, public_key).map(|_| ())
+}
+
+#[tauri::command]
+pub fn provision_yubikey(state: TauriState, provisioning: yubikey::Provisioning) -> Result<Option<yubikey::EnrolledKey>> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::provision(&app_state, &provisioning)
+}
+
+#[tauri::command]
+pub fn rotate_yubikey_key(state: TauriState, serial: u32, pin: &str, management_key: Option<String>) -> Result<yubikey::EnrolledKey> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::rotate(&app_state, serial, pin, management_key.as_deref())
+}
+
+#[tauri::command]
+pub fn yubikey_pin_status(state: TauriState, serial: u32) -> Result<yubikey::PinStatus> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::pin_status(app_state.piv(), serial)
+}
+
+#[tauri::command]
+pub fn change_yubikey_pin(state: TauriState, serial: u32, current_pin: &str, new_pin: &str) -> Result<()> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::change_pin(app_state.piv(), serial, current_pin, new_pin)
 }
+
 #[tauri::command]
-pub fn encrypt_with_yubikey(yubikey_serial: u32, data: String) -> Result<String> {
-    println!("encrypt_with_yubikey {:?}", yubikey_serial);
-    println!("encrypt_with_yubikey {:?}", data);
-    Err(Error::YubiKeyError("Not implemented".to_string()))
-    // yubikey::encrypt_with_yubikey(yubikey_serial, &data).map_err(|e| Error::YubiKeyError(e.to_string()))
+pub fn unblock_yubikey_pin(state: TauriState, serial: u32, puk: &str, new_pin: &str) -> Result<()> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::unblock_pin(app_state.piv(), serial, puk, new_pin)
 }
 
 #[tauri::command]
-pub fn save_yubikey_settings(state: TauriState, serial: u32, public_key: String) -> Result<()> {
+pub fn list_enrolled_yubikeys(state: TauriState) -> Result<Vec<yubikey::EnrolledKey>> {
     let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
-    let list = yubikey::list_yubikeys().map_err(|e| Error::YubiKeyError(e.to_string()))?;
-    let found = list.iter().find(move |x| x.serial.unwrap() == serial).ok_or(Error::YubiKeyError("YubiKey not found".to_string()))?;
-    let mut selected = found.clone();
-    selected.set_pub_key(public_key);
-    selected.save(&app_state).map_err(|e| Error::YubiKeyError(e.to_string()))?;
-    Ok(())
-}
\ No newline at end of file
+    Ok(yubikey::YubiKeySettings::load(&app_state)?.keys().to_vec())
+}
+
+#[tauri::command]
+pub fn rename_enrolled_yubikey(state: TauriState, serial: u32, label: &str) -> Result<()> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::rename_enrolled(&app_state, serial, label)
+}
+
+#[tauri::command]
+pub fn remove_enrolled_yubikey(state: TauriState, serial: u32) -> Result<()> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::remove_enrolled(&app_state, serial)
+}
+
+#[tauri::command]
+pub fn rewrap_for_yubikey(state: TauriState, serial: u32, pin: &str) -> Result<()> {
+    let app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::rewrap_for_backup(&app_state, serial, pin)
+}
+
+#[tauri::command]
+pub fn unlock_with_yubikey(state: TauriState, pin: &str, serial: Option<u32>) -> Result<()> {
+    let mut app_state = state.lock().map_err(|e| Error::StateLock(e.to_string()))?;
+    yubikey::unlock(&mut app_state, pin, serial)
+}
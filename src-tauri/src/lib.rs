@@ -2,13 +2,17 @@
 mod encrypt;
 mod file_system;
 mod secrets;
+mod vaults;
 mod error;
 mod app_state;
+mod session;
+mod lockout;
 mod ipc;
 pub mod yubikey;
 use tauri_plugin_fs::FsExt;
-use tauri::Manager;
+use tauri::{Emitter, Manager};
 use std::sync::Mutex;
+use std::time::Duration;
 
 pub use file_system::FileSystem;
 pub use error::{Error, Result};
@@ -18,13 +22,30 @@ use ipc::*;
 
 pub struct W<T>(pub T);
 
+// How often idle sessions are looked for while the app does nothing
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Every command but the status checks counts as activity, see `ipc::touch_session`
+fn with_session<R: tauri::Runtime>(
+    handler: impl Fn(tauri::ipc::Invoke<R>) -> bool + Send + Sync + 'static,
+) -> impl Fn(tauri::ipc::Invoke<R>) -> bool + Send + Sync + 'static {
+    move |invoke| {
+        touch_session(&invoke);
+        handler(invoke)
+    }
+}
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .setup(|app| {
             // Initialize AppState first
-            app.manage(Mutex::new(AppState::default()));
+            let mut state = AppState::default();
+            let handle = app.handle().clone();
+            state.set_lock_listener(Box::new(move || {
+                let _ = handle.emit("vault-locked", ());
+            }));
+            app.manage(Mutex::new(state));
             let fs = FileSystem::default();
             // Initialize file system
             fs.init()?;
@@ -33,20 +54,53 @@ pub fn run() {
             let scope = app.fs_scope();
             scope.allow_directory(&app_dir, false)?;
 
+            // Locks sessions that expire between commands
+            let handle = app.handle().clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(SESSION_CHECK_INTERVAL);
+                if let Ok(mut state) = handle.state::<Mutex<AppState>>().lock() {
+                    state.expire_session();
+                }
+            });
+
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![
+        .invoke_handler(with_session(tauri::generate_handler![
             is_authenticated,
             create_secret,
             get_secrets,
             get_secret,
+            update_secret,
+            delete_secret,
+            create_vault,
+            list_vaults,
+            rename_vault,
+            delete_vault,
+            get_vault_policy,
+            set_vault_policy,
             save_master_password,
             verify_master_password,
+            change_master_password,
+            recover_with_recovery_key,
             log_out,
             list_yubikeys,
             encrypt_with_yubikey,
             save_yubikey_settings,
-        ])
+            provision_yubikey,
+            rotate_yubikey_key,
+            yubikey_pin_status,
+            change_yubikey_pin,
+            unblock_yubikey_pin,
+            list_enrolled_yubikeys,
+            rename_enrolled_yubikey,
+            remove_enrolled_yubikey,
+            rewrap_for_yubikey,
+            unlock_with_yubikey,
+            get_session_policy,
+            set_session_policy,
+            get_lockout_policy,
+            set_lockout_policy,
+        ]))
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_shell::init())
         .run(tauri::generate_context!())
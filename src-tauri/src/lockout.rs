@@ -0,0 +1,274 @@
+use crate::yubikey::YubiKeySettings;
+use crate::{AppState, Error, FileSystem, MasterPassword, Result};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Wrong master passwords in a row, kept in the app data directory so
+/// restarting the app does not reset the backoff.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Attempts {
+    pub failures: u32,
+    /// Unix time of the last failure, in seconds.
+    pub last_failure: u64,
+}
+
+impl Attempts {
+    // Tries allowed before the backoff starts
+    const FREE: u32 = 3;
+    const BASE_DELAY_SECS: u64 = 5;
+    const MAX_DELAY_SECS: u64 = 60 * 60;
+
+    pub fn load(fs: &FileSystem) -> Self {
+        fs::read_to_string(fs.login_attempts())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, fs: &FileSystem) -> Result<()> {
+        let json = serde_json::to_string(self).map_err(|e| Error::Custom(e.to_string()))?;
+        fs::write(fs.login_attempts(), json).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    /// Seconds between the last failure and the next try, doubling with every failure.
+    pub fn delay_secs(&self) -> u64 {
+        if self.failures < Self::FREE {
+            return 0;
+        }
+        let doublings = (self.failures - Self::FREE).min(32);
+        (Self::BASE_DELAY_SECS << doublings).min(Self::MAX_DELAY_SECS)
+    }
+
+    /// Seconds left before the next try at `now`.
+    pub fn retry_after(&self, now: u64) -> u64 {
+        // A clock set back never makes the wait longer than the delay
+        (self.last_failure + self.delay_secs()).saturating_sub(now).min(self.delay_secs())
+    }
+
+    fn record_failure(&mut self, now: u64) {
+        self.failures += 1;
+        self.last_failure = now;
+    }
+}
+
+/// What happens past a number of wrong master passwords, on top of the backoff.
+/// Saved in `lockout.json`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LockoutPolicy {
+    /// The password is only accepted once the YubiKey unlocked the session.
+    pub require_yubikey_after: Option<u32>,
+    /// Logs out of the session, wiping the keys in memory.
+    pub wipe_session_after: Option<u32>,
+}
+
+impl LockoutPolicy {
+    pub fn load(fs: &FileSystem) -> Self {
+        fs::read_to_string(fs.lockout_policy())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Refuses to require a YubiKey when none is enrolled, the vault could
+    /// not be opened again.
+    pub fn save(&self, state: &AppState) -> Result<()> {
+        if self.require_yubikey_after == Some(0) || self.wipe_session_after == Some(0) {
+            return Err(Error::Validation("Failure thresholds must be at least 1".to_string()));
+        }
+        if self.require_yubikey_after.is_some() && YubiKeySettings::load(state)?.keys().is_empty() {
+            return Err(Error::Validation("Enroll a YubiKey before requiring it".to_string()));
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|e| Error::Custom(e.to_string()))?;
+        fs::write(state.file_system().lockout_policy(), json).map_err(|e| Error::Io(e.to_string()))
+    }
+}
+
+/// `MasterPassword::verify`, throttled: every wrong password past the first
+/// few doubles the wait before the next try, reported as `Error::TooManyAttempts`.
+pub fn verify_master_password(state: &mut AppState, password: &str) -> Result<String> {
+    throttled(state, |state| MasterPassword::verify(state, password))
+}
+
+/// `MasterPassword::change` for an unlocked session, a wrong old password
+/// counts against the same backoff as the unlock.
+pub fn change_master_password(state: &mut AppState, old_password: &str, new_password: &str) -> Result<String> {
+    if !state.is_authenticated() {
+        return Err(Error::Locked("Unlock the vault before changing the master password".to_string()));
+    }
+    throttled(state, |state| MasterPassword::change(state, old_password, new_password))
+}
+
+// Runs `check` unless the backoff or the lockout policy holds it back, and
+// records whether the master password it was given was right
+fn throttled(
+    state: &mut AppState,
+    check: impl FnOnce(&mut AppState) -> crate::encrypt::Result<String>,
+) -> Result<String> {
+    let fs = state.file_system().clone();
+    let now = unix_secs(state.now());
+    let mut attempts = Attempts::load(&fs);
+    let wait = attempts.retry_after(now);
+    if wait > 0 {
+        return Err(Error::TooManyAttempts(wait));
+    }
+    let policy = LockoutPolicy::load(&fs);
+    if policy.require_yubikey_after.is_some_and(|limit| attempts.failures >= limit)
+        && !state.is_yubikey_authenticated()
+    {
+        return Err(Error::YubiKeyRequired(format!(
+            "{} wrong passwords, unlock with the YubiKey first",
+            attempts.failures
+        )));
+    }
+    match check(state) {
+        Ok(message) => {
+            if attempts != Attempts::default() {
+                Attempts::default().save(&fs)?;
+            }
+            Ok(message)
+        }
+        Err(e) => {
+            if let crate::encrypt::Error::WrongPassword(_) = e {
+                attempts.record_failure(now);
+                attempts.save(&fs)?;
+                if policy.wipe_session_after.is_some_and(|limit| attempts.failures >= limit) {
+                    state.log_out();
+                }
+            }
+            Err(Error::MasterPassword(e.to_string()))
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::ManualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn state_with_clock() -> (AppState, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let mut state = AppState::new_test("password");
+        state.set_clock(clock.clone());
+        state.log_out();
+        (state, clock)
+    }
+
+    #[test]
+    fn test_backoff_doubles() {
+        let delays: Vec<u64> = (0..8)
+            .map(|failures| Attempts { failures, last_failure: 0 }.delay_secs())
+            .collect();
+        assert_eq!(delays, vec![0, 0, 0, 5, 10, 20, 40, 80]);
+        assert_eq!(Attempts { failures: 100, last_failure: 0 }.delay_secs(), 60 * 60);
+        let attempts = Attempts { failures: 4, last_failure: 1000 };
+        assert_eq!(attempts.retry_after(1004), 6);
+        assert_eq!(attempts.retry_after(1010), 0);
+        assert_eq!(attempts.retry_after(0), 10);
+    }
+
+    #[test]
+    fn test_wrong_passwords_are_throttled() {
+        let (mut state, clock) = state_with_clock();
+        for _ in 0..3 {
+            assert!(matches!(verify_master_password(&mut state, "wrong"), Err(Error::MasterPassword(_))));
+        }
+        // Even the right password waits
+        assert!(matches!(verify_master_password(&mut state, "password"), Err(Error::TooManyAttempts(5))));
+        clock.advance(Duration::from_secs(2));
+        assert!(matches!(verify_master_password(&mut state, "password"), Err(Error::TooManyAttempts(3))));
+        clock.advance(Duration::from_secs(3));
+        assert!(matches!(verify_master_password(&mut state, "wrong"), Err(Error::MasterPassword(_))));
+        assert!(matches!(verify_master_password(&mut state, "password"), Err(Error::TooManyAttempts(10))));
+
+        clock.advance(Duration::from_secs(10));
+        verify_master_password(&mut state, "password").unwrap();
+        assert!(state.is_authenticated());
+        assert_eq!(Attempts::load(state.file_system()), Attempts::default());
+    }
+
+    #[test]
+    fn test_attempts_survive_restart() {
+        let (mut state, clock) = state_with_clock();
+        for _ in 0..4 {
+            let _ = verify_master_password(&mut state, "wrong");
+            clock.advance(Duration::from_secs(5));
+        }
+        clock.rewind(Duration::from_secs(5));
+        // Nothing is kept in memory, the failures are read back from the app data directory
+        let fs = state.file_system().clone();
+        assert!(fs.login_attempts().starts_with(fs.app_data_directory()));
+        assert_eq!(Attempts::load(&fs).failures, 4);
+        assert!(matches!(verify_master_password(&mut state, "password"), Err(Error::TooManyAttempts(10))));
+    }
+
+    #[test]
+    fn test_change_password_is_throttled() {
+        let (mut state, clock) = state_with_clock();
+        assert!(matches!(change_master_password(&mut state, "password", "new"), Err(Error::Locked(_))));
+        assert!(!state.is_authenticated());
+
+        MasterPassword::verify(&mut state, "password").unwrap();
+        for _ in 0..3 {
+            assert!(matches!(change_master_password(&mut state, "wrong", "new"), Err(Error::MasterPassword(_))));
+        }
+        // The unlock and the change share the failures
+        assert!(matches!(change_master_password(&mut state, "password", "new"), Err(Error::TooManyAttempts(5))));
+        assert!(matches!(verify_master_password(&mut state, "password"), Err(Error::TooManyAttempts(5))));
+        clock.advance(Duration::from_secs(5));
+        change_master_password(&mut state, "password", "new").unwrap();
+        assert_eq!(Attempts::load(state.file_system()), Attempts::default());
+        state.log_out();
+        verify_master_password(&mut state, "new").unwrap();
+    }
+
+    #[test]
+    fn test_require_yubikey_policy() {
+        let (mut state, clock) = state_with_clock();
+        MasterPassword::verify(&mut state, "password").unwrap();
+        let info = crate::yubikey::list_yubikeys(state.piv()).unwrap().remove(0);
+        crate::yubikey::enroll(&state, info.serial.unwrap(), None, info.pub_key.unwrap()).unwrap();
+        let policy = LockoutPolicy { require_yubikey_after: Some(2), wipe_session_after: None };
+        policy.save(&state).unwrap();
+        state.log_out();
+
+        for _ in 0..2 {
+            let _ = verify_master_password(&mut state, "wrong");
+        }
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(verify_master_password(&mut state, "password"), Err(Error::YubiKeyRequired(_))));
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        verify_master_password(&mut state, "password").unwrap();
+        assert!(state.has_master_key());
+    }
+
+    #[test]
+    fn test_wipe_session_policy() {
+        let (mut state, clock) = state_with_clock();
+        MasterPassword::verify(&mut state, "password").unwrap();
+        let policy = LockoutPolicy { require_yubikey_after: None, wipe_session_after: Some(2) };
+        policy.save(&state).unwrap();
+
+        let _ = verify_master_password(&mut state, "wrong");
+        assert!(state.has_master_key());
+        clock.advance(Duration::from_secs(1));
+        let _ = verify_master_password(&mut state, "wrong");
+        assert!(!state.is_authenticated());
+        assert!(!state.has_master_key());
+    }
+
+    #[test]
+    fn test_policy_validation() {
+        let state = AppState::new_unauthenticated_test();
+        let zero = LockoutPolicy { require_yubikey_after: None, wipe_session_after: Some(0) };
+        assert!(matches!(zero.save(&state), Err(Error::Validation(_))));
+        let policy = LockoutPolicy { require_yubikey_after: Some(3), wipe_session_after: None };
+        assert!(matches!(policy.save(&state), Err(Error::Validation(_))));
+        assert_eq!(LockoutPolicy::load(state.file_system()), LockoutPolicy::default());
+    }
+}
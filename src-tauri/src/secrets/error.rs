@@ -8,6 +8,12 @@ pub enum Error {
     Io(String),
     AppStateLock(String),
     MasterPassword(String),
+    NotFound(String),
+    Validation(String),
+    Vault(String),
+    YubiKey(String),
+    WrongPin(u8),
+    PinBlocked(String),
 }
 
 impl From<serde_json::Error> for Error {
@@ -17,7 +23,31 @@ impl From<serde_json::Error> for Error {
 }
 impl From<crate::encrypt::Error> for Error {
     fn from(e: crate::encrypt::Error) -> Self {
-        Error::EncryptMod(e.to_string())
+        match e {
+            crate::encrypt::Error::WrongPin(tries) => Error::WrongPin(tries),
+            crate::encrypt::Error::PinBlocked(msg) => Error::PinBlocked(msg),
+            e => Error::EncryptMod(e.to_string()),
+        }
+    }
+}
+
+impl From<crate::vaults::Error> for Error {
+    fn from(e: crate::vaults::Error) -> Self {
+        match e {
+            crate::vaults::Error::NotFound(msg) => Error::NotFound(msg),
+            crate::vaults::Error::Validation(msg) => Error::Validation(msg),
+            e => Error::Vault(e.to_string()),
+        }
+    }
+}
+
+impl From<crate::Error> for Error {
+    fn from(e: crate::Error) -> Self {
+        match e {
+            crate::Error::WrongPin(tries) => Error::WrongPin(tries),
+            crate::Error::PinBlocked(msg) => Error::PinBlocked(msg),
+            e => Error::YubiKey(e.to_string()),
+        }
     }
 }
 
@@ -1,13 +1,16 @@
 mod error;
 pub use error::{Result, Error};
-use crate::{AppState, MasterPassword};
+use crate::AppState;
+use crate::encrypt::{encryptor_from_pem, DataKey, Encrypt, AES};
+use crate::file_system::Transaction;
+use crate::vaults::Vault;
+use crate::yubikey::{EnrolledKey, YubiKeySettings};
 
+use std::collections::BTreeMap;
 use std::fs;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
-static VAULT: &str = "default";
-
-
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct NewSecretForm {
     encryption: Encryption,
@@ -16,12 +19,34 @@ pub struct NewSecretForm {
     value: String,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Encryption {
     AES,
     Yubikey,
 }
 
+impl Encryption {
+    /// Ciphers sealing the value on top of the vault key, one per enrolled
+    /// YubiKey by serial. Empty when the vault key is enough.
+    fn sealers(&self, state: &AppState) -> Result<Vec<(u32, Box<dyn Encrypt>)>> {
+        match self {
+            Encryption::AES => Ok(vec![]),
+            Encryption::Yubikey => {
+                let settings = YubiKeySettings::load(state)?;
+                if settings.keys().is_empty() {
+                    return Err(Error::YubiKey("No YubiKey enrolled".to_string()));
+                }
+                settings
+                    .keys()
+                    .iter()
+                    .map(|key| Ok((key.serial, encryptor_from_pem(&key.pub_key)?)))
+                    .collect()
+            }
+        }
+    }
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq)]
 pub struct Secret {
     id: String,
@@ -29,6 +54,48 @@ pub struct Secret {
     name: String,
     encryption: Encryption,
     value: String,
+    /// Set when `value` is withheld because opening it needs the YubiKey.
+    #[serde(default)]
+    locked: bool,
+}
+
+// Form of a secret on disk, always encrypted with the vault key. Values of
+// secrets sealed to YubiKeys are kept in `sealed_for` instead of `value`, one
+// copy per enrolled key. `sealed` is the only copy of records written when a
+// single YubiKey could be enrolled.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Record {
+    id: String,
+    kind: String,
+    name: String,
+    encryption: Encryption,
+    #[serde(default)]
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sealed_for: BTreeMap<u32, String>,
+}
+
+impl Record {
+    fn is_sealed(&self) -> bool {
+        self.sealed.is_some() || !self.sealed_for.is_empty()
+    }
+
+    // A single-YubiKey copy could belong to any enrolled key, the card tells
+    fn sealed_copy(&self, serial: u32) -> Option<&String> {
+        self.sealed_for.get(&serial).or(self.sealed.as_ref())
+    }
+
+    /// Opens the value with the first connected enrolled YubiKey holding a copy.
+    fn unseal(&self, state: &AppState, pin: &str) -> Result<String> {
+        // Opening does not need the master password, the cards vouch for their settings
+        let (key, device) = YubiKeySettings::connected(state, pin, |key| self.sealed_copy(key.serial).is_some())?;
+        let sealed = self
+            .sealed_copy(key.serial)
+            .ok_or(Error::YubiKey("No connected YubiKey can open the secret".to_string()))?;
+        Ok(device.decrypt(sealed)?)
+    }
 }
 
 impl From<NewSecretForm> for Secret {
@@ -39,36 +106,71 @@ impl From<NewSecretForm> for Secret {
             name: data.name,
             value: data.value,
             encryption: data.encryption,
+            locked: false,
         }
     }
 }
 
 impl Secret {
-    pub fn save(&self, state: &AppState) -> Result<()> {
-        let fs = state.file_system();
-        let json = serde_json::to_string(&self)?;
-        let encryptor = MasterPassword::from_state(state)?;
-        let encrypted = encryptor.encrypt_string(&json)?;
-        let out_path = fs.secret_path(VAULT, &self.id);
+    pub fn save(&self, state: &AppState, vault: &str) -> Result<()> {
+        self.validate()?;
+        let out_path = Self::path(state, vault, &self.id)?;
+        let mut record = Record {
+            id: self.id.clone(),
+            kind: self.kind.clone(),
+            name: self.name.clone(),
+            encryption: self.encryption,
+            value: self.value.clone(),
+            sealed: None,
+            sealed_for: BTreeMap::new(),
+        };
+        for (serial, sealer) in self.encryption.sealers(state)? {
+            record.sealed_for.insert(serial, sealer.encrypt(&record.value)?);
+        }
+        if record.is_sealed() {
+            record.value = String::new();
+        }
+        let json = serde_json::to_string(&record)?;
+        let data_key = Vault::find(state, vault)?.data_key(state)?;
+        let encrypted = data_key.cipher().encrypt_string(&json)?;
         fs::write(out_path, encrypted)?;
         Ok(())
     }
-    
-    pub fn find(state: &AppState, id: &str) -> Result<Secret> {
-        let fs = state.file_system();
-        let encryptor = MasterPassword::from_state(state)?;
-        let secret_path = fs.secret_path(VAULT, id);
-        let encrypted = fs::read_to_string(secret_path)?;
-        let decrypted =
-            encryptor.decrypt_string(&encrypted)?;
-        let secret: Secret = serde_json::from_str(&decrypted)?;
+
+    /// Replaces the content of an existing secret, keeping its id.
+    pub fn update(state: &AppState, vault: &str, id: &str, data: NewSecretForm) -> Result<Secret> {
+        Self::existing_path(state, vault, id)?;
+        let secret = Secret {
+            id: id.to_string(),
+            ..Secret::from(data)
+        };
+        secret.save(state, vault)?;
         Ok(secret)
     }
-    
-    pub fn all(state: &AppState) -> Result<Vec<Secret>> {
+
+    /// Removes a secret, only once the session can open the vault.
+    pub fn delete(state: &AppState, vault: &str, id: &str) -> Result<()> {
+        let path = Self::existing_path(state, vault, id)?;
+        Vault::find(state, vault)?.data_key(state)?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Reads a secret, opening a sealed value when `pin` is given and leaving it locked otherwise.
+    pub fn find(state: &AppState, vault: &str, id: &str, pin: Option<&str>) -> Result<Secret> {
+        let secret_path = Self::existing_path(state, vault, id)?;
+        let data_key = Vault::find(state, vault)?.data_key(state)?;
+        let record = Self::read(data_key.cipher(), &secret_path)?;
+        Self::open(state, record, pin)
+    }
+
+    /// Lists the secrets of a vault, values sealed to a device are left locked.
+    pub fn all(state: &AppState, vault: &str) -> Result<Vec<Secret>> {
         let fs = state.file_system();
-        let encryptor = MasterPassword::from_state(state)?;
-        let secret_dir = fs.vault_folder(VAULT);
+        let vault = Vault::find(state, vault)?;
+        let data_key = vault.data_key(state)?;
+        let encryptor = data_key.cipher();
+        let secret_dir = fs.vault_folder(vault.name());
         let mut secrets = vec![];
         for entry in fs::read_dir(secret_dir)? {
             let entry = entry?;
@@ -79,15 +181,141 @@ impl Secret {
                     .map(|s| s == "enc")
                     .unwrap_or(false)
             {
-                let path = entry.path();
-                let encrypted = fs::read_to_string(&path)?;
-                let decrypted = encryptor.decrypt_string(&encrypted)?;
-                let secret: Secret = serde_json::from_str(&decrypted)?;
-                secrets.push(secret);
+                let record = Self::read(encryptor, &entry.path())?;
+                secrets.push(Self::open(state, record, None)?);
             }
         }
         Ok(secrets)
     }
+
+    fn read(encryptor: &AES, path: &Path) -> Result<Record> {
+        let encrypted = fs::read_to_string(path)?;
+        let decrypted = encryptor.decrypt_string(&encrypted)?;
+        Ok(serde_json::from_str(&decrypted)?)
+    }
+
+    fn open(state: &AppState, record: Record, pin: Option<&str>) -> Result<Secret> {
+        let mut secret = Secret {
+            id: record.id.clone(),
+            kind: record.kind.clone(),
+            name: record.name.clone(),
+            encryption: record.encryption,
+            value: record.value.clone(),
+            locked: false,
+        };
+        match record.encryption {
+            Encryption::Yubikey if record.is_sealed() => match pin {
+                Some(pin) => secret.value = record.unseal(state, pin)?,
+                None => secret.locked = true,
+            },
+            // Written before YubiKey secrets were sealed, the vault key is all there is
+            Encryption::Yubikey => {}
+            Encryption::AES if record.is_sealed() => {
+                return Err(Error::Validation(format!("Secret {} is sealed but stored as AES", record.id)));
+            }
+            Encryption::AES => {}
+        }
+        Ok(secret)
+    }
+
+    /// Seals the YubiKey secrets of `vault` for `key` as well. `opener` is an
+    /// enrolled YubiKey holding a copy of each, by serial.
+    pub fn reseal_for(
+        state: &AppState,
+        vault: &Vault,
+        data_key: &DataKey,
+        opener: (u32, &dyn Encrypt),
+        key: &EnrolledKey,
+        transaction: &mut Transaction,
+    ) -> Result<()> {
+        let cipher = data_key.cipher();
+        let sealer = encryptor_from_pem(&key.pub_key)?;
+        let (opener_serial, opener) = opener;
+        for (path, mut record) in Self::sealed_records(state, vault, cipher)? {
+            let sealed = record
+                .sealed_copy(opener_serial)
+                .ok_or(Error::YubiKey(format!("Secret {} has no copy for YubiKey {}", record.id, opener_serial)))?;
+            let value = opener.decrypt(sealed)?;
+            // The single-YubiKey copy was opened, so it belongs to the opener
+            if let Some(legacy) = record.sealed.take() {
+                record.sealed_for.entry(opener_serial).or_insert(legacy);
+            }
+            record.sealed_for.insert(key.serial, sealer.encrypt(&value)?);
+            transaction.stage(&path, cipher.encrypt_string(&serde_json::to_string(&record)?)?)?;
+        }
+        Ok(())
+    }
+
+    /// Number of YubiKey secrets of `vault` without a copy for any of `serials`.
+    pub fn count_sealed_without(state: &AppState, vault: &Vault, data_key: &DataKey, serials: &[u32]) -> Result<usize> {
+        let records = Self::sealed_records(state, vault, data_key.cipher())?;
+        Ok(records
+            .iter()
+            .filter(|(_, record)| !serials.iter().any(|serial| record.sealed_for.contains_key(serial)))
+            .count())
+    }
+
+    /// Drops the copies of the YubiKey secrets of `vault` sealed for `serial`.
+    pub fn remove_sealed_for(
+        state: &AppState,
+        vault: &Vault,
+        data_key: &DataKey,
+        serial: u32,
+        transaction: &mut Transaction,
+    ) -> Result<()> {
+        let cipher = data_key.cipher();
+        for (path, mut record) in Self::sealed_records(state, vault, cipher)? {
+            if record.sealed_for.remove(&serial).is_some() {
+                transaction.stage(&path, cipher.encrypt_string(&serde_json::to_string(&record)?)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sealed_records(state: &AppState, vault: &Vault, cipher: &AES) -> Result<Vec<(PathBuf, Record)>> {
+        let mut records = vec![];
+        for entry in fs::read_dir(state.file_system().vault_folder(vault.name()))? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().map(|s| s == "enc").unwrap_or(false) {
+                let record = Self::read(cipher, &path)?;
+                if record.is_sealed() {
+                    records.push((path, record));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(Error::Validation("Secret name can not be empty".to_string()));
+        }
+        if self.kind.trim().is_empty() {
+            return Err(Error::Validation("Secret kind can not be empty".to_string()));
+        }
+        Ok(())
+    }
+
+    // Ids end up in a file name, reject anything that could escape the vault folder
+    fn path(state: &AppState, vault: &str, id: &str) -> Result<PathBuf> {
+        let vault = Vault::find(state, vault)?;
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(Error::Validation(format!("Invalid secret id: {}", id)));
+        }
+        Ok(state.file_system().secret_path(vault.name(), id))
+    }
+
+    fn existing_path(state: &AppState, vault: &str, id: &str) -> Result<PathBuf> {
+        let path = Self::path(state, vault, id)?;
+        if !path.exists() {
+            return Err(Error::NotFound(format!("Secret {} not found", id)));
+        }
+        Ok(path)
+    }
 }
 
 
@@ -96,6 +324,7 @@ impl Secret {
 mod tests {
     use super::*;
     use crate::AppState;
+    use crate::vaults::DEFAULT_VAULT;
 
     fn setup() -> AppState {
         AppState::new_test("secret")
@@ -111,11 +340,264 @@ mod tests {
             kind: "test".to_string(),
             name: "test".to_string(),
             value: "test".to_string(),
+            locked: false,
         };
-        secret.save(&state).unwrap();
-        let read_secret = Secret::find(&state, id).unwrap();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+        let read_secret = Secret::find(&state, DEFAULT_VAULT, id, None).unwrap();
         assert_eq!(secret, read_secret);
-        let all = Secret::all(&state).unwrap();
+        let all = Secret::all(&state, DEFAULT_VAULT).unwrap();
         assert_eq!([secret], all.as_slice());
     }
+
+    fn form(name: &str, value: &str) -> NewSecretForm {
+        NewSecretForm {
+            encryption: Encryption::AES,
+            kind: "test".to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_update_secret_keeps_id() {
+        let state = setup();
+        let secret: Secret = form("test", "old").into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+
+        let updated = Secret::update(&state, DEFAULT_VAULT, &secret.id, form("renamed", "new")).unwrap();
+        assert_eq!(updated.id, secret.id);
+
+        let read_secret = Secret::find(&state, DEFAULT_VAULT, &secret.id, None).unwrap();
+        assert_eq!(read_secret.name, "renamed");
+        assert_eq!(read_secret.value, "new");
+        assert_eq!(Secret::all(&state, DEFAULT_VAULT).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_update_missing_secret() {
+        let state = setup();
+        let result = Secret::update(&state, DEFAULT_VAULT, "missing", form("test", "test"));
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn test_update_validates_form() {
+        let state = setup();
+        let secret: Secret = form("test", "test").into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+        let result = Secret::update(&state, DEFAULT_VAULT, &secret.id, form("  ", "test"));
+        assert!(matches!(result, Err(Error::Validation(_))));
+        assert_eq!(Secret::find(&state, DEFAULT_VAULT, &secret.id, None).unwrap().name, "test");
+    }
+
+    #[test]
+    fn test_delete_secret() {
+        let state = setup();
+        let secret: Secret = form("test", "test").into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+
+        Secret::delete(&state, DEFAULT_VAULT, &secret.id).unwrap();
+        assert!(matches!(Secret::find(&state, DEFAULT_VAULT, &secret.id, None), Err(Error::NotFound(_))));
+        assert!(matches!(Secret::delete(&state, DEFAULT_VAULT, &secret.id), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn test_locked_vault_keeps_secrets() {
+        let mut state = setup();
+        let secret: Secret = form("test", "test").into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+        state.log_out();
+
+        assert!(matches!(Secret::delete(&state, DEFAULT_VAULT, &secret.id), Err(Error::Vault(_))));
+        assert!(state.file_system().secret_path(DEFAULT_VAULT, &secret.id).exists());
+    }
+
+    #[test]
+    fn test_invalid_id() {
+        let state = setup();
+        assert!(matches!(Secret::delete(&state, DEFAULT_VAULT, "../master_password"), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_secrets_are_scoped_to_vault() {
+        let state = setup();
+        Vault::create(&state, "work").unwrap();
+        let secret: Secret = form("test", "test").into();
+        secret.save(&state, "work").unwrap();
+
+        assert_eq!(Secret::all(&state, "work").unwrap().len(), 1);
+        assert!(Secret::all(&state, DEFAULT_VAULT).unwrap().is_empty());
+        assert!(matches!(Secret::find(&state, DEFAULT_VAULT, &secret.id, None), Err(Error::NotFound(_))));
+        assert!(matches!(Secret::all(&state, "missing"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn test_yubikey_secret_is_sealed() {
+        let state = setup();
+        let secret: Secret = NewSecretForm {
+            encryption: Encryption::Yubikey,
+            ..form("card", "card value")
+        }
+        .into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+
+        let data_key = Vault::find(&state, DEFAULT_VAULT).unwrap().data_key(&state).unwrap();
+        let encryptor = data_key.cipher();
+        let path = state.file_system().secret_path(DEFAULT_VAULT, &secret.id);
+        let record = Secret::read(encryptor, &path).unwrap();
+        assert!(record.value.is_empty());
+        assert!(record.sealed.is_none());
+        assert_eq!(record.sealed_for.keys().collect::<Vec<_>>(), vec![&AppState::TEST_YUBIKEY_SERIAL]);
+
+        let listed = Secret::all(&state, DEFAULT_VAULT).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].locked);
+        assert_eq!(listed[0].name, "card");
+        assert!(listed[0].value.is_empty());
+        assert!(Secret::find(&state, DEFAULT_VAULT, &secret.id, None).unwrap().locked);
+    }
+
+    #[test]
+    fn test_yubikey_secret_opens_with_pin() {
+        let state = setup();
+        let secret: Secret = NewSecretForm {
+            encryption: Encryption::Yubikey,
+            ..form("card", "card value")
+        }
+        .into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+
+        let opened = Secret::find(&state, DEFAULT_VAULT, &secret.id, Some("123456")).unwrap();
+        assert!(!opened.locked);
+        assert_eq!(opened.value, "card value");
+        assert!(Secret::find(&state, DEFAULT_VAULT, &secret.id, Some("000000")).is_err());
+    }
+
+    #[test]
+    fn test_reads_yubikey_secret_saved_unsealed() {
+        // Before sealing, YubiKey secrets were stored like AES ones
+        let state = setup();
+        let secret = Secret {
+            encryption: Encryption::Yubikey,
+            ..Secret::from(form("old", "old value"))
+        };
+        let record = Record {
+            id: secret.id.clone(),
+            kind: secret.kind.clone(),
+            name: secret.name.clone(),
+            encryption: secret.encryption,
+            value: secret.value.clone(),
+            sealed: None,
+            sealed_for: BTreeMap::new(),
+        };
+        let data_key = Vault::find(&state, DEFAULT_VAULT).unwrap().data_key(&state).unwrap();
+        let encryptor = data_key.cipher();
+        let path = state.file_system().secret_path(DEFAULT_VAULT, &secret.id);
+        fs::write(&path, encryptor.encrypt_string(&serde_json::to_string(&record).unwrap()).unwrap()).unwrap();
+
+        assert_eq!(Secret::find(&state, DEFAULT_VAULT, &secret.id, None).unwrap(), secret);
+
+        // The encryption decides how the value opens, a sealed copy on an AES record is refused
+        let sealed = Record {
+            encryption: Encryption::AES,
+            value: String::new(),
+            sealed: Some("copy".to_string()),
+            ..record
+        };
+        fs::write(&path, encryptor.encrypt_string(&serde_json::to_string(&sealed).unwrap()).unwrap()).unwrap();
+        assert!(matches!(Secret::find(&state, DEFAULT_VAULT, &secret.id, None), Err(Error::Validation(_))));
+    }
+
+    const BACKUP: u32 = 20_000_002;
+
+    // Connects a second card with fresh P-256 keys and enrolls it
+    fn enroll_backup(state: &AppState) {
+        use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+        use yubikey::piv::SlotId;
+        let pem = || p256::SecretKey::random(&mut rand::rngs::OsRng).to_pkcs8_pem(LineEnding::LF).unwrap().to_string();
+        let card = crate::yubikey::VirtualCard::new(BACKUP)
+            .with_key(SlotId::KeyManagement, &pem())
+            .unwrap()
+            .with_key(SlotId::Authentication, &pem())
+            .unwrap();
+        state.virtual_piv().insert(card);
+        let info = crate::yubikey::list_yubikeys(state.piv())
+            .unwrap()
+            .into_iter()
+            .find(|info| info.serial == Some(BACKUP))
+            .unwrap();
+        crate::yubikey::enroll(state, BACKUP, Some("Backup".to_string()), info.pub_key.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_backup_yubikey_opens_secrets() {
+        let state = setup();
+        let secret: Secret = NewSecretForm {
+            encryption: Encryption::Yubikey,
+            ..form("card", "card value")
+        }
+        .into();
+        secret.save(&state, DEFAULT_VAULT).unwrap();
+        enroll_backup(&state);
+
+        // The secret is only sealed for the primary key until it is re-wrapped
+        assert!(crate::yubikey::remove_enrolled(&state, AppState::TEST_YUBIKEY_SERIAL).is_err());
+        crate::yubikey::rewrap_for_backup(&state, BACKUP, "123456").unwrap();
+        crate::yubikey::remove_enrolled(&state, AppState::TEST_YUBIKEY_SERIAL).unwrap();
+
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        let keyring = vault.keyring(&state).unwrap();
+        assert!(keyring.has_yubikey(BACKUP));
+        assert!(!keyring.has_yubikey(AppState::TEST_YUBIKEY_SERIAL));
+        let path = state.file_system().secret_path(DEFAULT_VAULT, &secret.id);
+        let record = Secret::read(vault.data_key(&state).unwrap().cipher(), &path).unwrap();
+        assert_eq!(record.sealed_for.keys().collect::<Vec<_>>(), vec![&BACKUP]);
+        let opened = Secret::find(&state, DEFAULT_VAULT, &secret.id, Some("123456")).unwrap();
+        assert_eq!(opened.value, "card value");
+        // The last key can go once no secret needs it
+        Secret::delete(&state, DEFAULT_VAULT, &secret.id).unwrap();
+        crate::yubikey::remove_enrolled(&state, BACKUP).unwrap();
+        assert!(YubiKeySettings::load(&state).unwrap().keys().is_empty());
+    }
+
+    #[test]
+    fn test_rewrap_moves_single_yubikey_copy() {
+        // Records written when one YubiKey could be enrolled hold a single `sealed` copy
+        let state = setup();
+        let secret = Secret {
+            encryption: Encryption::Yubikey,
+            ..Secret::from(form("old", "old value"))
+        };
+        let primary = YubiKeySettings::load(&state).unwrap().keys()[0].clone();
+        let record = Record {
+            id: secret.id.clone(),
+            kind: secret.kind.clone(),
+            name: secret.name.clone(),
+            encryption: secret.encryption,
+            value: String::new(),
+            sealed: Some(encryptor_from_pem(&primary.pub_key).unwrap().encrypt("old value").unwrap()),
+            sealed_for: BTreeMap::new(),
+        };
+        let data_key = Vault::find(&state, DEFAULT_VAULT).unwrap().data_key(&state).unwrap();
+        let encryptor = data_key.cipher();
+        let path = state.file_system().secret_path(DEFAULT_VAULT, &secret.id);
+        fs::write(&path, encryptor.encrypt_string(&serde_json::to_string(&record).unwrap()).unwrap()).unwrap();
+        assert_eq!(Secret::find(&state, DEFAULT_VAULT, &secret.id, Some("123456")).unwrap().value, "old value");
+
+        enroll_backup(&state);
+        crate::yubikey::rewrap_for_backup(&state, BACKUP, "123456").unwrap();
+        let record = Secret::read(encryptor, &path).unwrap();
+        assert!(record.sealed.is_none());
+        assert_eq!(
+            record.sealed_for.keys().collect::<Vec<_>>(),
+            vec![&AppState::TEST_YUBIKEY_SERIAL, &BACKUP]
+        );
+    }
+
+    #[test]
+    fn test_rewrap_needs_another_enrolled_yubikey() {
+        let state = setup();
+        assert!(crate::yubikey::rewrap_for_backup(&state, AppState::TEST_YUBIKEY_SERIAL, "123456").is_err());
+        assert!(crate::yubikey::rewrap_for_backup(&state, BACKUP, "123456").is_err());
+    }
 }
\ No newline at end of file
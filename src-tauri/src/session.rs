@@ -0,0 +1,192 @@
+use crate::{Error, FileSystem, Result};
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+/// Source of the current time, a manual clock in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to.
+#[cfg(test)]
+pub struct ManualClock(std::sync::Mutex<SystemTime>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(std::sync::Mutex::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+
+    pub fn rewind(&self, by: Duration) {
+        *self.0.lock().unwrap() -= by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// How long the vault stays unlocked, saved in `session.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SessionPolicy {
+    /// Locks after this many seconds without an IPC command.
+    pub idle_timeout_secs: u64,
+    /// Locks this many seconds after unlocking, whatever the activity.
+    pub max_lifetime_secs: u64,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 5 * 60,
+            max_lifetime_secs: 8 * 60 * 60,
+        }
+    }
+}
+
+impl SessionPolicy {
+    const MIN_IDLE_TIMEOUT_SECS: u64 = 60;
+    const MAX_LIFETIME_SECS: u64 = 7 * 24 * 60 * 60;
+
+    /// Saved policy, the default when there is none or it is out of bounds.
+    pub fn load(fs: &FileSystem) -> Self {
+        fs::read_to_string(fs.session_policy())
+            .ok()
+            .and_then(|content| serde_json::from_str::<Self>(&content).ok())
+            .filter(|policy| policy.validate().is_ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, fs: &FileSystem) -> Result<()> {
+        self.validate()?;
+        let json = serde_json::to_string_pretty(self).map_err(|e| Error::Custom(e.to_string()))?;
+        fs::write(fs.session_policy(), json).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.idle_timeout_secs < Self::MIN_IDLE_TIMEOUT_SECS {
+            return Err(Error::Validation(format!(
+                "The idle timeout must be at least {} seconds",
+                Self::MIN_IDLE_TIMEOUT_SECS
+            )));
+        }
+        if self.max_lifetime_secs < self.idle_timeout_secs {
+            return Err(Error::Validation("The session lifetime must not be shorter than the idle timeout".to_string()));
+        }
+        if self.max_lifetime_secs > Self::MAX_LIFETIME_SECS {
+            return Err(Error::Validation(format!(
+                "The session lifetime must not exceed {} seconds",
+                Self::MAX_LIFETIME_SECS
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Times of an unlocked session.
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    started: SystemTime,
+    last_activity: SystemTime,
+}
+
+impl Session {
+    pub fn start(now: SystemTime) -> Self {
+        Self {
+            started: now,
+            last_activity: now,
+        }
+    }
+
+    pub fn touch(&mut self, now: SystemTime) {
+        self.last_activity = now;
+    }
+
+    /// True once idle or alive for longer than `policy` allows. A clock set
+    /// back before the session started also expires it.
+    pub fn is_expired(&self, policy: &SessionPolicy, now: SystemTime) -> bool {
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or(Duration::MAX);
+        now < self.started
+            || elapsed(self.last_activity) >= Duration::from_secs(policy.idle_timeout_secs)
+            || elapsed(self.started) >= Duration::from_secs(policy.max_lifetime_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> SessionPolicy {
+        SessionPolicy {
+            idle_timeout_secs: 60,
+            max_lifetime_secs: 180,
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let clock = ManualClock::new();
+        let mut session = Session::start(clock.now());
+        clock.advance(Duration::from_secs(59));
+        assert!(!session.is_expired(&policy(), clock.now()));
+        session.touch(clock.now());
+        clock.advance(Duration::from_secs(59));
+        assert!(!session.is_expired(&policy(), clock.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(session.is_expired(&policy(), clock.now()));
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let clock = ManualClock::new();
+        let mut session = Session::start(clock.now());
+        for _ in 0..3 {
+            assert!(!session.is_expired(&policy(), clock.now()));
+            clock.advance(Duration::from_secs(59));
+            session.touch(clock.now());
+        }
+        clock.advance(Duration::from_secs(3));
+        session.touch(clock.now());
+        assert!(session.is_expired(&policy(), clock.now()));
+    }
+
+    #[test]
+    fn test_clock_set_back_expires() {
+        let clock = ManualClock::new();
+        let session = Session::start(clock.now());
+        clock.rewind(Duration::from_secs(1));
+        assert!(session.is_expired(&policy(), clock.now()));
+    }
+
+    #[test]
+    fn test_policy_bounds() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let fs = FileSystem::new_test(temp_dir.path().to_path_buf());
+        assert_eq!(SessionPolicy::load(&fs), SessionPolicy::default());
+
+        policy().save(&fs).unwrap();
+        assert_eq!(SessionPolicy::load(&fs), policy());
+        let too_short = SessionPolicy { idle_timeout_secs: 10, max_lifetime_secs: 60 };
+        assert!(matches!(too_short.save(&fs), Err(Error::Validation(_))));
+        let inverted = SessionPolicy { idle_timeout_secs: 600, max_lifetime_secs: 300 };
+        assert!(matches!(inverted.save(&fs), Err(Error::Validation(_))));
+        // An edited file can not turn the lock off
+        fs::write(fs.session_policy(), r#"{"idle_timeout_secs":0,"max_lifetime_secs":0}"#).unwrap();
+        assert_eq!(SessionPolicy::load(&fs), SessionPolicy::default());
+    }
+}
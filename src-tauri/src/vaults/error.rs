@@ -0,0 +1,41 @@
+use thiserror::Error;
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Error, Debug, serde::Serialize)]
+pub enum Error {
+    EncryptMod(String),
+    Io(String),
+    NotFound(String),
+    AlreadyExists(String),
+    Validation(String),
+    /// The session has not verified what the vault's unlock policy requires.
+    Locked(String),
+    YubiKey(String),
+}
+
+impl From<crate::encrypt::Error> for Error {
+    fn from(e: crate::encrypt::Error) -> Self {
+        Error::EncryptMod(e.to_string())
+    }
+}
+
+impl From<crate::Error> for Error {
+    fn from(e: crate::Error) -> Self {
+        match e {
+            crate::Error::Locked(msg) => Error::Locked(msg),
+            e => Error::YubiKey(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        write!(fmt, "vaults::{self:?}")
+    }
+}
@@ -0,0 +1,517 @@
+mod error;
+pub use error::{Result, Error};
+use crate::encrypt::{DataKey, Keyring, OpenedKey, PublicKey, UnlockPolicy};
+use crate::file_system::Transaction;
+use crate::{AppState, MasterPassword};
+use crate::yubikey::YubiKeySettings;
+
+use std::fs;
+
+pub static DEFAULT_VAULT: &str = "default";
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct Vault {
+    name: String,
+}
+
+impl Vault {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Creates the vault folder and its data key, wrapped by the master password and RSA master key.
+    pub fn create(state: &AppState, name: &str) -> Result<Vault> {
+        Self::validate_name(name)?;
+        let fs = state.file_system();
+        let folder = fs.vault_folder(name);
+        if folder.exists() {
+            return Err(Error::AlreadyExists(format!("Vault {} already exists", name)));
+        }
+        fs::create_dir_all(&folder)?;
+        let vault = Vault { name: name.to_string() };
+        if let Err(e) = vault.create_keyring(state) {
+            let _ = fs::remove_dir_all(&folder);
+            return Err(e);
+        }
+        Ok(vault)
+    }
+
+    pub fn find(state: &AppState, name: &str) -> Result<Vault> {
+        Self::validate_name(name)?;
+        if !state.file_system().vault_folder(name).is_dir() {
+            return Err(Error::NotFound(format!("Vault {} not found", name)));
+        }
+        Ok(Vault { name: name.to_string() })
+    }
+
+    pub fn all(state: &AppState) -> Result<Vec<Vault>> {
+        let fs = state.file_system();
+        let mut vaults = vec![];
+        for entry in fs::read_dir(fs.vaults_folder())? {
+            let path = entry?.path();
+            if !path.is_dir() || path.extension().map(|s| s != "vault").unwrap_or(true) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                vaults.push(Vault { name: name.to_string() });
+            }
+        }
+        vaults.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(vaults)
+    }
+
+    /// Renames a vault the session can open.
+    pub fn rename(state: &AppState, name: &str, new_name: &str) -> Result<Vault> {
+        let vault = Self::find(state, name)?;
+        if vault.name == DEFAULT_VAULT {
+            return Err(Error::Validation("The default vault can not be renamed".to_string()));
+        }
+        vault.data_key(state)?;
+        Self::validate_name(new_name)?;
+        let fs = state.file_system();
+        if fs.vault_folder(new_name).exists() {
+            return Err(Error::AlreadyExists(format!("Vault {} already exists", new_name)));
+        }
+        fs::rename(fs.vault_folder(name), fs.vault_folder(new_name))?;
+        Ok(Vault { name: new_name.to_string() })
+    }
+
+    /// Deletes a vault the session can open, with its secrets.
+    pub fn delete(state: &AppState, name: &str) -> Result<()> {
+        let vault = Self::find(state, name)?;
+        if vault.name == DEFAULT_VAULT {
+            return Err(Error::Validation("The default vault can not be deleted".to_string()));
+        }
+        vault.data_key(state)?;
+        fs::remove_dir_all(state.file_system().vault_folder(name))?;
+        Ok(())
+    }
+
+    /// Gives the vault a data key unless it already has one.
+    pub fn ensure_keyring(&self, state: &AppState) -> Result<()> {
+        if state.file_system().vault_keyring(&self.name).exists() {
+            return Ok(());
+        }
+        self.migrate(state).map(|_| ())
+    }
+
+    /// Opens the data key of the vault with what the session holds: the master
+    /// password, the copy the YubiKey unwrapped, or both as the policy says.
+    /// The YubiKey copy is borrowed from the session.
+    pub fn data_key<'a>(&self, state: &'a AppState) -> Result<OpenedKey<'a>> {
+        let path = state.file_system().vault_keyring(&self.name);
+        if !path.exists() {
+            self.check_policy(state, UnlockPolicy::Password)?;
+            return self.migrate(state).map(OpenedKey::Owned);
+        }
+        let keyring = self.keyring(state)?;
+        let policy = keyring.policy();
+        self.check_policy(state, policy)?;
+        let password_key = if policy.needs_password() {
+            Some(MasterPassword::from_state(state)?)
+        } else {
+            None
+        };
+        let card_copy = if policy.needs_yubikey() {
+            Some(state.vault_key(keyring.id()).ok_or_else(|| {
+                Error::Locked(format!("Vault {} must be unlocked with the YubiKey", self.name))
+            })?)
+        } else {
+            None
+        };
+        Ok(keyring.open(password_key, card_copy)?)
+    }
+
+    /// Brings every vault to the current format once the master password is
+    /// verified: vaults without a data key are migrated, and keyrings written
+    /// before they were authenticated are wrapped again for their policy and
+    /// signed, in one transaction. Such keyrings always had a password copy
+    /// of the data key, one without it was not written by the app.
+    pub fn upgrade_all(state: &AppState) -> Result<()> {
+        let fs = state.file_system();
+        let password_key = MasterPassword::from_state(state)?;
+        let master_pub = Self::master_pub(state)?;
+        let yubikeys = YubiKeySettings::load(state)?;
+        let mut rewrapped = vec![];
+        for vault in Self::all(state)? {
+            vault.ensure_keyring(state)?;
+            let path = fs.vault_keyring(&vault.name);
+            let mut keyring = Keyring::load(&path)?;
+            if keyring.is_signed() {
+                keyring.verify(password_key)?;
+                continue;
+            }
+            let data_key = keyring.unlock_with_password(password_key)?;
+            let cards = vault.cards(&keyring, &yubikeys);
+            // Without a copy for an enrolled YubiKey the vault would not open anymore
+            let policy = match keyring.policy() {
+                policy if policy.needs_yubikey() && cards.is_empty() => UnlockPolicy::Password,
+                policy => policy,
+            };
+            keyring.apply_policy(&data_key, policy, password_key, &master_pub, &cards)?;
+            rewrapped.push((path, keyring.to_json(password_key)?));
+        }
+        if rewrapped.is_empty() {
+            return Ok(());
+        }
+        let mut transaction = Transaction::new(fs)?;
+        for (path, json) in rewrapped {
+            transaction.stage(&path, json)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn policy(&self, state: &AppState) -> Result<UnlockPolicy> {
+        let path = state.file_system().vault_keyring(&self.name);
+        if !path.exists() {
+            return Ok(UnlockPolicy::default());
+        }
+        Ok(Keyring::load(&path)?.policy())
+    }
+
+    /// Changes what unlocks the vault. The session must satisfy the current
+    /// policy, and a YubiKey must be enrolled and verified before it is
+    /// required. Every copy of the data key is wrapped again for the policy.
+    pub fn set_policy(&self, state: &mut AppState, policy: UnlockPolicy) -> Result<()> {
+        let data_key = self.data_key(state)?;
+        let mut keyring = self.keyring(state)?;
+        let yubikeys = YubiKeySettings::load(state)?;
+        let cards = self.cards(&keyring, &yubikeys);
+        if policy.needs_yubikey() {
+            if cards.is_empty() {
+                return Err(Error::Validation(format!("Vault {} has no key for an enrolled YubiKey", self.name)));
+            }
+            if !state.is_yubikey_authenticated() {
+                return Err(Error::Locked("Unlock with the YubiKey before requiring it".to_string()));
+            }
+        }
+        let password_key = MasterPassword::from_state(state)?;
+        let card_copy = keyring.apply_policy(&data_key, policy, password_key, &Self::master_pub(state)?, &cards)?;
+        self.save_keyring(state, &keyring)?;
+        if policy.needs_yubikey() {
+            state.add_vault_key(card_copy);
+        }
+        Ok(())
+    }
+
+    // Enrolled YubiKeys the keyring has a copy for
+    fn cards<'a>(&self, keyring: &Keyring, yubikeys: &'a YubiKeySettings) -> Vec<(u32, &'a str)> {
+        yubikeys
+            .public_keys()
+            .into_iter()
+            .filter(|(serial, _)| keyring.has_yubikey(*serial))
+            .collect()
+    }
+
+    fn check_policy(&self, state: &AppState, policy: UnlockPolicy) -> Result<()> {
+        if policy.needs_password() && !state.has_master_key() {
+            return Err(Error::Locked(format!("Vault {} needs the master password", self.name)));
+        }
+        if policy.needs_yubikey() && !state.is_yubikey_authenticated() {
+            return Err(Error::Locked(format!("Vault {} needs the YubiKey", self.name)));
+        }
+        Ok(())
+    }
+
+    /// Reads the keyring, checked with the master key when the session has it.
+    /// A session opened with the YubiKey alone can not check it, the copies
+    /// it holds are then all that decides what opens the vault.
+    pub fn keyring(&self, state: &AppState) -> Result<Keyring> {
+        self.ensure_keyring(state)?;
+        let keyring = Keyring::load(&state.file_system().vault_keyring(&self.name))?;
+        if let Some(master_key) = state.master_key() {
+            keyring.verify(master_key)?;
+        }
+        Ok(keyring)
+    }
+
+    pub fn save_keyring(&self, state: &AppState, keyring: &Keyring) -> Result<()> {
+        keyring.save(&state.file_system().vault_keyring(&self.name), MasterPassword::from_state(state)?)?;
+        Ok(())
+    }
+
+    /// Content of the keyring file, authenticated with the master key, for
+    /// keyrings saved as part of a transaction.
+    pub fn keyring_json(state: &AppState, keyring: &Keyring) -> Result<String> {
+        Ok(keyring.to_json(MasterPassword::from_state(state)?)?)
+    }
+
+    fn master_pub(state: &AppState) -> Result<PublicKey> {
+        Ok(PublicKey::from_pem(&fs::read_to_string(state.file_system().master_pub())?)?)
+    }
+
+    fn new_keyring(state: &AppState) -> Result<(Keyring, DataKey)> {
+        let password_key = MasterPassword::from_state(state)?;
+        Ok(Keyring::generate(password_key, &Self::master_pub(state)?)?)
+    }
+
+    fn create_keyring(&self, state: &AppState) -> Result<()> {
+        let (keyring, _) = Self::new_keyring(state)?;
+        self.save_keyring(state, &keyring)
+    }
+
+    // Vaults created before data keys hold secrets encrypted with the master password key,
+    // move them to a new data key in one transaction
+    fn migrate(&self, state: &AppState) -> Result<DataKey> {
+        let fs = state.file_system();
+        let password_key = MasterPassword::from_state(state)?;
+        let (keyring, data_key) = Self::new_keyring(state)?;
+        let cipher = data_key.cipher();
+        let mut transaction = Transaction::new(fs)?;
+        for entry in fs::read_dir(fs.vault_folder(&self.name))? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().map(|s| s == "enc").unwrap_or(false) {
+                let plain = password_key.decrypt(&fs::read_to_string(&path)?)?;
+                transaction.stage(&path, cipher.encrypt(&plain)?)?;
+            }
+        }
+        transaction.stage(&fs.vault_keyring(&self.name), keyring.to_json(password_key)?)?;
+        transaction.commit()?;
+        Ok(data_key)
+    }
+
+    // Names end up in a folder name, reject anything that could escape the vaults folder
+    fn validate_name(name: &str) -> Result<()> {
+        let valid = !name.is_empty()
+            && name.len() <= 64
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(Error::Validation(format!(
+                "Invalid vault name: {}. Use letters, numbers, '-' or '_'",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+
+    fn setup() -> AppState {
+        AppState::new_test("secret")
+    }
+
+    #[test]
+    fn test_create_and_list_vaults() {
+        let state = setup();
+        let vault = Vault::create(&state, "work").unwrap();
+        assert_eq!(vault.name(), "work");
+        assert!(state.file_system().vault_keyring("work").exists());
+
+        let names: Vec<String> = Vault::all(&state)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, vec![DEFAULT_VAULT.to_string(), "work".to_string()]);
+        assert!(matches!(Vault::create(&state, "work"), Err(Error::AlreadyExists(_))));
+    }
+
+    #[test]
+    fn test_invalid_vault_names() {
+        let state = setup();
+        assert!(matches!(Vault::create(&state, ""), Err(Error::Validation(_))));
+        assert!(matches!(Vault::create(&state, "../escape"), Err(Error::Validation(_))));
+        assert!(matches!(Vault::find(&state, "a/b"), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_rename_vault() {
+        let state = setup();
+        Vault::create(&state, "work").unwrap();
+        let renamed = Vault::rename(&state, "work", "personal").unwrap();
+        assert_eq!(renamed.name(), "personal");
+        assert!(matches!(Vault::find(&state, "work"), Err(Error::NotFound(_))));
+        assert!(Vault::find(&state, "personal").is_ok());
+        assert!(matches!(
+            Vault::rename(&state, DEFAULT_VAULT, "other"),
+            Err(Error::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn test_delete_vault() {
+        let state = setup();
+        Vault::create(&state, "work").unwrap();
+        Vault::delete(&state, "work").unwrap();
+        assert!(!state.file_system().vault_folder("work").exists());
+        assert!(matches!(Vault::delete(&state, "work"), Err(Error::NotFound(_))));
+        assert!(matches!(Vault::delete(&state, DEFAULT_VAULT), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_locked_vaults_are_kept() {
+        let mut state = setup();
+        Vault::create(&state, "work").unwrap();
+        state.log_out();
+        assert!(matches!(Vault::rename(&state, "work", "personal"), Err(Error::Locked(_))));
+        assert!(matches!(Vault::delete(&state, "work"), Err(Error::Locked(_))));
+        assert!(state.file_system().vault_folder("work").exists());
+        assert!(!state.file_system().vault_folder("personal").exists());
+    }
+
+    #[test]
+    fn test_migrates_secrets_to_data_key() {
+        let state = setup();
+        let fs = state.file_system();
+        let password_key = MasterPassword::from_state(&state).unwrap();
+        let path = fs.secret_path(DEFAULT_VAULT, "old");
+        fs::write(&path, password_key.encrypt(b"old secret").unwrap()).unwrap();
+
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        let data_key = vault.data_key(&state).unwrap();
+        assert!(fs.vault_keyring(DEFAULT_VAULT).exists());
+        let encrypted = fs::read_to_string(&path).unwrap();
+        assert!(password_key.decrypt(&encrypted).is_err());
+        assert_eq!(data_key.cipher().decrypt(&encrypted).unwrap(), b"old secret");
+        assert_eq!(vault.data_key(&state).unwrap().id(), data_key.id());
+    }
+
+    #[test]
+    fn test_upgrade_rewraps_legacy_copies_once() {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+        use rsa::pkcs8::DecodePublicKey;
+
+        let state = setup();
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        vault.ensure_keyring(&state).unwrap();
+        let path = state.file_system().vault_keyring(DEFAULT_VAULT);
+        // Copies written before OAEP are the data key encrypted with PKCS#1 v1.5
+        let master = crate::encrypt::RsaKeyPair::try_from(&state).unwrap();
+        let mut json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let copy = json["wrapped"]
+            .as_array_mut()
+            .unwrap()
+            .iter_mut()
+            .find(|wrapped| wrapped["method"] == "rsa_master")
+            .unwrap();
+        let key = master.decrypt(&BASE64.decode(copy["key"].as_str().unwrap()).unwrap()).unwrap();
+        let public = rsa::RsaPublicKey::from_public_key_pem(&master.public_key_pem().unwrap()).unwrap();
+        let legacy = public.encrypt(&mut rand::rngs::OsRng, rsa::Pkcs1v15Encrypt, &key).unwrap();
+        copy["key"] = BASE64.encode(legacy).into();
+        let legacy = copy["key"].clone();
+        // Older keyrings were written without a MAC
+        json.as_object_mut().unwrap().remove("mac");
+        let legacy_json = serde_json::to_string_pretty(&json).unwrap();
+        fs::write(&path, &legacy_json).unwrap();
+
+        // Until it is signed the keyring is not trusted
+        assert!(matches!(vault.data_key(&state), Err(Error::EncryptMod(_))));
+        Vault::upgrade_all(&state).unwrap();
+        let upgraded = fs::read_to_string(&path).unwrap();
+        assert_ne!(upgraded, legacy_json);
+        let json: serde_json::Value = serde_json::from_str(&upgraded).unwrap();
+        let copy = json["wrapped"].as_array().unwrap().iter().find(|wrapped| wrapped["method"] == "rsa_master").unwrap();
+        assert_ne!(copy["key"], legacy);
+        assert_eq!(master.decrypt(&BASE64.decode(copy["key"].as_str().unwrap()).unwrap()).unwrap(), key);
+        vault.data_key(&state).unwrap();
+        Vault::upgrade_all(&state).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), upgraded);
+    }
+
+    #[test]
+    fn test_keyring_changed_on_disk_is_rejected() {
+        let state = setup();
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        vault.data_key(&state).unwrap();
+        let path = state.file_system().vault_keyring(DEFAULT_VAULT);
+        let json = fs::read_to_string(&path).unwrap();
+        fs::write(&path, json.replace("\"policy\": \"password\"", "\"policy\": \"yubikey\"")).unwrap();
+        assert!(matches!(vault.data_key(&state), Err(Error::EncryptMod(_))));
+        assert!(Vault::upgrade_all(&state).is_err());
+    }
+
+    #[test]
+    fn test_settings_errors_are_not_ignored() {
+        let mut state = setup();
+        enroll_yubikey(&state);
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        fs::write(state.file_system().yubikey_settings(), "{}").unwrap();
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        assert!(matches!(vault.set_policy(&mut state, UnlockPolicy::Yubikey), Err(Error::YubiKey(_))));
+        assert!(matches!(Vault::upgrade_all(&state), Err(Error::YubiKey(_))));
+    }
+
+    fn enroll_yubikey(state: &AppState) {
+        let info = crate::yubikey::list_yubikeys(state.piv()).unwrap().remove(0);
+        crate::yubikey::enroll(state, info.serial.unwrap(), None, info.pub_key.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_yubikey_only_policy() {
+        let mut state = setup();
+        let vault = Vault::create(&state, "work").unwrap();
+        enroll_yubikey(&state);
+        let key_id = vault.data_key(&state).unwrap().id().to_string();
+        // The YubiKey must be verified before it is required
+        assert!(matches!(vault.set_policy(&mut state, UnlockPolicy::Yubikey), Err(Error::Locked(_))));
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        vault.set_policy(&mut state, UnlockPolicy::Yubikey).unwrap();
+        assert_eq!(vault.policy(&state).unwrap(), UnlockPolicy::Yubikey);
+
+        state.log_out();
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        assert!(state.is_authenticated());
+        assert_eq!(vault.data_key(&state).unwrap().id(), key_id);
+        // Without the password only the vaults the YubiKey opens alone are available
+        let default = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        assert!(matches!(default.data_key(&state), Err(Error::Locked(_))));
+
+        state.log_out();
+        MasterPassword::verify(&mut state, "secret").unwrap();
+        assert!(matches!(vault.data_key(&state), Err(Error::Locked(_))));
+        assert!(default.data_key(&state).is_ok());
+    }
+
+    #[test]
+    fn test_password_and_yubikey_policy() {
+        let mut state = setup();
+        enroll_yubikey(&state);
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        vault.set_policy(&mut state, UnlockPolicy::PasswordAndYubikey).unwrap();
+
+        state.log_out();
+        MasterPassword::verify(&mut state, "secret").unwrap();
+        assert!(matches!(vault.data_key(&state), Err(Error::Locked(_))));
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        assert!(vault.data_key(&state).is_ok());
+
+        state.log_out();
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        assert!(matches!(vault.data_key(&state), Err(Error::Locked(_))));
+    }
+
+    #[test]
+    fn test_policy_needs_enrolled_yubikey() {
+        let mut state = setup();
+        // The test state saves YubiKey settings without wrapping any vault key for it
+        assert!(crate::yubikey::unlock(&mut state, "123456", None).is_err());
+        let vault = Vault::find(&state, DEFAULT_VAULT).unwrap();
+        assert!(matches!(vault.set_policy(&mut state, UnlockPolicy::Yubikey), Err(Error::Validation(_))));
+        assert_eq!(vault.policy(&state).unwrap(), UnlockPolicy::Password);
+    }
+
+    #[test]
+    fn test_last_yubikey_of_yubikey_vault_stays() {
+        let mut state = setup();
+        let vault = Vault::create(&state, "work").unwrap();
+        enroll_yubikey(&state);
+        crate::yubikey::unlock(&mut state, "123456", None).unwrap();
+        vault.set_policy(&mut state, UnlockPolicy::Yubikey).unwrap();
+
+        let serial = AppState::TEST_YUBIKEY_SERIAL;
+        assert!(crate::yubikey::remove_enrolled(&state, serial).is_err());
+        assert!(vault.keyring(&state).unwrap().has_yubikey(serial));
+        vault.set_policy(&mut state, UnlockPolicy::Password).unwrap();
+        crate::yubikey::remove_enrolled(&state, serial).unwrap();
+        assert!(!vault.keyring(&state).unwrap().has_yubikey(serial));
+    }
+}
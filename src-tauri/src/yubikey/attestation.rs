@@ -0,0 +1,388 @@
+// PIV attestation: the card signs a certificate for a key it generated with its
+// attestation key (slot F9), whose certificate Yubico issued at the factory.
+// Imported keys can not be attested, so a chain up to a Yubico root proves the
+// key never left the card.
+use super::PivCard;
+use crate::error::{Error, Result};
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384};
+use x509_cert::der::{oid::ObjectIdentifier, Decode, DecodePem, Encode};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+use x509_cert::Certificate;
+use yubikey::piv::SlotId;
+
+// Yubico extensions of attestation certificates
+pub(super) const OID_FIRMWARE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.3");
+pub(super) const OID_SERIAL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.7");
+pub(super) const OID_POLICY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.8");
+
+const SHA256_WITH_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+pub(super) const ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+const BUNDLED_ROOTS: &str = include_str!("../../resources/yubico-piv-roots.pem");
+// SHA-256 of the DER of every root allowed in `BUNDLED_ROOTS`, as published by
+// Yubico. A certificate in the file without its fingerprint here is refused.
+const BUNDLED_ROOT_FINGERPRINTS: &[&str] = &[];
+
+/// When the card asks for the PIN before using the key.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PinPolicy {
+    Default,
+    Never,
+    Once,
+    Always,
+    MatchOnce,
+    MatchAlways,
+}
+
+impl PinPolicy {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(PinPolicy::Default),
+            1 => Some(PinPolicy::Never),
+            2 => Some(PinPolicy::Once),
+            3 => Some(PinPolicy::Always),
+            4 => Some(PinPolicy::MatchOnce),
+            5 => Some(PinPolicy::MatchAlways),
+            _ => None,
+        }
+    }
+}
+
+/// When the card asks for a touch before using the key.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TouchPolicy {
+    Default,
+    Never,
+    Always,
+    Cached,
+}
+
+impl TouchPolicy {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TouchPolicy::Default),
+            1 => Some(TouchPolicy::Never),
+            2 => Some(TouchPolicy::Always),
+            3 => Some(TouchPolicy::Cached),
+            _ => None,
+        }
+    }
+}
+
+/// What a verified attestation says about a key. Having one means the key was
+/// generated on the card.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Attestation {
+    pub pin_policy: Option<PinPolicy>,
+    pub touch_policy: Option<TouchPolicy>,
+    /// Serial and firmware of the card, as signed by it.
+    pub serial: Option<u32>,
+    pub firmware: Option<String>,
+    /// Only asserted by a PKCS#11 token through the key's attributes, nothing
+    /// signs it.
+    #[serde(default)]
+    pub unsigned: bool,
+}
+
+impl Attestation {
+    fn from_certificate(certificate: &Certificate) -> Self {
+        let extension = |oid| {
+            certificate
+                .tbs_certificate
+                .extensions
+                .iter()
+                .flatten()
+                .find(|extension| extension.extn_id == oid)
+                .map(|extension| extension.extn_value.as_bytes())
+        };
+        let policy = extension(OID_POLICY);
+        Self {
+            pin_policy: policy.and_then(|policy| PinPolicy::from_byte(*policy.first()?)),
+            touch_policy: policy.and_then(|policy| TouchPolicy::from_byte(*policy.get(1)?)),
+            serial: extension(OID_SERIAL).and_then(|serial| u32::from_der(serial).ok()),
+            firmware: extension(OID_FIRMWARE)
+                .filter(|version| version.len() == 3)
+                .map(|version| format!("{}.{}.{}", version[0], version[1], version[2])),
+            unsigned: false,
+        }
+    }
+}
+
+/// Root certificates an attestation chain must end at.
+#[derive(Debug, Clone, Default)]
+pub struct TrustAnchors {
+    roots: Vec<Certificate>,
+}
+
+impl TrustAnchors {
+    /// The Yubico roots shipped with the app, in `resources/yubico-piv-roots.pem`.
+    pub fn bundled() -> Result<Self> {
+        Self::pinned(BUNDLED_ROOTS, BUNDLED_ROOT_FINGERPRINTS)
+    }
+
+    /// `from_pem`, failing when a root's SHA-256 fingerprint is not one of `fingerprints`.
+    pub fn pinned(pem: &str, fingerprints: &[&str]) -> Result<Self> {
+        let anchors = Self::from_pem(pem)?;
+        for root in &anchors.roots {
+            let fingerprint = fingerprint(root)?;
+            if !fingerprints.iter().any(|pin| pin.eq_ignore_ascii_case(&fingerprint)) {
+                return Err(Error::Attestation(format!("Root certificate {} is not pinned", fingerprint)));
+            }
+        }
+        Ok(anchors)
+    }
+
+    /// Reads every `CERTIFICATE` block of `pem`, text around them is ignored.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+        const END: &str = "-----END CERTIFICATE-----";
+        let mut roots = vec![];
+        let mut rest = pem;
+        while let Some(start) = rest.find(BEGIN) {
+            let block = &rest[start..];
+            let end = block
+                .find(END)
+                .ok_or(Error::Attestation("Unterminated root certificate".to_string()))?
+                + END.len();
+            roots.push(Certificate::from_pem(&block[..end]).map_err(invalid)?);
+            rest = &block[end..];
+        }
+        Ok(Self { roots })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+}
+
+/// Asks the card to attest the key in `slot` and verifies the statement, which
+/// must name the card's serial. Cards without attestation are taken at their word, see `PivCard::resident_key`.
+pub fn attest(card: &mut dyn PivCard, slot: SlotId, roots: &TrustAnchors, public_key: &str) -> Result<Attestation> {
+    match card.resident_key(slot)? {
+        Some(true) if card.read_certificate(slot)? == public_key => {
+            return Ok(Attestation {
+                pin_policy: None,
+                touch_policy: None,
+                serial: Some(card.serial()),
+                firmware: Some(card.version()),
+                unsigned: true,
+            });
+        }
+        Some(true) => return Err(Error::Attestation("The attestation is for another key".to_string())),
+        Some(false) => {
+            return Err(Error::Attestation(format!(
+                "The token reports the key in slot {:?} as imported or extractable",
+                slot
+            )));
+        }
+        None => {}
+    }
+    let statement = card.attest(slot).map_err(|e| {
+        Error::Attestation(format!(
+            "The key in slot {:?} can not be attested, it was not generated on the YubiKey: {}",
+            slot, e
+        ))
+    })?;
+    let attestation = verify(&statement, &card.attestation_certificate()?, roots, public_key)?;
+    for_card(attestation, card.serial())
+}
+
+// A card could pass off the statement of another YubiKey as its own
+fn for_card(attestation: Attestation, serial: u32) -> Result<Attestation> {
+    if attestation.serial != Some(serial) {
+        return Err(Error::Attestation(format!(
+            "The attestation is for YubiKey {}, not {}",
+            attestation.serial.map_or_else(|| "without serial".to_string(), |serial| serial.to_string()),
+            serial
+        )));
+    }
+    Ok(attestation)
+}
+
+/// Checks that the DER `statement` attests `public_key` (PEM), that it was signed
+/// by the card's attestation key `intermediate`, and that a root in `roots`
+/// issued that key. Validity dates are not checked: attestation certificates
+/// outlive their issuers by design.
+pub fn verify(statement: &[u8], intermediate: &[u8], roots: &TrustAnchors, public_key: &str) -> Result<Attestation> {
+    if roots.is_empty() {
+        return Err(Error::Attestation(
+            "No Yubico attestation root is bundled, keys can not be attested".to_string(),
+        ));
+    }
+    let statement = Certificate::from_der(statement).map_err(invalid)?;
+    let intermediate = Certificate::from_der(intermediate).map_err(invalid)?;
+    verify_signature(&statement, &intermediate)?;
+    if !roots.roots.iter().any(|root| verify_signature(&intermediate, root).is_ok()) {
+        return Err(Error::Attestation(
+            "The attestation key of the YubiKey was not issued by a trusted root".to_string(),
+        ));
+    }
+    let expected = SubjectPublicKeyInfoOwned::from_pem(public_key).map_err(invalid)?;
+    if statement.tbs_certificate.subject_public_key_info.to_der().map_err(invalid)? != expected.to_der().map_err(invalid)? {
+        return Err(Error::Attestation("The attestation is for another key".to_string()));
+    }
+    Ok(Attestation::from_certificate(&statement))
+}
+
+// Checks the signature `issuer` made over `certificate`
+fn verify_signature(certificate: &Certificate, issuer: &Certificate) -> Result<()> {
+    let bad_signature = || Error::Attestation("Invalid attestation signature".to_string());
+    if certificate.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return Err(Error::Attestation("The attestation chain is broken".to_string()));
+    }
+    let tbs = certificate.tbs_certificate.to_der().map_err(invalid)?;
+    let signature = certificate.signature.as_bytes().ok_or_else(bad_signature)?;
+    let key = issuer.tbs_certificate.subject_public_key_info.to_der().map_err(invalid)?;
+    let algorithm = certificate.signature_algorithm.oid;
+    if algorithm == SHA256_WITH_RSA {
+        let key = RsaPublicKey::from_public_key_der(&key).map_err(|_| bad_signature())?;
+        return key
+            .verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&tbs), signature)
+            .map_err(|_| bad_signature());
+    }
+    let digest = if algorithm == ECDSA_WITH_SHA256 {
+        Sha256::digest(&tbs).to_vec()
+    } else if algorithm == ECDSA_WITH_SHA384 {
+        Sha384::digest(&tbs).to_vec()
+    } else {
+        return Err(Error::Attestation(format!("Unsupported signature algorithm {}", algorithm)));
+    };
+    let verified = if let Ok(key) = p256::ecdsa::VerifyingKey::from_public_key_der(&key) {
+        p256::ecdsa::Signature::from_der(signature).is_ok_and(|signature| key.verify_prehash(&digest, &signature).is_ok())
+    } else if let Ok(key) = p384::ecdsa::VerifyingKey::from_public_key_der(&key) {
+        p384::ecdsa::Signature::from_der(signature).is_ok_and(|signature| key.verify_prehash(&digest, &signature).is_ok())
+    } else {
+        false
+    };
+    verified.then_some(()).ok_or_else(bad_signature)
+}
+
+fn fingerprint(certificate: &Certificate) -> Result<String> {
+    let der = certificate.to_der().map_err(invalid)?;
+    Ok(Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn invalid(e: impl std::fmt::Display) -> Error {
+    Error::Attestation(format!("Invalid attestation certificate: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yubikey::virtual_card::{ATTESTATION_ROOT, DEFAULT_MANAGEMENT_KEY, DEFAULT_PIN};
+    use crate::yubikey::{PivBackend, VirtualBackend, VirtualCard};
+    use yubikey::piv::AlgorithmId;
+
+    const SERIAL: u32 = 40_000_005;
+
+    fn card(backend: &VirtualBackend) -> Box<dyn PivCard> {
+        backend.open(SERIAL).unwrap()
+    }
+
+    fn backend() -> VirtualBackend {
+        let backend = VirtualBackend::default();
+        backend.insert(VirtualCard::from_fixtures(SERIAL, "tests/fixtures/piv").unwrap().with_version(5, 4, 3));
+        backend
+    }
+
+    #[test]
+    fn test_verify_fixture_chain() {
+        let backend = backend();
+        let mut card = card(&backend);
+        let public_key = card.read_certificate(SlotId::KeyManagement).unwrap();
+        let roots = TrustAnchors::from_pem(ATTESTATION_ROOT).unwrap();
+
+        let attestation = attest(card.as_mut(), SlotId::KeyManagement, &roots, &public_key).unwrap();
+        assert_eq!(attestation.pin_policy, Some(PinPolicy::Once));
+        assert_eq!(attestation.touch_policy, Some(TouchPolicy::Never));
+        assert_eq!(attestation.serial, Some(SERIAL));
+        assert_eq!(attestation.firmware.as_deref(), Some("5.4.3"));
+
+        // The statement is for the slot's key only
+        let other = card.read_certificate(SlotId::Authentication).unwrap();
+        let result = attest(card.as_mut(), SlotId::KeyManagement, &roots, &other);
+        assert!(matches!(result, Err(Error::Attestation(msg)) if msg.contains("another key")));
+    }
+
+    #[test]
+    fn test_attestation_names_the_card() {
+        let backend = backend();
+        let mut card = card(&backend);
+        let public_key = card.read_certificate(SlotId::KeyManagement).unwrap();
+        let statement = card.attest(SlotId::KeyManagement).unwrap();
+        let intermediate = card.attestation_certificate().unwrap();
+        let roots = TrustAnchors::from_pem(ATTESTATION_ROOT).unwrap();
+        let attestation = verify(&statement, &intermediate, &roots, &public_key).unwrap();
+        assert_eq!(for_card(attestation.clone(), SERIAL).unwrap(), attestation);
+        assert!(matches!(for_card(attestation.clone(), SERIAL + 1), Err(Error::Attestation(msg)) if msg.contains("not")));
+        let anonymous = Attestation { serial: None, ..attestation };
+        assert!(for_card(anonymous, SERIAL).is_err());
+    }
+
+    #[test]
+    fn test_untrusted_root() {
+        let backend = backend();
+        let mut card = card(&backend);
+        let public_key = card.read_certificate(SlotId::KeyManagement).unwrap();
+        let statement = card.attest(SlotId::KeyManagement).unwrap();
+        let intermediate = card.attestation_certificate().unwrap();
+
+        // Same name as the fixture root, another key
+        let untrusted = std::fs::read_to_string("tests/fixtures/attestation/untrusted-root.pem").unwrap();
+        let result = verify(&statement, &intermediate, &TrustAnchors::from_pem(&untrusted).unwrap(), &public_key);
+        assert!(matches!(result, Err(Error::Attestation(msg)) if msg.contains("trusted root")));
+        assert!(verify(&statement, &intermediate, &TrustAnchors::default(), &public_key).is_err());
+
+        // A statement signed by another key than the intermediate
+        let mut tampered = statement.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let roots = TrustAnchors::from_pem(ATTESTATION_ROOT).unwrap();
+        assert!(verify(&tampered, &intermediate, &roots, &public_key).is_err());
+        verify(&statement, &intermediate, &roots, &public_key).unwrap();
+    }
+
+    #[test]
+    fn test_imported_keys_are_not_attested() {
+        let pem = std::fs::read_to_string("tests/fixtures/piv/9d.pem").unwrap();
+        let backend = VirtualBackend::default();
+        backend.insert(VirtualCard::new(SERIAL).with_imported_key(SlotId::KeyManagement, &pem).unwrap());
+        let mut card = card(&backend);
+        let public_key = card.read_certificate(SlotId::KeyManagement).unwrap();
+        let roots = TrustAnchors::from_pem(ATTESTATION_ROOT).unwrap();
+        assert!(matches!(
+            attest(card.as_mut(), SlotId::KeyManagement, &roots, &public_key),
+            Err(Error::Attestation(_))
+        ));
+
+        // A key generated on the card in its place can be
+        card.authenticate_management(&DEFAULT_MANAGEMENT_KEY).unwrap();
+        card.verify_pin(DEFAULT_PIN.as_bytes()).unwrap();
+        let public_key = card.generate(SlotId::KeyManagement, AlgorithmId::EccP384).unwrap();
+        attest(card.as_mut(), SlotId::KeyManagement, &roots, &public_key).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs Yubico's PIV root in resources/yubico-piv-roots.pem and its fingerprint pinned"]
+    fn test_bundled_roots_parse() {
+        assert!(!TrustAnchors::bundled().unwrap().is_empty());
+        let roots = TrustAnchors::from_pem(&format!("comment\n{}\n{}", ATTESTATION_ROOT, ATTESTATION_ROOT)).unwrap();
+        assert_eq!(roots.roots.len(), 2);
+    }
+
+    #[test]
+    fn test_unpinned_roots_are_refused() {
+        let roots = TrustAnchors::from_pem(ATTESTATION_ROOT).unwrap();
+        let pin = fingerprint(&roots.roots[0]).unwrap();
+        assert_eq!(TrustAnchors::pinned(ATTESTATION_ROOT, &[&pin.to_uppercase()]).unwrap().roots.len(), 1);
+        assert!(matches!(TrustAnchors::pinned(ATTESTATION_ROOT, &[]), Err(Error::Attestation(_))));
+        TrustAnchors::pinned(BUNDLED_ROOTS, BUNDLED_ROOT_FINGERPRINTS).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use super::attestation::TrustAnchors;
+#[cfg(not(target_os = "macos"))]
+use super::pkcs11::Pkcs11Backend;
+use crate::error::{Error, Result};
+use rsa::pkcs1::der::{pem::LineEnding, DecodePem, Encode, EncodePem};
+use std::str::FromStr;
+use std::time::Duration;
+use x509_cert::{name::Name, serial_number::SerialNumber, spki::SubjectPublicKeyInfoOwned, time::Validity};
+use yubikey::certificate::yubikey_signer::{Rsa1024, Rsa2048, YubiRsa};
+use yubikey::certificate::Certificate;
+use yubikey::{piv, MgmKey, PinPolicy, TouchPolicy, YubiKey};
+use yubikey::piv::{AlgorithmId, SlotId};
+
+// Self-signed certificates only carry the public key, their dates are not checked
+const CERTIFICATE_VALIDITY: Duration = Duration::from_secs(20 * 365 * 24 * 60 * 60);
+
+/// The card operations the app relies on, implemented by YubiKeys and the
+/// virtual card used in tests.
+pub trait PivCard: Send {
+    fn serial(&self) -> u32;
+
+    fn name(&self) -> String;
+
+    fn version(&self) -> String;
+
+    /// Algorithm of the key in `slot`, `None` when the slot holds no key.
+    fn metadata(&mut self, slot: SlotId) -> Result<Option<AlgorithmId>>;
+
+    /// Public key of the certificate stored for `slot`, PEM encoded.
+    fn read_certificate(&mut self, slot: SlotId) -> Result<String>;
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()>;
+
+    /// Raw RSA decryption of one block, or ECDH with an uncompressed point on ECC keys.
+    fn decrypt(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>>;
+
+    /// Raw RSA signature of a padded block, or ECDSA signature of a digest on ECC keys.
+    fn sign(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>>;
+
+    /// Unlocks the administrative operations below with the 24 byte management key.
+    fn authenticate_management(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Generates a key on the card in `slot` and returns its public key, PEM encoded.
+    fn generate(&mut self, slot: SlotId, algorithm: AlgorithmId) -> Result<String>;
+
+    /// Stores a certificate for the key in `slot`, signed by that key. Needs the
+    /// management key and the PIN.
+    fn write_self_signed_certificate(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        public_key: &str,
+        subject: &str,
+    ) -> Result<()>;
+
+    /// PIN tries left before the PIN blocks.
+    fn pin_retries(&mut self) -> Result<u8>;
+
+    /// PUK tries left, None when the card can not tell (before firmware 5.3).
+    fn puk_retries(&mut self) -> Result<Option<u8>>;
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()>;
+
+    /// Sets a new PIN with the PUK, also when the PIN is blocked.
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()>;
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()>;
+
+    /// Replaces the management key, needs the current one.
+    fn set_management_key(&mut self, new_key: &[u8]) -> Result<()>;
+
+    /// True when the card can move keys between slots (firmware 5.7 and later).
+    fn can_move_keys(&self) -> bool;
+
+    /// Moves the key and certificate in `from` to `to`, needs the management key.
+    fn move_key(&mut self, from: SlotId, to: SlotId) -> Result<()>;
+
+    /// Attestation certificate for the key in `slot`, DER encoded. Fails for
+    /// keys that were imported instead of generated on the card.
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>>;
+
+    /// Certificate of the card's attestation key (slot F9), DER encoded.
+    fn attestation_certificate(&mut self) -> Result<Vec<u8>>;
+
+    /// For cards without attestation: whether the card reports the key in
+    /// `slot` as generated on it and never extractable. None on cards that
+    /// attest their keys.
+    fn resident_key(&mut self, slot: SlotId) -> Result<Option<bool>>;
+}
+
+/// Finds the connected cards.
+pub trait PivBackend: Send + Sync {
+    fn list(&self) -> Result<Vec<Box<dyn PivCard>>>;
+
+    fn open(&self, serial: u32) -> Result<Box<dyn PivCard>>;
+
+    /// Roots the attestation keys of these cards were issued by.
+    fn attestation_roots(&self) -> Result<TrustAnchors>;
+}
+
+/// YubiKeys connected through PC/SC.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Hardware;
+
+/// Every card the app can use: connected YubiKeys and, where PKCS#11 is
+/// available, the tokens of the modules listed in `PKCS11_MODULE_PATH`.
+#[derive(Clone)]
+pub struct Devices {
+    #[cfg(not(target_os = "macos"))]
+    tokens: Vec<Pkcs11Backend>,
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Self {
+            #[cfg(not(target_os = "macos"))]
+            tokens: Pkcs11Backend::from_env(),
+        }
+    }
+}
+
+impl PivBackend for Devices {
+    fn list(&self) -> Result<Vec<Box<dyn PivCard>>> {
+        #[cfg(not(target_os = "macos"))]
+        if !self.tokens.is_empty() {
+            // Tokens do not need the PC/SC service
+            let mut cards = Hardware.list().unwrap_or_default();
+            for tokens in &self.tokens {
+                cards.extend(tokens.list()?);
+            }
+            return Ok(cards);
+        }
+        Hardware.list()
+    }
+
+    fn open(&self, serial: u32) -> Result<Box<dyn PivCard>> {
+        #[cfg(not(target_os = "macos"))]
+        for tokens in &self.tokens {
+            if let Ok(card) = tokens.open(serial) {
+                return Ok(card);
+            }
+        }
+        Hardware.open(serial)
+    }
+
+    fn attestation_roots(&self) -> Result<TrustAnchors> {
+        Hardware.attestation_roots()
+    }
+}
+
+impl PivBackend for Hardware {
+    fn list(&self) -> Result<Vec<Box<dyn PivCard>>> {
+        let mut context = yubikey::reader::Context::open()
+            .map_err(|e| Error::YubiKeyError(format!("Failed to open YubiKey context: {}", e)))?;
+        let readers = context
+            .iter()
+            .map_err(|e| Error::YubiKeyError(format!("Failed to iterate readers: {}", e)))?;
+        Ok(readers
+            .filter_map(|reader| reader.open().ok())
+            .map(|yk| Box::new(yk) as Box<dyn PivCard>)
+            .collect())
+    }
+
+    fn open(&self, serial: u32) -> Result<Box<dyn PivCard>> {
+        let yk = YubiKey::open_by_serial(yubikey::Serial::from(serial))
+            .map_err(|e| Error::YubiKeyError(format!("Failed to open YubiKey {}: {}", serial, e)))?;
+        Ok(Box::new(yk))
+    }
+
+    fn attestation_roots(&self) -> Result<TrustAnchors> {
+        TrustAnchors::bundled()
+    }
+}
+
+impl PivCard for YubiKey {
+    fn serial(&self) -> u32 {
+        u32::from(YubiKey::serial(self))
+    }
+
+    fn name(&self) -> String {
+        format!("YubiKey {}", PivCard::serial(self))
+    }
+
+    fn version(&self) -> String {
+        YubiKey::version(self).to_string()
+    }
+
+    fn metadata(&mut self, slot: SlotId) -> Result<Option<AlgorithmId>> {
+        Ok(match piv::metadata(self, slot)?.algorithm {
+            piv::ManagementAlgorithmId::Asymmetric(algorithm) => Some(algorithm),
+            _ => None,
+        })
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<String> {
+        let cert = yubikey::certificate::Certificate::read(self, slot)
+            .map_err(|e| Error::YubiKeyError(format!("Failed to get certificate from slot {:?}: {}", slot, e)))?;
+        cert.subject_pki()
+            .to_pem(LineEnding::LF)
+            .map_err(|e| Error::YubiKeyError(format!("Failed to encode public key to PEM: {}", e)))
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
+        Ok(YubiKey::verify_pin(self, pin)?)
+    }
+
+    fn decrypt(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>> {
+        Ok(piv::decrypt_data(self, input, algorithm, slot)?.to_vec())
+    }
+
+    fn sign(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>> {
+        Ok(piv::sign_data(self, input, algorithm, slot)?.to_vec())
+    }
+
+    fn authenticate_management(&mut self, key: &[u8]) -> Result<()> {
+        Ok(self.authenticate(MgmKey::from_bytes(key)?)?)
+    }
+
+    fn generate(&mut self, slot: SlotId, algorithm: AlgorithmId) -> Result<String> {
+        piv::generate(self, slot, algorithm, PinPolicy::Default, TouchPolicy::Default)?
+            .to_pem(LineEnding::LF)
+            .map_err(|e| Error::YubiKeyError(format!("Failed to encode public key to PEM: {}", e)))
+    }
+
+    fn write_self_signed_certificate(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        public_key: &str,
+        subject: &str,
+    ) -> Result<()> {
+        let invalid = |e: String| Error::YubiKeyError(format!("Failed to build certificate: {}", e));
+        let public_key = SubjectPublicKeyInfoOwned::from_pem(public_key).map_err(|e| invalid(e.to_string()))?;
+        let subject = Name::from_str(&format!("CN={}", subject)).map_err(|e| invalid(e.to_string()))?;
+        // Positive 64 bit serial number, DER integers are signed
+        let mut serial = rand::random::<[u8; 8]>();
+        serial[0] &= 0x7f;
+        let serial = SerialNumber::new(&serial).map_err(|e| invalid(e.to_string()))?;
+        let validity = Validity::from_now(CERTIFICATE_VALIDITY).map_err(|e| invalid(e.to_string()))?;
+        match algorithm {
+            AlgorithmId::Rsa1024 => Certificate::generate_self_signed::<_, YubiRsa<Rsa1024>>(
+                self, slot, serial, validity, subject, public_key, |_| Ok(()),
+            ),
+            AlgorithmId::Rsa2048 => Certificate::generate_self_signed::<_, YubiRsa<Rsa2048>>(
+                self, slot, serial, validity, subject, public_key, |_| Ok(()),
+            ),
+            AlgorithmId::EccP256 => Certificate::generate_self_signed::<_, p256::NistP256>(
+                self, slot, serial, validity, subject, public_key, |_| Ok(()),
+            ),
+            AlgorithmId::EccP384 => Certificate::generate_self_signed::<_, p384::NistP384>(
+                self, slot, serial, validity, subject, public_key, |_| Ok(()),
+            ),
+        }?;
+        Ok(())
+    }
+
+    fn pin_retries(&mut self) -> Result<u8> {
+        Ok(self.get_pin_retries()?)
+    }
+
+    fn puk_retries(&mut self) -> Result<Option<u8>> {
+        let puk = SlotId::Management(piv::ManagementSlotId::Puk);
+        Ok(piv::metadata(self, puk).ok().and_then(|metadata| metadata.retries).map(|retries| retries.remaining_count))
+    }
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()> {
+        Ok(YubiKey::change_pin(self, current_pin, new_pin)?)
+    }
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()> {
+        Ok(YubiKey::unblock_pin(self, puk, new_pin)?)
+    }
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()> {
+        Ok(YubiKey::change_puk(self, current_puk, new_puk)?)
+    }
+
+    fn set_management_key(&mut self, new_key: &[u8]) -> Result<()> {
+        Ok(MgmKey::from_bytes(new_key)?.set_manual(self, false)?)
+    }
+
+    // The yubikey crate has no MOVE KEY command, rotation keeps old keys in place
+    fn can_move_keys(&self) -> bool {
+        false
+    }
+
+    fn move_key(&mut self, _from: SlotId, _to: SlotId) -> Result<()> {
+        Err(yubikey::Error::NotSupported.into())
+    }
+
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        Ok(piv::attest(self, slot)?.to_vec())
+    }
+
+    fn attestation_certificate(&mut self) -> Result<Vec<u8>> {
+        let cert = Certificate::read(self, SlotId::Attestation).map_err(|e| {
+            Error::YubiKeyError(format!("Failed to get the attestation certificate: {}", e))
+        })?;
+        cert.cert
+            .to_der()
+            .map_err(|e| Error::YubiKeyError(format!("Failed to encode the attestation certificate: {}", e)))
+    }
+
+    fn resident_key(&mut self, _slot: SlotId) -> Result<Option<bool>> {
+        Ok(None)
+    }
+}
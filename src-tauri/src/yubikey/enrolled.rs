@@ -0,0 +1,492 @@
+use super::{Attestation, YubiKeyDevice};
+use crate::encrypt::{self, ecc::EccAlgorithm, mac, Encrypt, AES};
+use crate::error::{Error, Result};
+use crate::{AppState, MasterPassword};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use yubikey::piv::{AlgorithmId, SlotId};
+use zeroize::Zeroizing;
+
+/// Algorithm of an enrolled key, `AlgorithmId` does not serialize.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAlgorithm {
+    Rsa1024,
+    Rsa2048,
+    EccP256,
+    EccP384,
+}
+
+impl KeyAlgorithm {
+    /// Reads the algorithm of an RSA or ECC `PUBLIC KEY` PEM.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        if let Ok(key) = encrypt::PublicKey::from_pem(pem) {
+            return match key.size() {
+                128 => Ok(KeyAlgorithm::Rsa1024),
+                256 => Ok(KeyAlgorithm::Rsa2048),
+                size => Err(Error::YubiKeyError(format!("Unsupported RSA key size {}", size * 8))),
+            };
+        }
+        Ok(match encrypt::EccPublicKey::from_pem(pem)?.algorithm {
+            EccAlgorithm::P256 => KeyAlgorithm::EccP256,
+            EccAlgorithm::P384 => KeyAlgorithm::EccP384,
+        })
+    }
+}
+
+impl From<AlgorithmId> for KeyAlgorithm {
+    fn from(algorithm: AlgorithmId) -> Self {
+        match algorithm {
+            AlgorithmId::Rsa1024 => KeyAlgorithm::Rsa1024,
+            AlgorithmId::Rsa2048 => KeyAlgorithm::Rsa2048,
+            AlgorithmId::EccP256 => KeyAlgorithm::EccP256,
+            AlgorithmId::EccP384 => KeyAlgorithm::EccP384,
+        }
+    }
+}
+
+impl From<KeyAlgorithm> for AlgorithmId {
+    fn from(algorithm: KeyAlgorithm) -> Self {
+        match algorithm {
+            KeyAlgorithm::Rsa1024 => AlgorithmId::Rsa1024,
+            KeyAlgorithm::Rsa2048 => AlgorithmId::Rsa2048,
+            KeyAlgorithm::EccP256 => AlgorithmId::EccP256,
+            KeyAlgorithm::EccP384 => AlgorithmId::EccP384,
+        }
+    }
+}
+
+/// A key replaced by a rotation, still on the card to open older data.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RetiredKey {
+    /// Slot holding it now, one of the retired slots (82 to 95) or its old slot.
+    pub slot: u8,
+    pub pub_key: String,
+}
+
+/// A YubiKey allowed to unwrap vault keys and open YubiKey secrets.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EnrolledKey {
+    pub label: String,
+    pub serial: u32,
+    /// PIV slot of the key wrapping data, the key management slot (9D) unless
+    /// a rotation put the current key in a retired slot.
+    pub slot: u8,
+    pub algorithm: KeyAlgorithm,
+    pub pub_key: String,
+    /// Public key of the authentication slot, verifies unlock challenges.
+    #[serde(default)]
+    pub auth_pub_key: Option<String>,
+    #[serde(default = "EnrolledKey::default_auth_slot")]
+    pub auth_slot: u8,
+    /// Earlier keys of the card, the most recent last.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retired: Vec<RetiredKey>,
+    /// Attestation of the current key, checked when it was enrolled or rotated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<Attestation>,
+}
+
+impl EnrolledKey {
+    pub fn new(label: String, serial: u32, algorithm: KeyAlgorithm, pub_key: String, auth_pub_key: Option<String>) -> Self {
+        Self {
+            label,
+            serial,
+            slot: SlotId::KeyManagement.into(),
+            algorithm,
+            pub_key,
+            auth_pub_key,
+            auth_slot: Self::default_auth_slot(),
+            retired: vec![],
+            attestation: None,
+        }
+    }
+
+    fn default_auth_slot() -> u8 {
+        SlotId::Authentication.into()
+    }
+}
+
+/// Ties the authentication key of an enrolled YubiKey to its card, for unlocking
+/// without the master password: a random key wrapped for the card's key
+/// management key, and a MAC made with it over the slots and public keys. Only
+/// the enrolled card unwraps the key, so settings edited to point at another
+/// authentication key or another card fail the check.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Anchor {
+    key: String,
+    mac: String,
+}
+
+impl Anchor {
+    fn new(enrolled: &EnrolledKey) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        let wrapped = encrypt::encryptor_from_pem(&enrolled.pub_key)?.encrypt_u8(key.as_ref())?;
+        let mac = Self::hmac(key.as_ref(), enrolled)?.finalize().into_bytes();
+        Ok(Self {
+            key: BASE64.encode(wrapped),
+            mac: BASE64.encode(mac),
+        })
+    }
+
+    // `device` must know the PIN to unwrap the key
+    fn verify(&self, enrolled: &EnrolledKey, device: &dyn Encrypt) -> Result<()> {
+        let tampered = || Error::Tampered(format!("YubiKey {} settings were modified outside the app", enrolled.serial));
+        let wrapped = BASE64.decode(&self.key).map_err(|_| tampered())?;
+        let key = Zeroizing::new(device.decrypt_u8(&wrapped).map_err(|e| match e {
+            encrypt::Error::WrongPin(_) | encrypt::Error::PinBlocked(_) => Error::from(e),
+            _ => tampered(),
+        })?);
+        let tag = BASE64.decode(&self.mac).map_err(|_| tampered())?;
+        Self::hmac(&key, enrolled)?.verify_slice(&tag).map_err(|_| tampered())
+    }
+
+    fn hmac(key: &[u8], enrolled: &EnrolledKey) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| Error::Custom(e.to_string()))?;
+        let anchored = serde_json::json!({
+            "serial": enrolled.serial,
+            "slot": enrolled.slot,
+            "pub_key": enrolled.pub_key,
+            "auth_slot": enrolled.auth_slot,
+            "auth_pub_key": enrolled.auth_pub_key,
+        });
+        mac.update(&serde_json::to_vec(&anchored).map_err(|e| Error::YubiKeyError(e.to_string()))?);
+        Ok(mac)
+    }
+}
+
+// Settings written when a single YubiKey could be enrolled, before they were authenticated
+#[derive(Deserialize)]
+struct LegacyKey {
+    serial: Option<u32>,
+    #[serde(default)]
+    name: String,
+    pub_key: Option<String>,
+    #[serde(default)]
+    auth_pub_key: Option<String>,
+}
+
+/// Content of `yubikey_settings.json`: every enrolled YubiKey, the primary first.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct YubiKeySettings {
+    keys: Vec<EnrolledKey>,
+    /// Anchor of every enrolled key by serial, made again on every save.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    anchors: BTreeMap<u32, Anchor>,
+}
+
+impl YubiKeySettings {
+    /// Reads the settings, empty when no YubiKey was ever enrolled. The file is
+    /// authenticated with a key derived from the master key, so a public key
+    /// swapped in on disk fails with `Error::Tampered` instead of being trusted.
+    pub fn load(app_state: &AppState) -> Result<Self> {
+        let Some(data) = Self::read(app_state)? else {
+            return Ok(Self::default());
+        };
+        let payload = mac::open(Self::master_key(app_state)?, mac::YUBIKEY_SETTINGS, &data)?;
+        serde_json::from_value(payload).map_err(|e| Error::YubiKeyError(e.to_string()))
+    }
+
+    /// First enrolled key of a connected card `accept` takes, opened with
+    /// `pin`, for when the master password is not there to check the
+    /// settings. A key is only returned once its card unwrapped the anchor,
+    /// see `Anchor`. Cards are tried in the order they were enrolled, a card
+    /// that fails is skipped, but a wrong PIN stops there so the other cards
+    /// keep their retries.
+    pub fn connected(
+        app_state: &AppState,
+        pin: &str,
+        accept: impl Fn(&EnrolledKey) -> bool,
+    ) -> Result<(EnrolledKey, YubiKeyDevice)> {
+        let Some(data) = Self::read(app_state)? else {
+            return Err(Error::YubiKeyError("No YubiKey is enrolled".to_string()));
+        };
+        let settings: Self =
+            serde_json::from_value(mac::read_unverified(&data)?).map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        let connected: Vec<u32> = app_state.piv().list()?.iter().map(|card| card.serial()).collect();
+        let mut failure = Error::YubiKeyError("No enrolled YubiKey is connected".to_string());
+        for key in settings.keys {
+            if !connected.contains(&key.serial) || !accept(&key) {
+                continue;
+            }
+            let Some(anchor) = settings.anchors.get(&key.serial) else {
+                failure = Error::YubiKeyError(format!(
+                    "Unlock with the master password once before using YubiKey {} alone",
+                    key.serial
+                ));
+                continue;
+            };
+            let device = YubiKeyDevice::open_enrolled(app_state.piv(), &key)?.with_pin(pin);
+            match anchor.verify(&key, &device) {
+                Ok(()) => return Ok((key, device)),
+                Err(e @ (Error::WrongPin(_) | Error::PinBlocked(_))) => return Err(e),
+                Err(e) => failure = e,
+            }
+        }
+        Err(failure)
+    }
+
+    /// Signs settings written before they were authenticated, once the master
+    /// key is there. Signed settings are saved again when a key has no anchor.
+    /// Once signed settings were written, see `MasterPassword::signs_settings`,
+    /// settings without a MAC, or with one that does not check out, are never
+    /// signed again and fail with `Error::Tampered`.
+    pub fn migrate(app_state: &AppState) -> Result<()> {
+        let signed_before = MasterPassword::signs_settings(app_state.file_system())?;
+        let Some(data) = Self::read(app_state)? else {
+            return Ok(MasterPassword::record_signed_settings(app_state)?);
+        };
+        let value: serde_json::Value = serde_json::from_str(&data).map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        let settings = if value.get("payload").is_some() && value.get("mac").is_some() {
+            let settings = Self::load(app_state)?;
+            if settings.keys.iter().all(|key| settings.anchors.contains_key(&key.serial)) {
+                return Ok(MasterPassword::record_signed_settings(app_state)?);
+            }
+            settings
+        } else if signed_before {
+            return Err(Error::Tampered("YubiKey settings lost their MAC outside the app".to_string()));
+        } else if value.get("keys").is_some() {
+            serde_json::from_value(value).map_err(|e| Error::YubiKeyError(e.to_string()))?
+        } else {
+            let legacy: LegacyKey = serde_json::from_value(value).map_err(|e| Error::YubiKeyError(e.to_string()))?;
+            let mut settings = Self::default();
+            if let (Some(serial), Some(pub_key)) = (legacy.serial, legacy.pub_key) {
+                let algorithm = KeyAlgorithm::from_pem(&pub_key)?;
+                settings.add(EnrolledKey::new(legacy.name, serial, algorithm, pub_key, legacy.auth_pub_key));
+            }
+            settings
+        };
+        settings.save(app_state)?;
+        Ok(MasterPassword::record_signed_settings(app_state)?)
+    }
+
+    pub fn save(&self, app_state: &AppState) -> Result<()> {
+        let fs = app_state.file_system();
+        let data = self.to_json(app_state)?;
+        std::fs::write(fs.yubikey_settings(), data).map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Content of the settings file, authenticated with the master key and
+    /// with a new anchor for every key.
+    pub fn to_json(&self, app_state: &AppState) -> Result<String> {
+        let master_key = Self::master_key(app_state)?;
+        let mut anchored = self.clone();
+        anchored.anchors = self
+            .keys
+            .iter()
+            .map(|key| Ok((key.serial, Anchor::new(key)?)))
+            .collect::<Result<_>>()?;
+        let payload = serde_json::to_value(&anchored).map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        Ok(mac::sign(master_key, mac::YUBIKEY_SETTINGS, payload)?)
+    }
+
+    fn read(app_state: &AppState) -> Result<Option<String>> {
+        let path = app_state.file_system().yubikey_settings();
+        if !path.exists() {
+            return Ok(None);
+        }
+        std::fs::read_to_string(path).map(Some).map_err(|e| Error::YubiKeyError(e.to_string()))
+    }
+
+    fn master_key(app_state: &AppState) -> Result<&AES> {
+        MasterPassword::from_state(app_state)
+            .map_err(|_| Error::Locked("Unlock with the master password to use the YubiKey settings".to_string()))
+    }
+
+    pub fn keys(&self) -> &[EnrolledKey] {
+        &self.keys
+    }
+
+    pub fn find(&self, serial: u32) -> Option<&EnrolledKey> {
+        self.keys.iter().find(|key| key.serial == serial)
+    }
+
+    fn find_mut(&mut self, serial: u32) -> Result<&mut EnrolledKey> {
+        self.keys
+            .iter_mut()
+            .find(|key| key.serial == serial)
+            .ok_or(Error::YubiKeyError(format!("YubiKey {} is not enrolled", serial)))
+    }
+
+    /// Adds `key`, or replaces the enrolled key with the same serial.
+    pub fn add(&mut self, key: EnrolledKey) {
+        match self.keys.iter_mut().find(|enrolled| enrolled.serial == key.serial) {
+            Some(enrolled) => *enrolled = key,
+            None => self.keys.push(key),
+        }
+    }
+
+    pub fn rename(&mut self, serial: u32, label: &str) -> Result<()> {
+        if label.trim().is_empty() {
+            return Err(Error::YubiKeyError("YubiKey label can not be empty".to_string()));
+        }
+        self.find_mut(serial)?.label = label.trim().to_string();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, serial: u32) -> Result<EnrolledKey> {
+        let index = self
+            .keys
+            .iter()
+            .position(|key| key.serial == serial)
+            .ok_or(Error::YubiKeyError(format!("YubiKey {} is not enrolled", serial)))?;
+        Ok(self.keys.remove(index))
+    }
+
+    /// Serial and public key of every enrolled key.
+    pub fn public_keys(&self) -> Vec<(u32, &str)> {
+        self.keys.iter().map(|key| (key.serial, key.pub_key.as_str())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(serial: u32) -> EnrolledKey {
+        let pem = std::fs::read_to_string("tests/fixtures/piv/9d.pem").unwrap();
+        let private = <rsa::RsaPrivateKey as rsa::pkcs8::DecodePrivateKey>::from_pkcs8_pem(&pem).unwrap();
+        let pub_key = rsa::pkcs8::EncodePublicKey::to_public_key_pem(&rsa::RsaPublicKey::from(&private), rsa::pkcs8::LineEnding::LF).unwrap();
+        EnrolledKey::new(format!("Key {}", serial), serial, KeyAlgorithm::from_pem(&pub_key).unwrap(), pub_key, None)
+    }
+
+    #[test]
+    fn test_settings_round_trip() {
+        let mut state = AppState::new_unauthenticated_test();
+        MasterPassword::save(&mut state, "password", None).unwrap();
+        assert!(YubiKeySettings::load(&state).unwrap().keys().is_empty());
+
+        let mut settings = YubiKeySettings::default();
+        settings.add(key(1));
+        settings.add(key(2));
+        settings.rename(2, " Backup ").unwrap();
+        settings.save(&state).unwrap();
+
+        let mut loaded = YubiKeySettings::load(&state).unwrap();
+        assert_eq!(loaded.keys().len(), 2);
+        assert_eq!(loaded.find(2).unwrap().label, "Backup");
+        assert_eq!(loaded.find(1).unwrap().algorithm, KeyAlgorithm::Rsa2048);
+        assert_eq!(loaded.find(1).unwrap().slot, 0x9d);
+        assert!(loaded.rename(2, " ").is_err());
+        assert!(loaded.rename(3, "Missing").is_err());
+        assert_eq!(loaded.remove(1).unwrap().serial, 1);
+        assert!(loaded.remove(1).is_err());
+    }
+
+    #[test]
+    fn test_tampered_settings_are_rejected() {
+        let state = AppState::new_test("password");
+        let path = state.file_system().yubikey_settings();
+        let signed = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(YubiKeySettings::load(&state).unwrap().keys().len(), 1);
+
+        // Swap in another public key
+        let mut value: serde_json::Value = serde_json::from_str(&signed).unwrap();
+        value["payload"]["keys"][0]["pub_key"] = key(1).pub_key.replace('A', "B").into();
+        std::fs::write(&path, value.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::load(&state), Err(Error::Tampered(_))));
+        // Settings without a MAC are not trusted either
+        std::fs::write(&path, value["payload"].to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::load(&state), Err(Error::Tampered(_))));
+
+        // A MAC that does not check out is not migrated away
+        std::fs::write(&path, value.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::migrate(&state), Err(Error::Tampered(_))));
+
+        std::fs::write(&path, &signed).unwrap();
+        let mut state = state;
+        state.log_out();
+        assert!(matches!(YubiKeySettings::load(&state), Err(Error::Locked(_))));
+        assert!(YubiKeySettings::connected(&state, "123456", |_| true).is_ok());
+    }
+
+    #[test]
+    fn test_anchor_binds_the_authentication_key() {
+        let mut state = AppState::new_test("password");
+        state.log_out();
+        let path = state.file_system().yubikey_settings();
+        let signed = std::fs::read_to_string(&path).unwrap();
+        let serial = AppState::TEST_YUBIKEY_SERIAL;
+        assert!(matches!(YubiKeySettings::connected(&state, "000000", |_| true), Err(Error::WrongPin(_))));
+
+        // Without the master key the MAC of the file can not be checked, the anchor still is
+        let other = p256::SecretKey::random(&mut OsRng).public_key();
+        let other = rsa::pkcs8::EncodePublicKey::to_public_key_pem(&other, rsa::pkcs8::LineEnding::LF).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&signed).unwrap();
+        value["payload"]["keys"][0]["auth_pub_key"] = other.clone().into();
+        std::fs::write(&path, value.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::connected(&state, "123456", |_| true), Err(Error::Tampered(_))));
+        // Nor can an anchor be moved to another key
+        let mut value: serde_json::Value = serde_json::from_str(&signed).unwrap();
+        value["payload"]["keys"][0]["pub_key"] = other.into();
+        std::fs::write(&path, value.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::connected(&state, "123456", |_| true), Err(Error::Tampered(_))));
+        // Settings never anchored wait for the master password
+        let mut value: serde_json::Value = serde_json::from_str(&signed).unwrap();
+        value["payload"].as_object_mut().unwrap().remove("anchors");
+        std::fs::write(&path, value.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::connected(&state, "123456", |_| true), Err(Error::YubiKeyError(_))));
+
+        MasterPassword::verify(&mut state, "password").unwrap();
+        assert!(matches!(YubiKeySettings::migrate(&state), Err(Error::Tampered(_))));
+        let master = MasterPassword::from_state(&state).unwrap();
+        std::fs::write(&path, mac::sign(master, mac::YUBIKEY_SETTINGS, value["payload"].clone()).unwrap()).unwrap();
+        YubiKeySettings::migrate(&state).unwrap();
+        let (key, _) = YubiKeySettings::connected(&state, "123456", |_| true).unwrap();
+        assert_eq!(key.serial, serial);
+    }
+
+    #[test]
+    fn test_unsigned_settings_after_signing_are_rejected() {
+        let state = AppState::new_test("password");
+        let path = state.file_system().yubikey_settings();
+        let payload = mac::read_unverified(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::write(&path, payload.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::migrate(&state), Err(Error::Tampered(_))));
+        assert!(matches!(YubiKeySettings::load(&state), Err(Error::Tampered(_))));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), payload.to_string());
+    }
+
+    #[test]
+    fn test_reads_single_yubikey_settings() {
+        let mut state = AppState::new_unauthenticated_test();
+        MasterPassword::save(&mut state, "password", None).unwrap();
+        // Settings of older versions were never signed
+        let unsigned_settings = |state: &AppState| {
+            let verifier = encrypt::Verifier::with_unsigned_settings(MasterPassword::from_state(state).unwrap()).unwrap();
+            std::fs::write(state.file_system().master_verifier(), verifier.to_json().unwrap()).unwrap();
+        };
+        let enrolled = key(7);
+        let legacy = serde_json::json!({
+            "serial": 7,
+            "name": "YubiKey 7",
+            "version": "5.4.3",
+            "is_fips": false,
+            "form_factor": "UsbAKeychain",
+            "pub_key": enrolled.pub_key,
+        });
+        let path = state.file_system().yubikey_settings();
+        std::fs::write(&path, legacy.to_string()).unwrap();
+        assert!(matches!(YubiKeySettings::load(&state), Err(Error::Tampered(_))));
+
+        unsigned_settings(&state);
+        YubiKeySettings::migrate(&state).unwrap();
+        assert!(MasterPassword::signs_settings(state.file_system()).unwrap());
+        let settings = YubiKeySettings::load(&state).unwrap();
+        assert_eq!(settings.keys(), &[EnrolledKey { label: "YubiKey 7".to_string(), ..enrolled.clone() }]);
+        assert!(settings.anchors.contains_key(&7));
+
+        // The plaintext list written before the settings were signed
+        let list = serde_json::json!({ "keys": [key(8)] });
+        std::fs::write(&path, list.to_string()).unwrap();
+        unsigned_settings(&state);
+        YubiKeySettings::migrate(&state).unwrap();
+        assert_eq!(YubiKeySettings::load(&state).unwrap().keys(), &[key(8)]);
+    }
+}
@@ -1,10 +1,35 @@
 use crate::error::{Error, Result};
  
-use crate::AppState; // Changed: split from previous line
+use crate::{AppState, MasterPassword}; // Changed: split from previous line
+use crate::encrypt::{self, ecc, hybrid, hybrid::Padding, padding, Encrypt};
+use crate::file_system::Transaction;
+use crate::secrets::Secret;
+use crate::vaults::Vault;
+use std::cell::RefCell;
 use base64::Engine;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use yubikey::{YubiKey, piv};
+use yubikey::piv;
+
+mod attestation;
+mod backend;
+mod enrolled;
+mod pin;
+#[cfg(not(target_os = "macos"))]
+mod pkcs11;
+mod provision;
+#[cfg(test)]
+mod virtual_card;
+
+pub use attestation::{Attestation, PinPolicy, TouchPolicy, TrustAnchors};
+pub use backend::{Devices, Hardware, PivBackend, PivCard};
+#[cfg(not(target_os = "macos"))]
+pub use pkcs11::Pkcs11Backend;
+pub use enrolled::{EnrolledKey, KeyAlgorithm, RetiredKey, YubiKeySettings};
+pub use pin::{change_pin, pin_status, unblock_pin, PinStatus};
+pub use provision::{provision, rotate, Provisioning};
+#[cfg(test)]
+pub use virtual_card::{VirtualBackend, VirtualCard};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct YubiKeyInfo {
@@ -13,7 +38,18 @@ pub struct YubiKeyInfo {
     pub version: Option<String>,
     pub is_fips: bool,
     pub form_factor: String,
-    pub pub_key: Option<String>
+    pub pub_key: Option<String>,
+    /// Public key of the authentication slot (9A), verifies unlock challenges.
+    #[serde(default)]
+    pub auth_pub_key: Option<String>,
+    /// Whether the key management key has an attestation signed by the card,
+    /// which only keys generated on it get.
+    #[serde(default)]
+    pub generated_on_device: bool,
+    #[serde(default)]
+    pub pin_policy: Option<PinPolicy>,
+    #[serde(default)]
+    pub touch_policy: Option<TouchPolicy>,
 }
 
 #[cfg(test)]
@@ -25,155 +61,354 @@ impl Default for YubiKeyInfo {
             version: None,
             is_fips: false,
             form_factor: "YubiKey".to_string(),
-            pub_key: None
+            pub_key: None,
+            auth_pub_key: None,
+            generated_on_device: false,
+            pin_policy: None,
+            touch_policy: None,
         }
     }
 }
 
 impl YubiKeyInfo {
-    fn from_yubikey(key: &mut YubiKey) -> Self { // Takes &mut YubiKey
+    fn from_card(card: &mut dyn PivCard, roots: &TrustAnchors) -> Self {
         // Get serial number
-        let serial_u32 = u32::from(key.serial());
+        let serial_u32 = card.serial();
 
         // Format the version as a string
-        let version = Some(key.version().to_string());
+        let version = Some(card.version());
 
         // YubiKey 0.8.0 doesn't expose form_factor directly
         let form_factor = "YubiKey".to_string();
 
-        // Try to get the public key using the new helper
-        let pub_key = YubiKeyInfo::extract_public_key_pem(key).ok();
+        // Public key of the key management certificate, if the slot is provisioned
+        let pub_key = card.read_certificate(piv::SlotId::KeyManagement).ok();
+        let auth_pub_key = card.read_certificate(piv::SlotId::Authentication).ok();
+        let attestation = pub_key
+            .as_deref()
+            .and_then(|pem| attestation::attest(card, piv::SlotId::KeyManagement, roots, pem).ok());
 
         Self {
             serial: Some(serial_u32),
-            name: format!("YubiKey {}", serial_u32),
+            name: card.name(),
             version,
             is_fips: false, // Not directly accessible in 0.8.0
             form_factor,
             pub_key,
+            auth_pub_key,
+            generated_on_device: attestation.as_ref().is_some_and(|attestation| !attestation.unsigned),
+            pin_policy: attestation.as_ref().and_then(|attestation| attestation.pin_policy),
+            touch_policy: attestation.as_ref().and_then(|attestation| attestation.touch_policy),
         }
     }
+}
 
-    // Helper function to extract PEM-encoded public key from a YubiKey instance
-    fn extract_public_key_pem(yubikey: &mut YubiKey) -> Result<String> {
-        use rsa::pkcs1::der::EncodePem;
-        let slot = piv::SlotId::KeyManagement;
-        let cert = yubikey::certificate::Certificate::read(yubikey, slot)
-            .map_err(|e| Error::YubiKeyError(format!("Failed to get certificate from slot {:?} for pubkey extraction: {}", slot, e)))?;
-        cert.subject_pki().to_pem(rsa::pkcs1::der::pem::LineEnding::LF)
-            .map_err(|e| Error::YubiKeyError(format!("Failed to encode public key to PEM for pubkey extraction: {}", e)))
+// Error handler for YubiKey operations, PIN failures stay typed for the UI
+impl From<yubikey::Error> for Error {
+    fn from(err: yubikey::Error) -> Self {
+        match err {
+            yubikey::Error::WrongPin { tries } => Error::WrongPin(tries),
+            yubikey::Error::PinLocked => Error::PinBlocked("The PIN is blocked, unblock it with the PUK".to_string()),
+            err => Error::YubiKeyError(format!("{}", err)),
+        }
     }
+}
+
+/// List all connected YubiKeys
+pub fn list_yubikeys(backend: &dyn PivBackend) -> Result<Vec<YubiKeyInfo>> {
+    let roots = backend.attestation_roots()?;
+    Ok(backend
+        .list()?
+        .iter_mut()
+        .map(|card| YubiKeyInfo::from_card(card.as_mut(), &roots))
+        .collect())
+}
 
-    pub fn set_pub_key(&mut self, pub_key: String) {
-        self.pub_key = Some(pub_key);
+/// Enrolls the connected YubiKey `serial` next to the keys already enrolled:
+/// every vault key gets a copy wrapped with `public_key`. Secrets sealed to the
+/// other keys are only readable by the new one after `rewrap_for_backup`.
+/// The key must be attested by the card, proving it was generated there.
+pub fn enroll(app_state: &AppState, serial: u32, label: Option<String>, public_key: String) -> Result<EnrolledKey> {
+    let list = list_yubikeys(app_state.piv())?;
+    let found = list.iter().find(move |x| x.serial == Some(serial)).ok_or(Error::YubiKeyError("YubiKey not found".to_string()))?;
+    let mut card = app_state.piv().open(serial)?;
+    let algorithm = card
+        .metadata(piv::SlotId::KeyManagement)?
+        .map(KeyAlgorithm::from)
+        .map_or_else(|| KeyAlgorithm::from_pem(&public_key), Ok)?;
+    let roots = app_state.piv().attestation_roots()?;
+    let attestation = attestation::attest(card.as_mut(), piv::SlotId::KeyManagement, &roots, &public_key)?;
+    drop(card);
+    let label = label.filter(|label| !label.trim().is_empty()).unwrap_or_else(|| found.name.clone());
+    let mut key = EnrolledKey::new(label, serial, algorithm, public_key, found.auth_pub_key.clone());
+    key.attestation = Some(attestation);
+    let mut settings = YubiKeySettings::load(app_state)?;
+    settings.add(key.clone());
+    // Let the YubiKey unwrap the data key of every vault the session opens.
+    // Keyrings are all wrapped before the transaction, migrating a vault
+    // commits its own. Vaults requiring a YubiKey that is not verified keep
+    // the copies they have, see `rewrap_for_backup`
+    let password_key = MasterPassword::from_state(app_state)?;
+    let mut keyrings = vec![];
+    for vault in Vault::all(app_state)? {
+        let mut keyring = vault.keyring(app_state)?;
+        let data_key = match vault.data_key(app_state) {
+            Err(crate::vaults::Error::Locked(_)) => continue,
+            data_key => data_key?,
+        };
+        keyring.add_yubikey(&keyring.card_copy(&data_key, password_key)?, serial, &key.pub_key)?;
+        keyrings.push((vault, keyring));
+    }
+    let fs = app_state.file_system();
+    let mut transaction = Transaction::new(fs).map_err(|e| Error::Io(e.to_string()))?;
+    for (vault, keyring) in keyrings {
+        transaction
+            .stage(&fs.vault_keyring(vault.name()), Vault::keyring_json(app_state, &keyring)?)
+            .map_err(|e| Error::Io(e.to_string()))?;
     }
+    transaction
+        .stage(&fs.yubikey_settings(), settings.to_json(app_state)?)
+        .map_err(|e| Error::Io(e.to_string()))?;
+    transaction.commit().map_err(|e| Error::Io(e.to_string()))?;
+    Ok(key)
+}
+
+pub fn rename_enrolled(app_state: &AppState, serial: u32, label: &str) -> Result<()> {
+    let mut settings = YubiKeySettings::load(app_state)?;
+    settings.rename(serial, label)?;
+    settings.save(app_state)
+}
 
-    pub fn save(&self, app_state: &AppState) -> Result<()> {
-        let fs = app_state.file_system();
-        let data = serde_json::to_string(self).map_err(|e| Error::YubiKeyError(e.to_string()))?;
-        // TODO: encrypt the data before saving with master password
-        std::fs::write(fs.yubikey_settings(), data).map_err(|e| Error::YubiKeyError(e.to_string()))?;
-        Ok(())
+/// Removes an enrolled YubiKey and its copies of vault keys and secrets. Refused
+/// when a vault requiring a YubiKey, or a YubiKey secret, would be left without
+/// a copy for one of the remaining keys.
+pub fn remove_enrolled(app_state: &AppState, serial: u32) -> Result<()> {
+    let mut settings = YubiKeySettings::load(app_state)?;
+    settings.remove(serial)?;
+    let remaining: Vec<u32> = settings.keys().iter().map(|key| key.serial).collect();
+
+    let mut vaults = vec![];
+    for vault in Vault::all(app_state)? {
+        let mut keyring = vault.keyring(app_state)?;
+        if keyring.policy().needs_yubikey() && !remaining.iter().any(|serial| keyring.has_yubikey(*serial)) {
+            return Err(Error::YubiKeyError(format!(
+                "Vault {} needs a YubiKey, enroll a backup before removing this one",
+                vault.name()
+            )));
+        }
+        let data_key = vault.data_key(app_state)?;
+        if Secret::count_sealed_without(app_state, &vault, &data_key, &remaining)? > 0 {
+            return Err(Error::YubiKeyError(format!(
+                "Secrets of vault {} can only be opened with this YubiKey, re-wrap them for a backup first",
+                vault.name()
+            )));
+        }
+        keyring.remove_yubikey(serial);
+        vaults.push((vault, keyring, data_key));
     }
 
-    pub fn get(app_state: &AppState) -> Result<Self> {
-        let fs = app_state.file_system();
-        let data = std::fs::read_to_string(fs.yubikey_settings()).map_err(|e| Error::YubiKeyError(e.to_string()))?;
-        let yubikey_info: YubiKeyInfo = serde_json::from_str(&data).map_err(|e| Error::YubiKeyError(e.to_string()))?;
-        Ok(yubikey_info)
+    let fs = app_state.file_system();
+    let mut transaction = Transaction::new(fs).map_err(|e| Error::Io(e.to_string()))?;
+    for (vault, keyring, data_key) in vaults {
+        Secret::remove_sealed_for(app_state, &vault, &data_key, serial, &mut transaction)?;
+        transaction
+            .stage(&fs.vault_keyring(vault.name()), Vault::keyring_json(app_state, &keyring)?)
+            .map_err(|e| Error::Io(e.to_string()))?;
     }
+    transaction
+        .stage(&fs.yubikey_settings(), settings.to_json(app_state)?)
+        .map_err(|e| Error::Io(e.to_string()))?;
+    transaction.commit().map_err(|e| Error::Io(e.to_string()))?;
+    Ok(())
 }
 
-impl From<AppState> for YubiKeyInfo {
-    fn from(app_state: AppState) -> Self {
-        YubiKeyInfo::get(&app_state).unwrap()
+/// Gives the enrolled YubiKey `serial` a copy of every vault key and YubiKey
+/// secret. Another enrolled YubiKey must be connected to open them with `pin`.
+pub fn rewrap_for_backup(app_state: &AppState, serial: u32, pin: &str) -> Result<()> {
+    let settings = YubiKeySettings::load(app_state)?;
+    let backup = settings
+        .find(serial)
+        .ok_or(Error::YubiKeyError(format!("YubiKey {} is not enrolled", serial)))?;
+    let connected: Vec<u32> = app_state.piv().list()?.iter().map(|card| card.serial()).collect();
+    let opener = settings
+        .keys()
+        .iter()
+        .find(|key| key.serial != serial && connected.contains(&key.serial))
+        .ok_or(Error::YubiKeyError("Connect another enrolled YubiKey to re-wrap for a backup".to_string()))?;
+    let device = YubiKeyDevice::open_enrolled(app_state.piv(), opener)?.with_pin(pin);
+
+    let vaults = Vault::all(app_state)?;
+    // Migrating a vault commits its own transaction, it can not happen while staging
+    for vault in &vaults {
+        vault.ensure_keyring(app_state)?;
     }
-}
-// Error handler for YubiKey operations
-impl From<yubikey::Error> for Error {
-    fn from(err: yubikey::Error) -> Self {
-        Error::YubiKeyError(format!("{}", err))
+    let password_key = MasterPassword::from_state(app_state)?;
+    let fs = app_state.file_system();
+    let mut transaction = Transaction::new(fs).map_err(|e| Error::Io(e.to_string()))?;
+    for vault in vaults {
+        let mut keyring = vault.keyring(app_state)?;
+        // The data key is opened from the opener's copy, the backup gets the same copy
+        let opener_copy = if keyring.has_yubikey(opener.serial) {
+            Some(keyring.unlock_with_yubikey(opener.serial, &device)?)
+        } else {
+            None
+        };
+        let data_key = match &opener_copy {
+            Some(opener_copy) => keyring.open(Some(password_key), Some(opener_copy))?,
+            None => vault.data_key(app_state)?,
+        };
+        let card_copy = keyring.card_copy(&data_key, password_key)?;
+        Secret::reseal_for(app_state, &vault, &data_key, (opener.serial, &device), backup, &mut transaction)?;
+        keyring.add_yubikey(&card_copy, serial, &backup.pub_key)?;
+        transaction
+            .stage(&fs.vault_keyring(vault.name()), Vault::keyring_json(app_state, &keyring)?)
+            .map_err(|e| Error::Io(e.to_string()))?;
     }
+    transaction.commit().map_err(|e| Error::Io(e.to_string()))?;
+    Ok(())
 }
 
-/// List all connected YubiKeys
-pub fn list_yubikeys() -> Result<Vec<YubiKeyInfo>> {
-    let mut keys = Vec::new();
-    let mut context = yubikey::reader::Context::open()
-        .map_err(|e| Error::YubiKeyError(format!("Failed to open YubiKey context: {}", e)))?;
-
-    // Find all YubiKey readers
-    match context.iter() {
-        Ok(readers) => {
-            for reader in readers {
-                // Try to open the YubiKey from this reader
-                if let Ok(mut yubikey_instance) = reader.open() { // yubikey_instance is now mutable
-                    keys.push(YubiKeyInfo::from_yubikey(&mut yubikey_instance)); // Pass &mut
-                }
-            }
-            Ok(keys)
+/// Unlocks the session with an enrolled YubiKey: YubiKey `serial`, or the first
+/// connected one that opens with `pin`, signs a random challenge, checked
+/// against the authentication key saved at enrollment, then unwraps its copies
+/// of the vault keys. The YubiKey only counts as verified once it unwrapped
+/// one, and the session is only authenticated when a vault opens with the
+/// YubiKey alone.
+pub fn unlock(app_state: &mut AppState, pin: &str, serial: Option<u32>) -> Result<()> {
+    // Without the master password the settings can not be checked, the card
+    // vouches for the authentication key by unwrapping its anchor
+    let (key, mut device) =
+        YubiKeySettings::connected(app_state, pin, |key| serial.is_none_or(|serial| key.serial == serial))?;
+    let serial = key.serial;
+    let auth_pub_key = key
+        .auth_pub_key
+        .as_deref()
+        .ok_or(Error::YubiKeyError("The enrolled YubiKey has no authentication key".to_string()))?;
+    let challenge = device.generate_authentication_challenge()?;
+    let signature = device.authenticate(pin.to_string(), &challenge)?;
+    verify_challenge(auth_pub_key, &challenge, &signature)?;
+
+    // Vaults requiring the YubiKey keep the copy, the data key or the card
+    // share, one copy of another vault is enough to prove the card holds its key
+    let fs = app_state.file_system();
+    let mut keys = vec![];
+    let mut verified = false;
+    let mut opened = false;
+    for vault in Vault::all(app_state)? {
+        if !fs.vault_keyring(vault.name()).exists() {
+            continue;
         }
-        Err(e) => Err(Error::YubiKeyError(format!(
-            "Failed to iterate readers: {}",
-            e
-        ))),
+        let keyring = vault.keyring(app_state)?;
+        if !keyring.has_yubikey(serial) {
+            continue;
+        }
+        if keyring.policy().needs_yubikey() {
+            let card_copy = keyring.unlock_with_yubikey(serial, &device)?;
+            opened |= keyring.open(None, Some(&card_copy)).is_ok();
+            keys.push(card_copy);
+        } else if !verified {
+            keyring.unlock_with_yubikey(serial, &device)?;
+        }
+        verified = true;
+    }
+    if !verified {
+        return Err(Error::YubiKeyError(format!(
+            "YubiKey {} holds no vault key, unlock with the master password",
+            serial
+        )));
     }
+    for key in keys {
+        app_state.add_vault_key(key);
+    }
+    app_state.set_yubikey_authenticated(true);
+    if opened {
+        app_state.set_authenticated(true);
+    }
+    Ok(())
+}
+
+/// Checks the base64 signature `authenticate` made of a base64 challenge.
+pub fn verify_challenge(public_key_pem: &str, challenge_base64: &str, signature_base64: &str) -> Result<()> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let challenge = engine
+        .decode(challenge_base64)
+        .map_err(|e| Error::YubiKeyError(format!("Invalid challenge format (base64 decode failed): {}", e)))?;
+    let signature = engine
+        .decode(signature_base64)
+        .map_err(|e| Error::YubiKeyError(format!("Invalid signature format (base64 decode failed): {}", e)))?;
+    encrypt::verify_prehash_from_pem(public_key_pem, &challenge, &signature)
+        .map_err(|_| Error::YubiKeyError("The challenge was not signed by the enrolled YubiKey".to_string()))
 }
 
 pub fn encrypt_with_yubikey(app_state: &AppState, data: &str) -> Result<String> {
-    let info = YubiKeyInfo::get(app_state)?;
-    let pub_key = info.pub_key.ok_or(Error::YubiKeyError("Public key not found".to_string()))?;
-    let encryptor = crate::encrypt::PublicKey::from_pem(&pub_key)?;
-    encryptor.encrypt(data.as_bytes())
+    let settings = YubiKeySettings::load(app_state)?;
+    let key = settings.keys().first().ok_or(Error::YubiKeyError("Public key not found".to_string()))?;
+    let encryptor = encrypt::encryptor_from_pem(&key.pub_key)?;
+    encryptor.encrypt_u8(data.as_bytes())
         .map_err(|e| Error::YubiKeyError(e.to_string()))
         .map(|encrypted| base64::engine::general_purpose::STANDARD.encode(&encrypted))
 }
 
-
-
-
+/// The retired key management slots (82 to 95), they keep earlier keys.
+pub fn retired_slots() -> impl Iterator<Item = piv::SlotId> {
+    (0x82..=0x95u8).filter_map(|slot| piv::SlotId::try_from(slot).ok())
+}
 
 // New struct to wrap a YubiKey instance
 pub struct YubiKeyDevice {
-    yk: YubiKey,
+    // Card operations need `&mut`, the `Encrypt` trait only hands out `&self`
+    card: RefCell<Box<dyn PivCard>>,
+    key_slot: piv::SlotId,
+    auth_slot: piv::SlotId,
     authentication: Option<piv::AlgorithmId>,
     key_management: Option<piv::AlgorithmId>,
+    pin: Option<String>,
 }
 
 impl YubiKeyDevice {
-    /// Opens a YubiKey by its serial number and wraps it.
-    pub fn open(serial_u32: u32) -> Result<Self> {
-        let serial = yubikey::Serial::from(serial_u32);
-        let mut yk = YubiKey::open_by_serial(serial)
-            .map_err(|e| Error::YubiKeyError(format!("Failed to open YubiKey {}: {}", serial_u32, e)))?;
+    /// Opens a YubiKey by its serial number and wraps it, using the key
+    /// management (9D) and authentication (9A) slots.
+    pub fn open(backend: &dyn PivBackend, serial_u32: u32) -> Result<Self> {
+        Self::open_slots(backend, serial_u32, piv::SlotId::KeyManagement, piv::SlotId::Authentication)
+    }
+
+    /// Opens an enrolled YubiKey with the slots recorded at enrollment.
+    pub fn open_enrolled(backend: &dyn PivBackend, key: &EnrolledKey) -> Result<Self> {
+        Self::open_slots(backend, key.serial, key.slot.try_into()?, key.auth_slot.try_into()?)
+    }
+
+    fn open_slots(backend: &dyn PivBackend, serial_u32: u32, key_slot: piv::SlotId, auth_slot: piv::SlotId) -> Result<Self> {
+        let mut card = backend.open(serial_u32)?;
 
         // Attempt to get authentication algorithm
-        let authentication_algorithm = piv::metadata(&mut yk, piv::SlotId::Authentication)
-            .ok()
-            .and_then(|meta| match meta.algorithm {
-                piv::ManagementAlgorithmId::Asymmetric(alg_id) => Some(alg_id),
-                _ => None,
-            });
+        let authentication_algorithm = card.metadata(auth_slot).ok().flatten();
 
         // Attempt to get key management algorithm
-        let key_management_algorithm = piv::metadata(&mut yk, piv::SlotId::KeyManagement)
-            .ok()
-            .and_then(|meta| match meta.algorithm {
-                piv::ManagementAlgorithmId::Asymmetric(alg_id) => Some(alg_id),
-                _ => None,
-            });
+        let key_management_algorithm = card.metadata(key_slot).ok().flatten();
 
         Ok(Self {
-            yk,
+            card: RefCell::new(card),
+            key_slot,
+            auth_slot,
             authentication: authentication_algorithm,
             key_management: key_management_algorithm,
+            pin: None,
         })
     }
 
+    /// PIN used when the device decrypts through the `Encrypt` trait.
+    pub fn with_pin(mut self, pin: &str) -> Self {
+        self.pin = Some(pin.to_string());
+        self
+    }
+
     /// Retrieves the public key from the YubiKey's Key Management slot and its algorithm ID.
     pub fn get_public_key(&mut self) -> Result<String> {
-        use rsa::pkcs1::der::EncodePem;
-        let slot = piv::SlotId::KeyManagement;
+        self.public_key_pem()
+    }
+
+    fn public_key_pem(&self) -> Result<String> {
+        let slot = self.key_slot;
 
         // Use the stored key_management algorithm if available
         self.key_management.ok_or_else(|| {
@@ -189,16 +424,16 @@ impl YubiKeyDevice {
         // If PIN is strictly required for cert reading, it should be passed here.
         // However, typically reading public certs doesn\'t require PIN.
 
-        let cert = yubikey::certificate::Certificate::read(&mut self.yk, slot)
-            .map_err(|e| Error::YubiKeyError(format!("Failed to get certificate from slot {:?}: {}", slot, e)))?;
-        
-        let pem = cert.subject_pki().to_pem(rsa::pkcs1::der::pem::LineEnding::LF)
-            .map_err(|e| Error::YubiKeyError(format!("Failed to encode public key to PEM: {}", e)))?;
-        Ok(pem)
+        self.card.borrow_mut().read_certificate(slot)
     }
 
     /// Encrypts data using the YubiKey\\\'s public key (retrieved from the device).
     pub fn encrypt_data(&mut self, data: Vec<u8>) -> Result<String> {
+        let encrypted_bytes = self.encrypt_bytes(&data)?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(&encrypted_bytes))
+    }
+
+    fn encrypt_bytes(&self, data: &[u8]) -> Result<Vec<u8>> {
         // Retrieve the algorithm ID from the stored key_management metadata.
         let algorithm_id = self.key_management.ok_or_else(|| {
             Error::YubiKeyError(
@@ -209,18 +444,16 @@ impl YubiKeyDevice {
         match algorithm_id {
             piv::AlgorithmId::Rsa1024 | piv::AlgorithmId::Rsa2048 => {
                 // The key is RSA, proceed with RSA encryption.
-                let pub_key_pem = self.get_public_key()?; // Fetches the PEM-encoded public key.
+                let pub_key_pem = self.public_key_pem()?; // Fetches the PEM-encoded public key.
                 
                 let encryptor = crate::encrypt::PublicKey::from_pem(&pub_key_pem)?;
-                let encrypted_bytes = encryptor.encrypt(&data)?;
-                Ok(base64::engine::general_purpose::STANDARD.encode(&encrypted_bytes))
+                Ok(encryptor.encrypt(data)?)
             }
             piv::AlgorithmId::EccP256 | piv::AlgorithmId::EccP384 => {
-                // The key is ECC.
-                Err(Error::YubiKeyError(format!(
-                    "Encryption with ECC algorithm ({:?}) is not currently supported by this function. Only RSA encryption is available.",
-                    algorithm_id
-                )))
+                // The key is ECC, ECIES to the key management public key.
+                let pub_key_pem = self.public_key_pem()?;
+                let encryptor = encrypt::EccPublicKey::from_pem(&pub_key_pem)?;
+                Ok(encryptor.encrypt(data)?)
             }
             // All variants of piv::AlgorithmId relevant here (Rsa1024, Rsa2048, EccP256, EccP384)
             // are explicitly handled above. No other Asymmetric AlgorithmId types are defined
@@ -234,71 +467,88 @@ impl YubiKeyDevice {
         pin: String,
         encrypted_data_base64_bytes: Vec<u8>,
     ) -> Result<Vec<u8>> {
-        self.yk.verify_pin(pin.as_bytes())?;
-
-        let slot = piv::SlotId::KeyManagement;
+        let raw_ciphertext = base64::engine::general_purpose::STANDARD.decode(&encrypted_data_base64_bytes)
+            .map_err(|e| Error::YubiKeyError(format!("Failed to decode base64 encrypted data: {}", e)))?;
+        self.decrypt_bytes(&pin, &raw_ciphertext)
+    }
 
-        // Use the stored key_management algorithm if available
-        let algorithm = self.key_management.ok_or_else(|| {
+    /// Decrypts with the key named by the key id in `ciphertext`, in the
+    /// current slot or one holding an earlier key. Data written before key ids is tried
+    /// with the current key first, then with the retired keys.
+    fn decrypt_bytes(&self, pin: &str, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.key_management.ok_or_else(|| {
             Error::YubiKeyError(format!(
                 "Key management algorithm not found for slot {:?} for decryption. Device might not have been properly initialized or slot is not configured.",
-                slot
+                self.key_slot
             ))
         })?;
-
-        let raw_ciphertext = base64::engine::general_purpose::STANDARD.decode(&encrypted_data_base64_bytes)
-            .map_err(|e| Error::YubiKeyError(format!("Failed to decode base64 encrypted data: {}", e)))?;
-
-        let decrypted_zeroizing_vec = piv::decrypt_data(&mut self.yk, &raw_ciphertext, algorithm, slot)
-            .map_err(Error::from)?;
-        
-        let padded_data = decrypted_zeroizing_vec.to_vec();
-        let block_len = padded_data.len();
-
-        // PKCS#1 v1.5 unpadding (EME-PKCS1-v1_5 type 2)
-        if block_len < 11 {
-            return Err(Error::YubiKeyError(format!(
-                "Decryption error: Padded data too short ({} bytes). Expected at least 11 for PKCS#1 v1.5.",
-                block_len
-            )));
-        }
-        if padded_data[0] != 0x00 || padded_data[1] != 0x02 {
-            return Err(Error::YubiKeyError(format!(
-                "Decryption error: Invalid PKCS#1 v1.5 padding header. Expected 00 02, got: {:02X} {:02X}",
-                padded_data.first().cloned().unwrap_or(0xFF),
-                padded_data.get(1).cloned().unwrap_or(0xFF)
-            )));
-        }
-        let mut separator_index: Option<usize> = None;
-        for i in 2..block_len {
-            if padded_data[i] == 0x00 {
-                separator_index = Some(i);
-                break;
+        self.card.borrow_mut().verify_pin(pin.as_bytes())?;
+
+        let key_id = encrypt::ciphertext_key_id(ciphertext);
+        let mut failure = None;
+        // Old keys are in the retired slots, or in 9D when the card could not move them
+        let others = std::iter::once(piv::SlotId::KeyManagement).chain(retired_slots());
+        for slot in std::iter::once(self.key_slot).chain(others.filter(|slot| *slot != self.key_slot)) {
+            let Some(algorithm) = self.card.borrow_mut().metadata(slot).ok().flatten() else {
+                continue;
+            };
+            if let Some(key_id) = &key_id {
+                let pem = self.card.borrow_mut().read_certificate(slot).ok();
+                if pem.and_then(|pem| encrypt::key_id_from_pem(&pem).ok()).as_ref() == Some(key_id) {
+                    return self.decrypt_in_slot(slot, algorithm, ciphertext);
+                }
+                continue;
+            }
+            match self.decrypt_in_slot(slot, algorithm, ciphertext) {
+                Ok(plaintext) => return Ok(plaintext),
+                Err(e) => failure = failure.or(Some(e)),
             }
         }
-        match separator_index {
-            Some(idx) => {
-                if (idx - 2) < 8 {
-                    return Err(Error::YubiKeyError(format!(
-                        "Decryption error: PKCS#1 v1.5 padding string (PS) too short ({} bytes). Minimum 8 bytes required. Separator at index {}.",
-                        idx - 2, idx
-                    )));
-                }
-                if (idx + 1) >= block_len {
-                     return Err(Error::YubiKeyError(
-                        "Decryption error: PKCS#1 v1.5 unpadding resulted in an empty message.".to_string(),
-                    ));
-                }
-                Ok(padded_data[idx + 1..].to_vec())
+        Err(failure.unwrap_or_else(|| {
+            Error::YubiKeyError(format!("No key on the YubiKey matches key id {}", key_id.unwrap_or_default()))
+        }))
+    }
+
+    // Decrypts with the key in `slot`, the PIN is verified
+    fn decrypt_in_slot(&self, slot: piv::SlotId, algorithm: piv::AlgorithmId, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let key_size = match algorithm {
+            piv::AlgorithmId::Rsa1024 => 128,
+            piv::AlgorithmId::Rsa2048 => 256,
+            piv::AlgorithmId::EccP256 | piv::AlgorithmId::EccP384 => {
+                // The card does the ECDH step, HKDF and AES-GCM happen in software
+                return Ok(ecc::decrypt_with(ciphertext, algorithm.try_into()?, |ephemeral_public_key| {
+                    let shared_secret = self.card.borrow_mut().decrypt(ephemeral_public_key, algorithm, slot)
+                        .map_err(|e| encrypt::Error::YubiKey(e.to_string()))?;
+                    Ok(shared_secret.to_vec())
+                })?);
             }
-            None => Err(Error::YubiKeyError(
-                "Decryption error: PKCS#1 v1.5 padding separator 0x00 not found after PS.".to_string(),
-            )),
+        };
+
+        // Only the wrapped content key goes through the card
+        Ok(hybrid::open(ciphertext, key_size, |wrapped, padding| {
+            self.decrypt_block(slot, algorithm, key_size, wrapped, padding)
+        })?)
+    }
+
+    // Raw RSA decryption of one block on the card, unpadded in software
+    fn decrypt_block(
+        &self,
+        slot: piv::SlotId,
+        algorithm: piv::AlgorithmId,
+        key_size: usize,
+        raw_ciphertext: &[u8],
+        padding: Padding,
+    ) -> encrypt::Result<Vec<u8>> {
+        let block = self.card.borrow_mut().decrypt(raw_ciphertext, algorithm, slot)
+            .map_err(|e| encrypt::Error::YubiKey(e.to_string()))?;
+        match padding {
+            Padding::Oaep => padding::oaep_sha256_unpad(&block, key_size),
+            Padding::Pkcs1v15 => padding::pkcs1v15_unpad(&block, key_size),
         }
     }
 
     pub fn generate_authentication_challenge(&mut self) -> Result<String> {
-        let slot = piv::SlotId::Authentication;
+        let slot = self.auth_slot;
         
         // Use the stored authentication algorithm if available
         let alg_id = self.authentication.ok_or_else(|| {
@@ -311,10 +561,6 @@ impl YubiKeyDevice {
         let challenge_len = match alg_id {
             piv::AlgorithmId::EccP384 => 48,
             piv::AlgorithmId::Rsa1024 | piv::AlgorithmId::Rsa2048 | piv::AlgorithmId::EccP256 => 32,
-            _ => return Err(Error::YubiKeyError(format!(
-                "Unsupported asymmetric algorithm {:?} in authentication slot {:?} for challenge generation.",
-                alg_id, slot
-            ))),
         };
 
         let mut rng = rand::thread_rng();
@@ -329,10 +575,9 @@ impl YubiKeyDevice {
         pin: String,
         challenge_base64: &str,
     ) -> Result<String> {
-        self.yk.verify_pin(pin.as_bytes())
-            .map_err(|e| Error::YubiKeyError(format!("PIN verification failed: {}", e)))?;
+        self.card.get_mut().verify_pin(pin.as_bytes())?;
 
-        let slot = piv::SlotId::Authentication; // Slot 9A
+        let slot = self.auth_slot;
 
         let alg_id = self.authentication.ok_or_else(|| {
             Error::YubiKeyError(format!(
@@ -369,7 +614,14 @@ impl YubiKeyDevice {
             }
         }
 
-        let signature_bytes = piv::sign_data(&mut self.yk, &challenge_bytes, alg_id, slot)
+        // RSA keys sign raw blocks, the challenge is signed as a SHA-256 digest
+        let input = match alg_id {
+            piv::AlgorithmId::Rsa1024 => padding::pkcs1v15_sign_pad(&challenge_bytes, 128)?,
+            piv::AlgorithmId::Rsa2048 => padding::pkcs1v15_sign_pad(&challenge_bytes, 256)?,
+            piv::AlgorithmId::EccP256 | piv::AlgorithmId::EccP384 => challenge_bytes,
+        };
+
+        let signature_bytes = self.card.get_mut().sign(&input, alg_id, slot)
             .map_err(|e| Error::YubiKeyError(format!("Failed to sign challenge with algorithm {:?} in slot {:?}: {}", alg_id, slot, e)))?;
 
         if signature_bytes.is_empty() {
@@ -379,23 +631,35 @@ impl YubiKeyDevice {
     }
 }
 
+impl Encrypt for YubiKeyDevice {
+    fn encrypt_u8(&self, data: &[u8]) -> encrypt::Result<Vec<u8>> {
+        self.encrypt_bytes(data)
+            .map_err(|e| encrypt::Error::YubiKey(e.to_string()))
+    }
 
+    fn decrypt_u8(&self, data: &[u8]) -> encrypt::Result<Vec<u8>> {
+        let pin = self
+            .pin
+            .as_deref()
+            .ok_or(encrypt::Error::YubiKey("A PIN is required to decrypt with the YubiKey".to_string()))?;
+        Ok(self.decrypt_bytes(pin, data)?)
+    }
+}
 
 #[cfg(test)]
 mod test {
 
     use super::*;
-    use serial_test::serial;
 
-    fn get_device() -> YubiKeyDevice {
-        let yubikey_serial = 32233649; // Standard test serial
-        YubiKeyDevice::open(yubikey_serial).unwrap()
+    fn virtual_device() -> YubiKeyDevice {
+        let backend = VirtualBackend::default();
+        backend.insert(VirtualCard::from_fixtures(AppState::TEST_YUBIKEY_SERIAL, "tests/fixtures/piv").unwrap());
+        YubiKeyDevice::open(&backend, AppState::TEST_YUBIKEY_SERIAL).unwrap()
     }
 
     #[test]
-    #[serial]
     fn test_encrypt_with_yubikey() { // This test was actually for do_encrypt
-        let mut device = get_device();
+        let mut device = virtual_device();
         // Using the free function which now wraps YubiKeyDevice
         let result = device.encrypt_data("data".as_bytes().to_vec());
         assert!(result.is_ok(), "do_encrypt failed: {:?}", result.err());
@@ -403,11 +667,9 @@ mod test {
     }
 
     #[test]
-    #[serial]
     fn test_authenticate_with_yubikey() {
-        let yubikey_serial = 32233649;
         let pin = "123456".to_string();
-        let mut device = YubiKeyDevice::open(yubikey_serial).unwrap(); // Ensure the YubiKey is open
+        let mut device = virtual_device();
         let challenge = device.generate_authentication_challenge().unwrap();
         
         // Using the free function
@@ -419,9 +681,8 @@ mod test {
     }
 
     #[test]
-    #[serial]
     fn test_decrypt_data() {
-        let mut device = get_device();
+        let mut device = virtual_device();
         let pin = "123456".to_string();
         let original_data_str = "This is a secret test message for YubiKey!";
         let original_data_bytes = original_data_str.as_bytes().to_vec();
@@ -464,9 +725,16 @@ mod test {
     }
 
     #[test]
-    #[serial]
+    fn test_encrypt_trait_round_trip() {
+        let device = virtual_device().with_pin(provision::DEFAULT_PIN);
+        let cipher: &dyn Encrypt = &device;
+        let encrypted = cipher.encrypt("trait secret").unwrap();
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "trait secret");
+    }
+
+    #[test]
     fn test_get_public_key_success() {
-        let mut device = get_device();
+        let mut device = virtual_device();
 
         let result = device.get_public_key();
         assert!(result.is_ok(), "get_public_key failed: {:?}", result.err());
@@ -493,9 +761,8 @@ mod test {
     }
 
     #[test]
-    #[serial]
     fn test_yubikey_device_authenticate_success() {
-        let mut device = get_device();
+        let mut device = virtual_device();
         let pin = "123456".to_string();    // Standard test PIN
         let challenge = device.generate_authentication_challenge().unwrap();
 
@@ -507,32 +774,23 @@ mod test {
     }
 
     #[test]
-    #[serial]
     fn test_yubikey_device_authenticate_incorrect_pin() {
-        let mut device = get_device();
+        let mut device = virtual_device();
         let incorrect_pin = "000000".to_string(); // An incorrect PIN
         let challenge = device.generate_authentication_challenge().unwrap();
 
         let signature_result = device.authenticate(incorrect_pin, &challenge);
         
         assert!(signature_result.is_err(), "YubiKeyDevice.authenticate should fail with an incorrect PIN");
-        if let Err(Error::YubiKeyError(msg)) = signature_result {
-            assert!(msg.starts_with("PIN verification failed:"), "Error message mismatch for incorrect PIN. Got: {}", msg);
-        } else {
-            panic!("Expected YubiKeyError for incorrect PIN, got {:?}", signature_result);
-        }
+        assert!(matches!(signature_result, Err(Error::WrongPin(_))), "Expected WrongPin for incorrect PIN, got {:?}", signature_result);
     }
 
     #[test]
-    #[serial]
     fn test_yubikey_device_authenticate_malformed_challenge() {
-        let yubikey_serial = 32233649;
         let pin = "123456".to_string();
         let malformed_challenge = "this is not valid base64 characters !!!"; // Malformed challenge
 
-        let device_result = YubiKeyDevice::open(yubikey_serial);
-        assert!(device_result.is_ok(), "Failed to open YubiKeyDevice for testing: {:?}", device_result.err());
-        let mut device = device_result.unwrap();
+        let mut device = virtual_device();
 
         let signature_result = device.authenticate(pin, malformed_challenge);
         
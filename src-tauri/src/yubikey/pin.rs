@@ -0,0 +1,108 @@
+use super::PivBackend;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Tries left on a card, shown before the user types a PIN.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PinStatus {
+    pub serial: u32,
+    pub pin_retries: u8,
+    /// None when the card does not report it.
+    pub puk_retries: Option<u8>,
+}
+
+impl PinStatus {
+    pub fn is_blocked(&self) -> bool {
+        self.pin_retries == 0
+    }
+}
+
+pub fn pin_status(backend: &dyn PivBackend, serial: u32) -> Result<PinStatus> {
+    let mut card = backend.open(serial)?;
+    Ok(PinStatus {
+        serial,
+        pin_retries: card.pin_retries()?,
+        puk_retries: card.puk_retries()?,
+    })
+}
+
+pub fn change_pin(backend: &dyn PivBackend, serial: u32, current_pin: &str, new_pin: &str) -> Result<()> {
+    validate("PIN", new_pin)?;
+    backend.open(serial)?.change_pin(current_pin.as_bytes(), new_pin.as_bytes())
+}
+
+/// Sets `new_pin` with the PUK, which also resets the PIN retry counter.
+/// A wrong PUK is reported as `Error::WrongPuk`.
+pub fn unblock_pin(backend: &dyn PivBackend, serial: u32, puk: &str, new_pin: &str) -> Result<()> {
+    validate("PIN", new_pin)?;
+    backend
+        .open(serial)?
+        .unblock_pin(puk.as_bytes(), new_pin.as_bytes())
+        .map_err(|e| match e {
+            Error::WrongPin(tries) => Error::WrongPuk(tries),
+            Error::PinBlocked(_) => Error::PinBlocked("The PUK is blocked, the PIV application must be reset".to_string()),
+            e => e,
+        })
+}
+
+// PIV PINs and PUKs are 6 to 8 characters
+fn validate(name: &str, value: &str) -> Result<()> {
+    if !(6..=8).contains(&value.len()) || !value.is_ascii() {
+        return Err(Error::Validation(format!("The {} must be 6 to 8 characters", name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yubikey::{VirtualBackend, VirtualCard, YubiKeyDevice};
+    use crate::yubikey::virtual_card::{DEFAULT_PIN, DEFAULT_PUK, PIN_RETRIES, PUK_RETRIES};
+
+    const SERIAL: u32 = 40_000_004;
+
+    fn backend() -> VirtualBackend {
+        let backend = VirtualBackend::default();
+        backend.insert(VirtualCard::from_fixtures(SERIAL, "tests/fixtures/piv").unwrap());
+        backend
+    }
+
+    #[test]
+    fn test_wrong_pin_counts_down_to_blocked() {
+        let backend = backend();
+        let mut device = YubiKeyDevice::open(&backend, SERIAL).unwrap();
+        let challenge = device.generate_authentication_challenge().unwrap();
+        for tries in (0..PIN_RETRIES).rev() {
+            let result = device.authenticate("000000".to_string(), &challenge);
+            assert!(matches!(result, Err(Error::WrongPin(left)) if left == tries));
+            assert_eq!(pin_status(&backend, SERIAL).unwrap().pin_retries, tries);
+        }
+        assert!(pin_status(&backend, SERIAL).unwrap().is_blocked());
+        assert!(matches!(device.authenticate(DEFAULT_PIN.to_string(), &challenge), Err(Error::PinBlocked(_))));
+    }
+
+    #[test]
+    fn test_unblock_with_puk() {
+        let backend = backend();
+        for _ in 0..PIN_RETRIES {
+            assert!(backend.open(SERIAL).unwrap().verify_pin(b"000000").is_err());
+        }
+        assert!(matches!(unblock_pin(&backend, SERIAL, "00000000", "654321"), Err(Error::WrongPuk(2))));
+        assert_eq!(pin_status(&backend, SERIAL).unwrap().puk_retries, Some(PUK_RETRIES - 1));
+        assert!(matches!(unblock_pin(&backend, SERIAL, DEFAULT_PUK, "123"), Err(Error::Validation(_))));
+
+        unblock_pin(&backend, SERIAL, DEFAULT_PUK, "654321").unwrap();
+        let status = pin_status(&backend, SERIAL).unwrap();
+        assert_eq!((status.pin_retries, status.puk_retries), (PIN_RETRIES, Some(PUK_RETRIES)));
+        backend.open(SERIAL).unwrap().verify_pin(b"654321").unwrap();
+    }
+
+    #[test]
+    fn test_change_pin() {
+        let backend = backend();
+        assert!(matches!(change_pin(&backend, SERIAL, "000000", "654321"), Err(Error::WrongPin(2))));
+        change_pin(&backend, SERIAL, DEFAULT_PIN, "654321").unwrap();
+        assert!(matches!(backend.open(SERIAL).unwrap().verify_pin(DEFAULT_PIN.as_bytes()), Err(Error::WrongPin(_))));
+        backend.open(SERIAL).unwrap().verify_pin(b"654321").unwrap();
+    }
+}
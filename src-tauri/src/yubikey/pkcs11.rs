@@ -0,0 +1,590 @@
+// PKCS#11 tokens (smartcards, Nitrokeys, TPM-backed tokens, SoftHSM) used like
+// YubiKeys, for the same enrollment and unwrap flow. A token key stands for the
+// PIV slot written in its CKA_ID: the key management key has id 9d and the
+// authentication key id 9a, e.g. `pkcs11-tool --keypairgen --id 9d`. The PIN is
+// the user PIN and the PUK the security officer PIN.
+use super::attestation::TrustAnchors;
+use super::backend::{PivBackend, PivCard};
+use crate::error::{Error, Result};
+use pkcs11::errors::Error as Pkcs11Error;
+use pkcs11::types::*;
+use pkcs11::Ctx;
+use rsa::pkcs8::{EncodePublicKey, LineEnding};
+use rsa::{BigUint, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use x509_cert::der::asn1::OctetString;
+use x509_cert::der::{Decode, EncodePem};
+use x509_cert::Certificate;
+use yubikey::piv::{AlgorithmId, SlotId};
+
+/// Environment variable listing the PKCS#11 modules to load, separated like `PATH`.
+pub const MODULE_PATH_VAR: &str = "PKCS11_MODULE_PATH";
+
+// DER encoded CKA_EC_PARAMS of the supported curves
+const P256_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384_PARAMS: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// The tokens of one PKCS#11 module.
+#[derive(Clone)]
+pub struct Pkcs11Backend {
+    ctx: Arc<Ctx>,
+}
+
+impl Pkcs11Backend {
+    pub fn load(module: impl AsRef<Path>) -> Result<Self> {
+        let module = module.as_ref();
+        let ctx = Ctx::new_and_initialize(module).map_err(|e| {
+            Error::YubiKeyError(format!("Failed to load PKCS#11 module {}: {}", module.display(), e))
+        })?;
+        Ok(Self { ctx: Arc::new(ctx) })
+    }
+
+    /// Loads every module in `PKCS11_MODULE_PATH`, skipping the ones that fail.
+    pub fn from_env() -> Vec<Self> {
+        std::env::var_os(MODULE_PATH_VAR)
+            .map(|paths| std::env::split_paths(&paths).filter_map(|path| Self::load(path).ok()).collect())
+            .unwrap_or_default()
+    }
+
+    fn slots(&self) -> Result<Vec<CK_SLOT_ID>> {
+        self.ctx.get_slot_list(true).map_err(token_error)
+    }
+}
+
+impl PivBackend for Pkcs11Backend {
+    fn list(&self) -> Result<Vec<Box<dyn PivCard>>> {
+        self.slots()?
+            .into_iter()
+            .map(|slot| Token::open(self.ctx.clone(), slot).map(|token| Box::new(token) as Box<dyn PivCard>))
+            .collect()
+    }
+
+    fn open(&self, serial: u32) -> Result<Box<dyn PivCard>> {
+        for slot in self.slots()? {
+            let info = self.ctx.get_token_info(slot).map_err(token_error)?;
+            if token_serial(&String::from(info.serialNumber)) == serial {
+                return Ok(Box::new(Token::open(self.ctx.clone(), slot)?));
+            }
+        }
+        Err(Error::YubiKeyError(format!("Failed to open token {}: {}", serial, yubikey::Error::NotFound)))
+    }
+
+    // Tokens have no attestation, see `PivCard::resident_key`
+    fn attestation_roots(&self) -> Result<TrustAnchors> {
+        Ok(TrustAnchors::default())
+    }
+}
+
+// Enrolled keys are found by a u32 serial: decimal token serials are used as
+// is, others are hashed
+fn token_serial(serial: &str) -> u32 {
+    let serial = serial.trim();
+    serial.parse().unwrap_or_else(|_| {
+        let digest = Sha256::digest(serial.as_bytes());
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    })
+}
+
+fn token_error(e: Pkcs11Error) -> Error {
+    Error::YubiKeyError(e.to_string())
+}
+
+/// An open session on a token.
+pub struct Token {
+    ctx: Arc<Ctx>,
+    slot: CK_SLOT_ID,
+    session: CK_SESSION_HANDLE,
+    serial: u32,
+    label: String,
+    version: String,
+}
+
+impl Token {
+    fn open(ctx: Arc<Ctx>, slot: CK_SLOT_ID) -> Result<Self> {
+        let info = ctx.get_token_info(slot).map_err(token_error)?;
+        let session = ctx
+            .open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None)
+            .map_err(token_error)?;
+        Ok(Self {
+            ctx,
+            slot,
+            session,
+            serial: token_serial(&String::from(info.serialNumber)),
+            label: String::from(info.label).trim().to_string(),
+            version: format!("{}.{}", info.firmwareVersion.major, info.firmwareVersion.minor),
+        })
+    }
+
+    fn flags(&self) -> Result<CK_FLAGS> {
+        Ok(self.ctx.get_token_info(self.slot).map_err(token_error)?.flags)
+    }
+
+    // Logs in as `user` after dropping an earlier login, the token keeps one
+    // login for every session of the app
+    fn login(&mut self, user: CK_USER_TYPE, pin: &[u8]) -> Result<()> {
+        let _ = self.ctx.logout(self.session);
+        match self.ctx.login_with_raw(self.session, user, Some(pin)) {
+            Ok(()) => Ok(()),
+            Err(Pkcs11Error::Pkcs11(CKR_PIN_INCORRECT)) => {
+                let tries = if user == CKU_SO { self.puk_retries()? } else { self.pin_retries().ok() };
+                Err(tries.map_or(Error::YubiKeyError("Wrong PIN".to_string()), Error::WrongPin))
+            }
+            Err(Pkcs11Error::Pkcs11(CKR_PIN_LOCKED)) => Err(yubikey::Error::PinLocked.into()),
+            Err(e) => Err(token_error(e)),
+        }
+    }
+
+    fn find(&self, class: CK_OBJECT_CLASS, slot: SlotId) -> Result<Option<CK_OBJECT_HANDLE>> {
+        let id = [u8::from(slot)];
+        let template = vec![
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+        ];
+        self.ctx.find_objects_init(self.session, &template).map_err(token_error)?;
+        let found = self.ctx.find_objects(self.session, 1);
+        self.ctx.find_objects_final(self.session).map_err(token_error)?;
+        Ok(found.map_err(token_error)?.first().copied())
+    }
+
+    fn private_key(&self, slot: SlotId) -> Result<CK_OBJECT_HANDLE> {
+        self.find(CKO_PRIVATE_KEY, slot)?.ok_or(yubikey::Error::NotFound.into())
+    }
+
+    fn attribute(&self, object: CK_OBJECT_HANDLE, kind: CK_ATTRIBUTE_TYPE) -> Result<Vec<u8>> {
+        let mut template = vec![CK_ATTRIBUTE::new(kind)];
+        self.ctx.get_attribute_value(self.session, object, &mut template).map_err(token_error)?;
+        let len = template[0].ulValueLen;
+        if len == CK_UNAVAILABLE_INFORMATION {
+            return Err(token_error(Pkcs11Error::UnavailableInformation));
+        }
+        let mut value = vec![0u8; len as usize];
+        let mut attribute = CK_ATTRIBUTE::new(kind);
+        attribute.pValue = value.as_mut_ptr() as CK_VOID_PTR;
+        attribute.ulValueLen = len;
+        let mut template = vec![attribute];
+        self.ctx.get_attribute_value(self.session, object, &mut template).map_err(token_error)?;
+        Ok(value)
+    }
+
+    fn flag(&self, object: CK_OBJECT_HANDLE, kind: CK_ATTRIBUTE_TYPE) -> bool {
+        self.attribute(object, kind).is_ok_and(|value| value.first().is_some_and(|b| *b != 0))
+    }
+
+    fn algorithm(&self, object: CK_OBJECT_HANDLE) -> Result<Option<AlgorithmId>> {
+        let key_type = self.attribute(object, CKA_KEY_TYPE)?;
+        let key_type = CK_KEY_TYPE::from_ne_bytes(key_type.try_into().map_err(|_| yubikey::Error::ParseError)?);
+        Ok(match key_type {
+            CKK_RSA => match self.attribute(object, CKA_MODULUS)?.len() {
+                128 => Some(AlgorithmId::Rsa1024),
+                256 => Some(AlgorithmId::Rsa2048),
+                _ => None,
+            },
+            CKK_EC => match self.attribute(object, CKA_EC_PARAMS)?.as_slice() {
+                P256_PARAMS => Some(AlgorithmId::EccP256),
+                P384_PARAMS => Some(AlgorithmId::EccP384),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    fn public_key_pem(&self, object: CK_OBJECT_HANDLE) -> Result<String> {
+        let pem = match self.algorithm(object)? {
+            Some(AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048) => {
+                let modulus = BigUint::from_bytes_be(&self.attribute(object, CKA_MODULUS)?);
+                let exponent = BigUint::from_bytes_be(&self.attribute(object, CKA_PUBLIC_EXPONENT)?);
+                RsaPublicKey::new(modulus, exponent)
+                    .map_err(|e| Error::YubiKeyError(e.to_string()))?
+                    .to_public_key_pem(LineEnding::LF)
+            }
+            Some(AlgorithmId::EccP256) => p256::PublicKey::from_sec1_bytes(&ec_point(self.attribute(object, CKA_EC_POINT)?))
+                .map_err(|_| Error::from(yubikey::Error::ParseError))?
+                .to_public_key_pem(LineEnding::LF),
+            Some(AlgorithmId::EccP384) => p384::PublicKey::from_sec1_bytes(&ec_point(self.attribute(object, CKA_EC_POINT)?))
+                .map_err(|_| Error::from(yubikey::Error::ParseError))?
+                .to_public_key_pem(LineEnding::LF),
+            None => return Err(yubikey::Error::AlgorithmError.into()),
+        };
+        pem.map_err(|e| Error::YubiKeyError(format!("Failed to encode public key to PEM: {}", e)))
+    }
+
+    fn key(&self, slot: SlotId, algorithm: AlgorithmId) -> Result<CK_OBJECT_HANDLE> {
+        let key = self.private_key(slot)?;
+        if self.algorithm(key)? != Some(algorithm) {
+            return Err(yubikey::Error::AlgorithmError.into());
+        }
+        Ok(key)
+    }
+
+    // ECDH with the key in `key`, the shared secret is read back from a
+    // session object that is destroyed right after
+    fn derive(&self, key: CK_OBJECT_HANDLE, point: &[u8], len: CK_ULONG) -> Result<Vec<u8>> {
+        let mut point = point.to_vec();
+        let mut params = CK_ECDH1_DERIVE_PARAMS {
+            kdf: CKD_NULL,
+            ulSharedDataLen: 0,
+            pSharedData: std::ptr::null_mut(),
+            ulPublicDataLen: point.len() as CK_ULONG,
+            pPublicData: point.as_mut_ptr(),
+        };
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_ECDH1_DERIVE,
+            pParameter: &mut params as *mut CK_ECDH1_DERIVE_PARAMS as CK_VOID_PTR,
+            ulParameterLen: std::mem::size_of::<CK_ECDH1_DERIVE_PARAMS>() as CK_ULONG,
+        };
+        let (class, key_type) = (CKO_SECRET_KEY, CKK_GENERIC_SECRET);
+        let template = vec![
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+            CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&len),
+        ];
+        let secret = self.ctx.derive_key(self.session, &mechanism, key, &template).map_err(token_error)?;
+        let value = self.attribute(secret, CKA_VALUE);
+        let _ = self.ctx.destroy_object(self.session, secret);
+        value
+    }
+
+    fn not_supported<T>() -> Result<T> {
+        Err(yubikey::Error::NotSupported.into())
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        let _ = self.ctx.close_session(self.session);
+    }
+}
+
+// CKA_EC_POINT is a DER OCTET STRING holding the point, some tokens leave out the wrapping
+fn ec_point(value: Vec<u8>) -> Vec<u8> {
+    match OctetString::from_der(&value) {
+        Ok(point) if matches!(point.as_bytes(), [0x04, rest @ ..] if rest.len() == 64 || rest.len() == 96) => {
+            point.as_bytes().to_vec()
+        }
+        _ => value,
+    }
+}
+
+fn mechanism(kind: CK_MECHANISM_TYPE) -> CK_MECHANISM {
+    CK_MECHANISM {
+        mechanism: kind,
+        pParameter: std::ptr::null_mut(),
+        ulParameterLen: 0,
+    }
+}
+
+fn pin_str(pin: &[u8]) -> Result<&str> {
+    std::str::from_utf8(pin).map_err(|_| Error::Validation("The PIN must be text".to_string()))
+}
+
+impl PivCard for Token {
+    fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    fn name(&self) -> String {
+        self.label.clone()
+    }
+
+    fn version(&self) -> String {
+        self.version.clone()
+    }
+
+    fn metadata(&mut self, slot: SlotId) -> Result<Option<AlgorithmId>> {
+        // Private keys are hidden before the login, public keys are not
+        match self.find(CKO_PUBLIC_KEY, slot)?.or(self.find(CKO_PRIVATE_KEY, slot)?) {
+            Some(key) => self.algorithm(key),
+            None => Ok(None),
+        }
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<String> {
+        if let Some(certificate) = self.find(CKO_CERTIFICATE, slot)? {
+            let certificate = Certificate::from_der(&self.attribute(certificate, CKA_VALUE)?)
+                .map_err(|e| Error::YubiKeyError(format!("Failed to get certificate from slot {:?}: {}", slot, e)))?;
+            return certificate
+                .tbs_certificate
+                .subject_public_key_info
+                .to_pem(LineEnding::LF)
+                .map_err(|e| Error::YubiKeyError(format!("Failed to encode public key to PEM: {}", e)));
+        }
+        match self.find(CKO_PUBLIC_KEY, slot)? {
+            Some(key) => self.public_key_pem(key),
+            None => Err(Error::YubiKeyError(format!(
+                "Failed to get certificate from slot {:?}: {}",
+                slot,
+                yubikey::Error::NotFound
+            ))),
+        }
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
+        self.login(CKU_USER, pin)
+    }
+
+    fn decrypt(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>> {
+        let key = self.key(slot, algorithm)?;
+        match algorithm {
+            AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => {
+                self.ctx.decrypt_init(self.session, &mechanism(CKM_RSA_X_509), key).map_err(token_error)?;
+                self.ctx.decrypt(self.session, input).map_err(token_error)
+            }
+            AlgorithmId::EccP256 => self.derive(key, input, 32),
+            AlgorithmId::EccP384 => self.derive(key, input, 48),
+        }
+    }
+
+    fn sign(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>> {
+        let key = self.key(slot, algorithm)?;
+        let kind = match algorithm {
+            AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => CKM_RSA_X_509,
+            AlgorithmId::EccP256 | AlgorithmId::EccP384 => CKM_ECDSA,
+        };
+        self.ctx.sign_init(self.session, &mechanism(kind), key).map_err(token_error)?;
+        let signature = self.ctx.sign(self.session, input).map_err(token_error)?;
+        // Tokens return r and s side by side, YubiKeys a DER sequence
+        let invalid = |_| Error::from(yubikey::Error::ParseError);
+        match algorithm {
+            AlgorithmId::EccP256 => Ok(p256::ecdsa::Signature::from_slice(&signature).map_err(invalid)?.to_der().as_bytes().to_vec()),
+            AlgorithmId::EccP384 => Ok(p384::ecdsa::Signature::from_slice(&signature).map_err(invalid)?.to_der().as_bytes().to_vec()),
+            _ => Ok(signature),
+        }
+    }
+
+    // Provisioning is left to the token's own tools
+    fn authenticate_management(&mut self, _key: &[u8]) -> Result<()> {
+        Self::not_supported()
+    }
+
+    fn generate(&mut self, _slot: SlotId, _algorithm: AlgorithmId) -> Result<String> {
+        Self::not_supported()
+    }
+
+    fn write_self_signed_certificate(
+        &mut self,
+        _slot: SlotId,
+        _algorithm: AlgorithmId,
+        _public_key: &str,
+        _subject: &str,
+    ) -> Result<()> {
+        Self::not_supported()
+    }
+
+    // Tokens only flag the last tries
+    fn pin_retries(&mut self) -> Result<u8> {
+        let flags = self.flags()?;
+        if flags & CKF_USER_PIN_LOCKED != 0 {
+            Ok(0)
+        } else if flags & CKF_USER_PIN_FINAL_TRY != 0 {
+            Ok(1)
+        } else {
+            Err(Error::YubiKeyError("The token does not tell how many PIN tries are left".to_string()))
+        }
+    }
+
+    fn puk_retries(&mut self) -> Result<Option<u8>> {
+        let flags = self.flags()?;
+        Ok(if flags & CKF_SO_PIN_LOCKED != 0 {
+            Some(0)
+        } else if flags & CKF_SO_PIN_FINAL_TRY != 0 {
+            Some(1)
+        } else {
+            None
+        })
+    }
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()> {
+        self.login(CKU_USER, current_pin)?;
+        let result = self.ctx.set_pin(self.session, Some(pin_str(current_pin)?), Some(pin_str(new_pin)?));
+        let _ = self.ctx.logout(self.session);
+        result.map_err(token_error)
+    }
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()> {
+        self.login(CKU_SO, puk)?;
+        let result = self.ctx.init_pin(self.session, Some(pin_str(new_pin)?));
+        let _ = self.ctx.logout(self.session);
+        result.map_err(token_error)
+    }
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()> {
+        self.login(CKU_SO, current_puk)?;
+        let result = self.ctx.set_pin(self.session, Some(pin_str(current_puk)?), Some(pin_str(new_puk)?));
+        let _ = self.ctx.logout(self.session);
+        result.map_err(token_error)
+    }
+
+    fn set_management_key(&mut self, _new_key: &[u8]) -> Result<()> {
+        Self::not_supported()
+    }
+
+    fn can_move_keys(&self) -> bool {
+        false
+    }
+
+    fn move_key(&mut self, _from: SlotId, _to: SlotId) -> Result<()> {
+        Self::not_supported()
+    }
+
+    fn attest(&mut self, _slot: SlotId) -> Result<Vec<u8>> {
+        Self::not_supported()
+    }
+
+    fn attestation_certificate(&mut self) -> Result<Vec<u8>> {
+        Self::not_supported()
+    }
+
+    // The private key can be hidden until the login, its flags are only read
+    // once it is visible. The public key of a pair generated on the token is
+    // flagged local as well
+    fn resident_key(&mut self, slot: SlotId) -> Result<Option<bool>> {
+        let private_key = self.find(CKO_PRIVATE_KEY, slot)?.ok_or(Error::Attestation(format!(
+            "The private key in slot {:?} is hidden, enter the token PIN first",
+            slot
+        )))?;
+        let local = self.find(CKO_PUBLIC_KEY, slot)?.is_some_and(|key| self.flag(key, CKA_LOCAL));
+        Ok(Some(local && self.flag(private_key, CKA_NEVER_EXTRACTABLE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::Encrypt;
+    use crate::yubikey::{list_yubikeys, YubiKeyDevice};
+    use serial_test::serial;
+    use std::path::PathBuf;
+
+    const PIN: &str = "654321";
+    const SO_PIN: &str = "87654321";
+
+    #[test]
+    fn test_token_serial() {
+        assert_eq!(token_serial("12345678        "), 12_345_678);
+        let hashed = token_serial("a3f1c2d4e5b60718");
+        assert_eq!(hashed, token_serial("a3f1c2d4e5b60718  "));
+        assert_ne!(hashed, token_serial("a3f1c2d4e5b60719"));
+    }
+
+    #[test]
+    fn test_ec_point() {
+        let mut point = vec![0x04];
+        point.extend([0x3f; 64]);
+        // Raw points pass through, wrapped ones are unwrapped
+        assert_eq!(ec_point(point.clone()), point);
+        let mut wrapped = vec![0x04, 65];
+        wrapped.extend(&point);
+        assert_eq!(ec_point(wrapped), point);
+    }
+
+    // SoftHSM with a fresh token
+    fn softhsm() -> (Pkcs11Backend, tempfile::TempDir) {
+        let module = std::env::var_os("SOFTHSM2_MODULE")
+            .map(PathBuf::from)
+            .or_else(|| {
+                ["/usr/lib/softhsm/libsofthsm2.so", "/usr/lib/x86_64-linux-gnu/softhsm/libsofthsm2.so", "/usr/local/lib/softhsm/libsofthsm2.so"]
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .find(|path| path.exists())
+            })
+            .expect("SoftHSM is not installed, set SOFTHSM2_MODULE");
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = dir.path().join("softhsm2.conf");
+        std::fs::write(&config, format!("directories.tokendir = {}\n", dir.path().display())).unwrap();
+        // SAFETY: the tests using SoftHSM run serially
+        unsafe { std::env::set_var("SOFTHSM2_CONF", &config) };
+        let backend = Pkcs11Backend::load(module).unwrap();
+
+        let ctx = &backend.ctx;
+        let slot = ctx.get_slot_list(false).unwrap()[0];
+        ctx.init_token(slot, Some(SO_PIN), "vault test").unwrap();
+        let session = ctx.open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None).unwrap();
+        ctx.login(session, CKU_SO, Some(SO_PIN)).unwrap();
+        ctx.init_pin(session, Some(PIN)).unwrap();
+        ctx.logout(session).unwrap();
+        ctx.login(session, CKU_USER, Some(PIN)).unwrap();
+
+        // RSA key management key, P-256 authentication key
+        let (yes, bits) = (CK_TRUE, 2048 as CK_ULONG);
+        let exponent = [1u8, 0, 1];
+        let (id_9d, id_9a) = ([0x9du8], [0x9au8]);
+        let private = |id: &[u8]| {
+            vec![
+                CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+            ]
+        };
+        ctx.generate_key_pair(
+            session,
+            &mechanism(CKM_RSA_PKCS_KEY_PAIR_GEN),
+            &[
+                CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_MODULUS_BITS).with_ck_ulong(&bits),
+                CK_ATTRIBUTE::new(CKA_PUBLIC_EXPONENT).with_bytes(&exponent),
+                CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id_9d),
+            ],
+            &private(&id_9d),
+        )
+        .unwrap();
+        ctx.generate_key_pair(
+            session,
+            &mechanism(CKM_EC_KEY_PAIR_GEN),
+            &[
+                CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(P256_PARAMS),
+                CK_ATTRIBUTE::new(CKA_VERIFY).with_bool(&yes),
+                CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id_9a),
+            ],
+            &private(&id_9a),
+        )
+        .unwrap();
+        ctx.close_session(session).unwrap();
+        (backend, dir)
+    }
+
+    #[test]
+    #[serial]
+    #[ignore = "needs SoftHSM, run with --ignored"]
+    fn test_softhsm_unwrap_and_sign() {
+        let (backend, _dir) = softhsm();
+        let mut cards = backend.list().unwrap();
+        let card = cards[0].as_mut();
+        assert_eq!(card.name(), "vault test");
+        assert_eq!(card.metadata(SlotId::KeyManagement).unwrap(), Some(AlgorithmId::Rsa2048));
+        assert_eq!(card.metadata(SlotId::Authentication).unwrap(), Some(AlgorithmId::EccP256));
+        // The private key is only checked once the login shows it
+        assert!(matches!(card.resident_key(SlotId::KeyManagement), Err(Error::Attestation(_))));
+        card.verify_pin(PIN.as_bytes()).unwrap();
+        assert_eq!(card.resident_key(SlotId::KeyManagement).unwrap(), Some(true));
+        let serial = card.serial();
+        drop(cards);
+        // Nothing signs a token's word, so it does not count as generated on the device
+        let info = list_yubikeys(&backend).unwrap().remove(0);
+        assert!(!info.generated_on_device);
+
+        // The same unwrap and challenge flow as a YubiKey
+        let device = YubiKeyDevice::open(&backend, serial).unwrap().with_pin(PIN);
+        let cipher: &dyn Encrypt = &device;
+        let encrypted = cipher.encrypt("token secret").unwrap();
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "token secret");
+        let mut device = YubiKeyDevice::open(&backend, serial).unwrap();
+        let challenge = device.generate_authentication_challenge().unwrap();
+        device.authenticate(PIN.to_string(), &challenge).unwrap();
+
+        let mut card = backend.open(serial).unwrap();
+        assert!(card.verify_pin(b"000000").is_err());
+        card.unblock_pin(SO_PIN.as_bytes(), b"111111").unwrap();
+        card.verify_pin(b"111111").unwrap();
+    }
+}
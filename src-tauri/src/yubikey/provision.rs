@@ -0,0 +1,345 @@
+use super::{attestation, enroll, retired_slots, EnrolledKey, KeyAlgorithm, RetiredKey, YubiKeyDevice, YubiKeySettings};
+use crate::error::{Error, Result};
+use crate::file_system::Transaction;
+use crate::vaults::Vault;
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use yubikey::piv::{AlgorithmId, SlotId};
+
+/// Factory credentials of a YubiKey PIV application.
+pub const DEFAULT_PIN: &str = "123456";
+pub const DEFAULT_PUK: &str = "12345678";
+pub const DEFAULT_MANAGEMENT_KEY: [u8; 24] = [
+    1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8,
+];
+
+/// What to set up on a card: a new key and self-signed certificate in `slot`,
+/// and optionally new credentials. Management keys are hex encoded. The
+/// factory PIN, PUK and management key are used when they are not given.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Provisioning {
+    pub serial: u32,
+    /// 0x9d (key management) or 0x9a (authentication).
+    pub slot: u8,
+    pub algorithm: KeyAlgorithm,
+    pub label: Option<String>,
+    #[serde(default)]
+    pub pin: Option<String>,
+    #[serde(default)]
+    pub new_pin: Option<String>,
+    #[serde(default)]
+    pub puk: Option<String>,
+    #[serde(default)]
+    pub new_puk: Option<String>,
+    #[serde(default)]
+    pub management_key: Option<String>,
+    #[serde(default)]
+    pub new_management_key: Option<String>,
+}
+
+/// Generates a key on the card and writes its self-signed certificate, then
+/// changes the credentials that were asked for. Once the key management slot
+/// holds a key the card is enrolled, which is returned. Provisioning the
+/// authentication slot first lets the enrolled key verify unlock challenges.
+/// Needs an unlocked session, and enrolled cards are not provisioned again.
+pub fn provision(app_state: &AppState, provisioning: &Provisioning) -> Result<Option<EnrolledKey>> {
+    let slot = match SlotId::try_from(provisioning.slot)? {
+        slot @ (SlotId::KeyManagement | SlotId::Authentication) => slot,
+        slot => return Err(Error::YubiKeyError(format!("Can not provision slot {:?}", slot))),
+    };
+    if !app_state.is_authenticated() {
+        return Err(Error::Locked("Unlock the vault before provisioning a YubiKey".to_string()));
+    }
+    if YubiKeySettings::load(app_state)?.find(provisioning.serial).is_some() {
+        // A new key would leave the vault keys wrapped for the old one
+        // unreadable, and unlock challenges signed by a key the anchor does not cover
+        return Err(Error::YubiKeyError(format!(
+            "YubiKey {} is enrolled, remove it before replacing its keys",
+            provisioning.serial
+        )));
+    }
+    let management_key = match &provisioning.management_key {
+        Some(key) => parse_management_key(key)?,
+        None => DEFAULT_MANAGEMENT_KEY.to_vec(),
+    };
+    let new_management_key = provisioning.new_management_key.as_deref().map(parse_management_key).transpose()?;
+    let algorithm = AlgorithmId::from(provisioning.algorithm);
+    let pin = provisioning.pin.as_deref().unwrap_or(DEFAULT_PIN);
+
+    let mut card = app_state.piv().open(provisioning.serial)?;
+    card.authenticate_management(&management_key)?;
+    card.verify_pin(pin.as_bytes())?;
+    let public_key = card.generate(slot, algorithm)?;
+    let subject = provisioning.label.clone().unwrap_or_else(|| format!("YubiKey {}", provisioning.serial));
+    card.write_self_signed_certificate(slot, algorithm, &public_key, &subject)?;
+
+    if let Some(new_pin) = &provisioning.new_pin {
+        card.change_pin(pin.as_bytes(), new_pin.as_bytes())?;
+    }
+    if let Some(new_puk) = &provisioning.new_puk {
+        let puk = provisioning.puk.as_deref().unwrap_or(DEFAULT_PUK);
+        card.change_puk(puk.as_bytes(), new_puk.as_bytes())?;
+    }
+    if let Some(new_management_key) = new_management_key {
+        card.set_management_key(&new_management_key)?;
+    }
+    drop(card);
+
+    match slot {
+        SlotId::KeyManagement => enroll(app_state, provisioning.serial, provisioning.label.clone(), public_key).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Replaces the encryption key of the enrolled YubiKey `serial`. The old key
+/// moves to a free retired slot (82 to 95) and a new one is generated in its
+/// place, vault keys are wrapped again for the new key. Cards that can not move
+/// keys keep the old key where it is and get the new one in the retired slot.
+/// Either way older ciphertext names its key and still opens.
+pub fn rotate(app_state: &AppState, serial: u32, pin: &str, management_key: Option<&str>) -> Result<EnrolledKey> {
+    let mut settings = YubiKeySettings::load(app_state)?;
+    let mut key = settings
+        .find(serial)
+        .cloned()
+        .ok_or(Error::YubiKeyError(format!("YubiKey {} is not enrolled", serial)))?;
+    let management_key = match management_key {
+        Some(key) => parse_management_key(key)?,
+        None => DEFAULT_MANAGEMENT_KEY.to_vec(),
+    };
+
+    // Vault keys are unwrapped with the current key before it moves
+    let device = YubiKeyDevice::open_enrolled(app_state.piv(), &key)?.with_pin(pin);
+    let mut keyrings = vec![];
+    for vault in Vault::all(app_state)? {
+        let keyring = vault.keyring(app_state)?;
+        if keyring.has_yubikey(serial) {
+            let card_copy = keyring.unlock_with_yubikey(serial, &device)?;
+            keyrings.push((vault, keyring, card_copy));
+        }
+    }
+    drop(device);
+
+    let mut card = app_state.piv().open(serial)?;
+    card.authenticate_management(&management_key)?;
+    card.verify_pin(pin.as_bytes())?;
+    let current = SlotId::try_from(key.slot)?;
+    let taken: Vec<u8> = key.retired.iter().map(|retired| retired.slot).collect();
+    let free = retired_slots()
+        .filter(|slot| *slot != current && !taken.contains(&u8::from(*slot)))
+        .find(|slot| matches!(card.metadata(*slot), Ok(None) | Err(_)))
+        .ok_or(Error::YubiKeyError("Every retired slot of the YubiKey holds a key".to_string()))?;
+    let (retired_slot, new_slot) = if card.can_move_keys() {
+        card.move_key(current, free)?;
+        (free, current)
+    } else {
+        (current, free)
+    };
+    let algorithm = AlgorithmId::from(key.algorithm);
+    let public_key = card.generate(new_slot, algorithm)?;
+    card.write_self_signed_certificate(new_slot, algorithm, &public_key, &key.label)?;
+    let roots = app_state.piv().attestation_roots()?;
+    key.attestation = Some(attestation::attest(card.as_mut(), new_slot, &roots, &public_key)?);
+    drop(card);
+
+    key.retired.push(RetiredKey {
+        slot: retired_slot.into(),
+        pub_key: std::mem::replace(&mut key.pub_key, public_key),
+    });
+    key.slot = new_slot.into();
+    settings.add(key.clone());
+
+    let fs = app_state.file_system();
+    let mut transaction = Transaction::new(fs).map_err(|e| Error::Io(e.to_string()))?;
+    for (vault, mut keyring, card_copy) in keyrings {
+        keyring.add_yubikey(&card_copy, serial, &key.pub_key)?;
+        transaction
+            .stage(&fs.vault_keyring(vault.name()), Vault::keyring_json(app_state, &keyring)?)
+            .map_err(|e| Error::Io(e.to_string()))?;
+    }
+    transaction
+        .stage(&fs.yubikey_settings(), settings.to_json(app_state)?)
+        .map_err(|e| Error::Io(e.to_string()))?;
+    transaction.commit().map_err(|e| Error::Io(e.to_string()))?;
+    Ok(key)
+}
+
+/// Parses a 24 byte management key written as 48 hex digits.
+pub fn parse_management_key(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    let invalid = || Error::YubiKeyError("Management key must be 48 hex digits".to_string());
+    if hex.len() != DEFAULT_MANAGEMENT_KEY.len() * 2 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::{ecc::EccAlgorithm, EccPublicKey};
+    use crate::yubikey::{list_yubikeys, VirtualCard, YubiKeyDevice};
+
+    const BLANK: u32 = 30_000_003;
+
+    fn blank_card(state: &AppState) {
+        state.virtual_piv().insert(VirtualCard::new(BLANK));
+    }
+
+    fn provisioning(slot: SlotId, algorithm: KeyAlgorithm) -> Provisioning {
+        Provisioning {
+            serial: BLANK,
+            slot: slot.into(),
+            algorithm,
+            label: Some("Provisioned".to_string()),
+            pin: None,
+            new_pin: None,
+            puk: None,
+            new_puk: None,
+            management_key: None,
+            new_management_key: None,
+        }
+    }
+
+    #[test]
+    fn test_provision_and_enroll() {
+        let state = AppState::new_test("password");
+        blank_card(&state);
+        let found = |state: &AppState| list_yubikeys(state.piv()).unwrap().into_iter().find(|key| key.serial == Some(BLANK)).unwrap();
+        assert!(found(&state).pub_key.is_none());
+
+        let auth = provisioning(SlotId::Authentication, KeyAlgorithm::EccP256);
+        assert!(provision(&state, &auth).unwrap().is_none());
+        let auth_pub_key = found(&state).auth_pub_key.unwrap();
+        assert_eq!(EccPublicKey::from_pem(&auth_pub_key).unwrap().algorithm, EccAlgorithm::P256);
+
+        let mut key_management = provisioning(SlotId::KeyManagement, KeyAlgorithm::EccP384);
+        key_management.new_pin = Some("654321".to_string());
+        key_management.new_puk = Some("87654321".to_string());
+        key_management.new_management_key = Some("00112233445566778899aabbccddeeff0011223344556677".to_string());
+        let enrolled = provision(&state, &key_management).unwrap().unwrap();
+        assert_eq!(enrolled.label, "Provisioned");
+        assert_eq!(enrolled.algorithm, KeyAlgorithm::EccP384);
+        assert_eq!(enrolled.auth_pub_key.as_deref(), Some(auth_pub_key.as_str()));
+        assert_eq!(Some(enrolled.pub_key.clone()), found(&state).pub_key);
+        assert!(YubiKeySettings::load(&state).unwrap().find(BLANK).is_some());
+
+        // The new PIN opens data encrypted for the new key
+        let mut device = YubiKeyDevice::open(state.piv(), BLANK).unwrap();
+        let encrypted = device.encrypt_data(b"provisioned".to_vec()).unwrap();
+        assert!(device.decrypt_data(DEFAULT_PIN.to_string(), encrypted.clone().into_bytes()).is_err());
+        assert_eq!(device.decrypt_data("654321".to_string(), encrypted.into_bytes()).unwrap(), b"provisioned");
+
+        // No key of the enrolled card is replaced, even with its credentials
+        assert!(provision(&state, &key_management).is_err());
+        let mut again = provisioning(SlotId::Authentication, KeyAlgorithm::EccP256);
+        again.pin = Some("654321".to_string());
+        again.management_key = key_management.new_management_key.clone();
+        assert!(provision(&state, &again).is_err());
+        assert_eq!(found(&state).auth_pub_key.as_deref(), Some(auth_pub_key.as_str()));
+    }
+
+    #[test]
+    fn test_provision_needs_unlocked_session() {
+        let mut state = AppState::new_test("password");
+        blank_card(&state);
+        state.log_out();
+        let auth = provisioning(SlotId::Authentication, KeyAlgorithm::EccP256);
+        assert!(matches!(provision(&state, &auth), Err(Error::Locked(_))));
+        assert!(list_yubikeys(state.piv()).unwrap().into_iter().find(|key| key.serial == Some(BLANK)).unwrap().auth_pub_key.is_none());
+    }
+
+    #[test]
+    fn test_provision_checks_credentials() {
+        let state = AppState::new_test("password");
+        blank_card(&state);
+        let mut wrong_pin = provisioning(SlotId::KeyManagement, KeyAlgorithm::EccP256);
+        wrong_pin.pin = Some("000000".to_string());
+        assert!(provision(&state, &wrong_pin).is_err());
+        assert!(state.virtual_piv().pin_retries(BLANK).unwrap() < 3);
+
+        let mut wrong_key = provisioning(SlotId::KeyManagement, KeyAlgorithm::EccP256);
+        wrong_key.management_key = Some("00".repeat(24));
+        assert!(provision(&state, &wrong_key).is_err());
+        assert!(provision(&state, &provisioning(SlotId::Signature, KeyAlgorithm::EccP256)).is_err());
+        assert!(YubiKeySettings::load(&state).unwrap().find(BLANK).is_none());
+    }
+
+    // Card with authentication and encryption keys, enrolled
+    fn provisioned(state: &AppState, card: VirtualCard) -> EnrolledKey {
+        state.virtual_piv().insert(card);
+        provision(state, &provisioning(SlotId::Authentication, KeyAlgorithm::EccP256)).unwrap();
+        provision(state, &provisioning(SlotId::KeyManagement, KeyAlgorithm::EccP256)).unwrap().unwrap()
+    }
+
+    fn unlock_vaults(state: &AppState, key: &EnrolledKey) {
+        let device = YubiKeyDevice::open_enrolled(state.piv(), key).unwrap().with_pin(DEFAULT_PIN);
+        let vaults = Vault::all(state).unwrap();
+        assert!(!vaults.is_empty());
+        for vault in vaults {
+            let keyring = vault.keyring(state).unwrap();
+            assert!(keyring.unlock_with_yubikey(key.serial, &device).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_rotate_moves_old_key_to_retired_slot() {
+        let state = AppState::new_test("password");
+        let key = provisioned(&state, VirtualCard::new(BLANK).with_version(5, 7, 0));
+        let mut device = YubiKeyDevice::open_enrolled(state.piv(), &key).unwrap();
+        let old = device.encrypt_data(b"before rotation".to_vec()).unwrap();
+
+        let rotated = rotate(&state, BLANK, DEFAULT_PIN, None).unwrap();
+        assert_eq!(rotated.slot, 0x9d);
+        assert_ne!(rotated.pub_key, key.pub_key);
+        assert_eq!(rotated.retired, vec![RetiredKey { slot: 0x82, pub_key: key.pub_key.clone() }]);
+        assert_eq!(YubiKeySettings::load(&state).unwrap().find(BLANK), Some(&rotated));
+        unlock_vaults(&state, &rotated);
+
+        let rotated = rotate(&state, BLANK, DEFAULT_PIN, None).unwrap();
+        assert_eq!(rotated.retired.iter().map(|retired| retired.slot).collect::<Vec<_>>(), vec![0x82, 0x83]);
+        let mut device = YubiKeyDevice::open_enrolled(state.piv(), &rotated).unwrap();
+        assert_eq!(device.decrypt_data(DEFAULT_PIN.to_string(), old.into_bytes()).unwrap(), b"before rotation");
+        let new = device.encrypt_data(b"after rotation".to_vec()).unwrap();
+        assert_eq!(device.decrypt_data(DEFAULT_PIN.to_string(), new.into_bytes()).unwrap(), b"after rotation");
+    }
+
+    #[test]
+    fn test_rotate_without_moving_keys() {
+        let state = AppState::new_test("password");
+        let key = provisioned(&state, VirtualCard::new(BLANK));
+        let mut device = YubiKeyDevice::open_enrolled(state.piv(), &key).unwrap();
+        let old = device.encrypt_data(b"before rotation".to_vec()).unwrap();
+
+        let rotated = rotate(&state, BLANK, DEFAULT_PIN, None).unwrap();
+        assert_eq!(rotated.slot, 0x82);
+        assert_eq!(rotated.retired, vec![RetiredKey { slot: 0x9d, pub_key: key.pub_key.clone() }]);
+        unlock_vaults(&state, &rotated);
+        let mut device = YubiKeyDevice::open_enrolled(state.piv(), &rotated).unwrap();
+        assert_eq!(device.decrypt_data(DEFAULT_PIN.to_string(), old.into_bytes()).unwrap(), b"before rotation");
+
+        // Unlocking still signs with the authentication slot
+        let mut state = state;
+        state.log_out();
+        crate::yubikey::unlock(&mut state, DEFAULT_PIN, Some(BLANK)).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_needs_pin_and_enrollment() {
+        let state = AppState::new_test("password");
+        provisioned(&state, VirtualCard::new(BLANK));
+        assert!(rotate(&state, BLANK, "000000", None).is_err());
+        assert!(rotate(&state, BLANK, DEFAULT_PIN, Some(&"00".repeat(24))).is_err());
+        assert!(rotate(&state, 1, DEFAULT_PIN, None).is_err());
+        assert!(YubiKeySettings::load(&state).unwrap().find(BLANK).unwrap().retired.is_empty());
+    }
+
+    #[test]
+    fn test_parse_management_key() {
+        assert_eq!(parse_management_key("010203040506070801020304050607080102030405060708").unwrap(), DEFAULT_MANAGEMENT_KEY);
+        assert!(parse_management_key("0102").is_err());
+        assert!(parse_management_key(&"zz".repeat(24)).is_err());
+    }
+}
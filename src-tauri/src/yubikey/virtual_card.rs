@@ -0,0 +1,818 @@
+// In-process PIV card for tests. It follows the YubiKey closely enough for the
+// app: the same input size checks, a PIN retry counter that blocks the card,
+// and private key operations only after the PIN was verified in the session.
+// Provisioning needs the management key, like on the card. Keys are attested
+// with the fixture chain in tests/fixtures/attestation instead of Yubico's.
+use super::attestation::{TrustAnchors, ECDSA_WITH_SHA256, OID_FIRMWARE, OID_POLICY, OID_SERIAL};
+use super::backend::{PivBackend, PivCard};
+pub use super::provision::{DEFAULT_MANAGEMENT_KEY, DEFAULT_PIN, DEFAULT_PUK};
+use crate::error::{Error, Result};
+use p256::ecdsa::signature::hazmat::PrehashSigner;
+use rsa::pkcs8::{DecodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+use rsa::{BigUint, RsaPrivateKey, RsaPublicKey};
+use rand::rngs::OsRng;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use x509_cert::der::asn1::{BitString, OctetString};
+use x509_cert::der::{DecodePem, Encode};
+use x509_cert::ext::Extension;
+use x509_cert::name::Name;
+use x509_cert::serial_number::SerialNumber;
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+use x509_cert::{Certificate, TbsCertificate, Version};
+use yubikey::piv::{AlgorithmId, SlotId};
+
+pub const PIN_RETRIES: u8 = 3;
+pub const PUK_RETRIES: u8 = 3;
+
+/// Root of the fixture attestation chain, stands in for Yubico's.
+pub const ATTESTATION_ROOT: &str = include_str!("../../tests/fixtures/attestation/root.pem");
+// The attestation key (slot F9) every virtual card shares, and its certificate
+const ATTESTATION_KEY: &str = include_str!("../../tests/fixtures/attestation/f9-key.pem");
+const ATTESTATION_CERTIFICATE: &str = include_str!("../../tests/fixtures/attestation/f9.pem");
+// Policies of keys generated with the defaults of slot 9D: PIN once, no touch
+const ATTESTED_POLICY: [u8; 2] = [2, 1];
+
+enum SlotKey {
+    Rsa(Box<RsaPrivateKey>),
+    P256(p256::SecretKey),
+    P384(p384::SecretKey),
+}
+
+impl SlotKey {
+    fn from_pem(pem: &str) -> Result<Self> {
+        if let Ok(key) = RsaPrivateKey::from_pkcs8_pem(pem) {
+            return Ok(SlotKey::Rsa(Box::new(key)));
+        }
+        if let Ok(key) = p256::SecretKey::from_pkcs8_pem(pem) {
+            return Ok(SlotKey::P256(key));
+        }
+        p384::SecretKey::from_pkcs8_pem(pem)
+            .map(SlotKey::P384)
+            .map_err(|e| Error::YubiKeyError(format!("Unsupported private key: {}", e)))
+    }
+
+    fn generate(algorithm: AlgorithmId) -> Result<Self> {
+        let rsa = |bits| {
+            RsaPrivateKey::new(&mut OsRng, bits)
+                .map(|key| SlotKey::Rsa(Box::new(key)))
+                .map_err(|e| Error::YubiKeyError(e.to_string()))
+        };
+        match algorithm {
+            AlgorithmId::Rsa1024 => rsa(1024),
+            AlgorithmId::Rsa2048 => rsa(2048),
+            AlgorithmId::EccP256 => Ok(SlotKey::P256(p256::SecretKey::random(&mut OsRng))),
+            AlgorithmId::EccP384 => Ok(SlotKey::P384(p384::SecretKey::random(&mut OsRng))),
+        }
+    }
+
+    fn algorithm(&self) -> AlgorithmId {
+        match self {
+            SlotKey::Rsa(key) if key.size() == 128 => AlgorithmId::Rsa1024,
+            SlotKey::Rsa(_) => AlgorithmId::Rsa2048,
+            SlotKey::P256(_) => AlgorithmId::EccP256,
+            SlotKey::P384(_) => AlgorithmId::EccP384,
+        }
+    }
+
+    fn public_key_pem(&self) -> Result<String> {
+        match self {
+            SlotKey::Rsa(key) => RsaPublicKey::from(key.as_ref()).to_public_key_pem(LineEnding::LF),
+            SlotKey::P256(key) => key.public_key().to_public_key_pem(LineEnding::LF),
+            SlotKey::P384(key) => key.public_key().to_public_key_pem(LineEnding::LF),
+        }
+        .map_err(|e| Error::YubiKeyError(e.to_string()))
+    }
+
+    // The RSA primitive on a full block, like the card it does no padding
+    fn rsa_raw(key: &RsaPrivateKey, input: &[u8]) -> Result<Vec<u8>> {
+        if input.len() != key.size() {
+            return Err(yubikey::Error::SizeError.into());
+        }
+        let c = BigUint::from_bytes_be(input);
+        if &c >= key.n() {
+            return Err(yubikey::Error::GenericError.into());
+        }
+        let m = c.modpow(key.d(), key.n()).to_bytes_be();
+        let mut block = vec![0u8; key.size() - m.len()];
+        block.extend_from_slice(&m);
+        Ok(block)
+    }
+
+    fn decrypt(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let invalid_point = |_| Error::from(yubikey::Error::SizeError);
+        match self {
+            SlotKey::Rsa(key) => Self::rsa_raw(key, input),
+            SlotKey::P256(key) => {
+                let peer = p256::PublicKey::from_sec1_bytes(input).map_err(invalid_point)?;
+                let shared = p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer.as_affine());
+                Ok(shared.raw_secret_bytes().to_vec())
+            }
+            SlotKey::P384(key) => {
+                let peer = p384::PublicKey::from_sec1_bytes(input).map_err(invalid_point)?;
+                let shared = p384::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer.as_affine());
+                Ok(shared.raw_secret_bytes().to_vec())
+            }
+        }
+    }
+
+    fn sign(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let sign_error = |_| Error::from(yubikey::Error::SizeError);
+        match self {
+            SlotKey::Rsa(key) => Self::rsa_raw(key, input),
+            SlotKey::P256(key) => {
+                let signature: p256::ecdsa::Signature = p256::ecdsa::SigningKey::from(key)
+                    .sign_prehash(input)
+                    .map_err(sign_error)?;
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+            SlotKey::P384(key) => {
+                let signature: p384::ecdsa::Signature = p384::ecdsa::SigningKey::from(key)
+                    .sign_prehash(input)
+                    .map_err(sign_error)?;
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+        }
+    }
+}
+
+/// A simulated YubiKey holding its keys in memory.
+pub struct VirtualCard {
+    serial: u32,
+    version: (u8, u8, u8),
+    pin: String,
+    pin_retries: u8,
+    puk: String,
+    puk_retries: u8,
+    management_key: Vec<u8>,
+    keys: HashMap<SlotId, SlotKey>,
+    // Slots with a certificate, it holds the slot's public key
+    certificates: HashSet<SlotId>,
+    // Slots with a key imported instead of generated on the card
+    imported: HashSet<SlotId>,
+}
+
+impl VirtualCard {
+    pub fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            // Rotates like the hardware backend, which can not move keys
+            version: (5, 4, 3),
+            pin: DEFAULT_PIN.to_string(),
+            pin_retries: PIN_RETRIES,
+            puk: DEFAULT_PUK.to_string(),
+            puk_retries: PUK_RETRIES,
+            management_key: DEFAULT_MANAGEMENT_KEY.to_vec(),
+            keys: HashMap::new(),
+            certificates: HashSet::new(),
+            imported: HashSet::new(),
+        }
+    }
+
+    /// Loads one PKCS#8 private key per slot, named after the slot, e.g. `9d.pem`.
+    pub fn from_fixtures(serial: u32, dir: impl AsRef<Path>) -> Result<Self> {
+        let io = |e: std::io::Error| Error::Io(e.to_string());
+        let mut card = Self::new(serial);
+        for entry in std::fs::read_dir(dir).map_err(io)? {
+            let path = entry.map_err(io)?.path();
+            let Some(slot) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) else {
+                continue;
+            };
+            card = card.with_key(slot, &std::fs::read_to_string(&path).map_err(io)?)?;
+        }
+        Ok(card)
+    }
+
+    /// Puts the PKCS#8 PEM encoded private key in `slot`, with its certificate.
+    /// It passes for a key generated on the card and can be attested.
+    pub fn with_key(mut self, slot: SlotId, pem: &str) -> Result<Self> {
+        self.keys.insert(slot, SlotKey::from_pem(pem)?);
+        self.certificates.insert(slot);
+        Ok(self)
+    }
+
+    /// Like `with_key`, for a key imported into the card, which has no attestation.
+    pub fn with_imported_key(mut self, slot: SlotId, pem: &str) -> Result<Self> {
+        self = self.with_key(slot, pem)?;
+        self.imported.insert(slot);
+        Ok(self)
+    }
+
+    /// Firmware version, 5.4.3 by default. Keys can only be moved from 5.7 on.
+    pub fn with_version(mut self, major: u8, minor: u8, patch: u8) -> Self {
+        self.version = (major, minor, patch);
+        self
+    }
+
+    // Same counter rules as the PIN, the card reports a wrong PUK as a wrong PIN
+    fn check_puk(&mut self, puk: &[u8]) -> Result<()> {
+        if self.puk_retries == 0 {
+            return Err(yubikey::Error::PinLocked.into());
+        }
+        if puk != self.puk.as_bytes() {
+            self.puk_retries -= 1;
+            return Err(yubikey::Error::WrongPin { tries: self.puk_retries }.into());
+        }
+        self.puk_retries = PUK_RETRIES;
+        Ok(())
+    }
+
+    // Certificate for the key in `slot` signed by the attestation key, with the
+    // extensions a YubiKey adds
+    fn attestation_statement(&self, slot: SlotId) -> Result<Vec<u8>> {
+        let invalid = |e: x509_cert::der::Error| Error::YubiKeyError(e.to_string());
+        if self.imported.contains(&slot) {
+            return Err(yubikey::Error::GenericError.into());
+        }
+        let key = self.keys.get(&slot).ok_or(Error::from(yubikey::Error::NotFound))?;
+        let issuer = Certificate::from_pem(ATTESTATION_CERTIFICATE).map_err(invalid)?;
+        let signer = p256::SecretKey::from_pkcs8_pem(ATTESTATION_KEY)
+            .map(p256::ecdsa::SigningKey::from)
+            .map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        let extension = |oid, value: &[u8]| -> Result<Extension> {
+            Ok(Extension {
+                extn_id: oid,
+                critical: false,
+                extn_value: OctetString::new(value).map_err(invalid)?,
+            })
+        };
+        let (major, minor, patch) = self.version;
+        let algorithm = AlgorithmIdentifierOwned {
+            oid: ECDSA_WITH_SHA256,
+            parameters: None,
+        };
+        let tbs_certificate = TbsCertificate {
+            version: Version::V3,
+            serial_number: SerialNumber::new(&[u8::from(slot)]).map_err(invalid)?,
+            signature: algorithm.clone(),
+            issuer: issuer.tbs_certificate.subject.clone(),
+            validity: issuer.tbs_certificate.validity,
+            subject: Name::from_str(&format!("CN=YubiKey PIV Attestation {:x}", u8::from(slot))).map_err(invalid)?,
+            subject_public_key_info: SubjectPublicKeyInfoOwned::from_pem(&key.public_key_pem()?).map_err(invalid)?,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(vec![
+                extension(OID_FIRMWARE, &[major, minor, patch])?,
+                extension(OID_SERIAL, &self.serial.to_der().map_err(invalid)?)?,
+                extension(OID_POLICY, &ATTESTED_POLICY)?,
+            ]),
+        };
+        let signature: p256::ecdsa::Signature =
+            p256::ecdsa::signature::Signer::sign(&signer, &tbs_certificate.to_der().map_err(invalid)?);
+        Certificate {
+            tbs_certificate,
+            signature_algorithm: algorithm,
+            signature: BitString::from_bytes(signature.to_der().as_bytes()).map_err(invalid)?,
+        }
+        .to_der()
+        .map_err(invalid)
+    }
+
+    fn key(&self, slot: SlotId, algorithm: AlgorithmId) -> Result<&SlotKey> {
+        match self.keys.get(&slot) {
+            Some(key) if key.algorithm() == algorithm => Ok(key),
+            Some(_) => Err(yubikey::Error::AlgorithmError.into()),
+            None => Err(yubikey::Error::NotFound.into()),
+        }
+    }
+}
+
+/// Cards are shared between sessions so PIN retries carry over, like on a real key.
+#[derive(Clone, Default)]
+pub struct VirtualBackend {
+    cards: Arc<Mutex<Vec<Arc<Mutex<VirtualCard>>>>>,
+}
+
+impl VirtualBackend {
+    pub fn insert(&self, card: VirtualCard) {
+        if let Ok(mut cards) = self.cards.lock() {
+            cards.push(Arc::new(Mutex::new(card)));
+        }
+    }
+
+    /// PIN tries left before the card blocks.
+    pub fn pin_retries(&self, serial: u32) -> Result<u8> {
+        let session = self.session(serial)?;
+        let retries = lock(&session.card)?.pin_retries;
+        Ok(retries)
+    }
+
+    fn session(&self, serial: u32) -> Result<Session> {
+        let cards = self.cards.lock().map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        for card in cards.iter() {
+            if lock(card)?.serial == serial {
+                return Ok(Session::new(card.clone()));
+            }
+        }
+        Err(Error::YubiKeyError(format!("Failed to open YubiKey {}: {}", serial, yubikey::Error::NotFound)))
+    }
+}
+
+impl PivBackend for VirtualBackend {
+    fn list(&self) -> Result<Vec<Box<dyn PivCard>>> {
+        let cards = self.cards.lock().map_err(|e| Error::YubiKeyError(e.to_string()))?;
+        Ok(cards
+            .iter()
+            .map(|card| Box::new(Session::new(card.clone())) as Box<dyn PivCard>)
+            .collect())
+    }
+
+    fn open(&self, serial: u32) -> Result<Box<dyn PivCard>> {
+        Ok(Box::new(self.session(serial)?))
+    }
+
+    fn attestation_roots(&self) -> Result<TrustAnchors> {
+        TrustAnchors::from_pem(ATTESTATION_ROOT)
+    }
+}
+
+fn lock(card: &Mutex<VirtualCard>) -> Result<MutexGuard<'_, VirtualCard>> {
+    card.lock().map_err(|e| Error::YubiKeyError(e.to_string()))
+}
+
+// An open connection, the verified PIN only lasts as long as the session
+struct Session {
+    card: Arc<Mutex<VirtualCard>>,
+    serial: u32,
+    pin_verified: bool,
+    management_authenticated: bool,
+}
+
+impl Session {
+    fn new(card: Arc<Mutex<VirtualCard>>) -> Self {
+        let serial = card.lock().map(|card| card.serial).unwrap_or_default();
+        Self {
+            card,
+            serial,
+            pin_verified: false,
+            management_authenticated: false,
+        }
+    }
+
+    fn authorize(&self) -> Result<()> {
+        if self.pin_verified {
+            Ok(())
+        } else {
+            Err(yubikey::Error::AuthenticationError.into())
+        }
+    }
+
+    fn authorize_management(&self) -> Result<()> {
+        if self.management_authenticated {
+            Ok(())
+        } else {
+            Err(yubikey::Error::AuthenticationError.into())
+        }
+    }
+}
+
+impl PivCard for Session {
+    fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    fn name(&self) -> String {
+        format!("YubiKey {}", self.serial)
+    }
+
+    fn version(&self) -> String {
+        let (major, minor, patch) = lock(&self.card).map(|card| card.version).unwrap_or_default();
+        format!("{}.{}.{}", major, minor, patch)
+    }
+
+    fn metadata(&mut self, slot: SlotId) -> Result<Option<AlgorithmId>> {
+        Ok(lock(&self.card)?.keys.get(&slot).map(SlotKey::algorithm))
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<String> {
+        let card = lock(&self.card)?;
+        let key = card.keys.get(&slot).filter(|_| card.certificates.contains(&slot)).ok_or_else(|| {
+            Error::YubiKeyError(format!(
+                "Failed to get certificate from slot {:?}: {}",
+                slot,
+                yubikey::Error::NotFound
+            ))
+        })?;
+        key.public_key_pem()
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
+        let mut card = lock(&self.card)?;
+        if card.pin_retries == 0 {
+            return Err(yubikey::Error::PinLocked.into());
+        }
+        if pin != card.pin.as_bytes() {
+            card.pin_retries -= 1;
+            self.pin_verified = false;
+            return Err(yubikey::Error::WrongPin { tries: card.pin_retries }.into());
+        }
+        card.pin_retries = PIN_RETRIES;
+        self.pin_verified = true;
+        Ok(())
+    }
+
+    fn decrypt(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>> {
+        self.authorize()?;
+        lock(&self.card)?.key(slot, algorithm)?.decrypt(input)
+    }
+
+    fn sign(&mut self, input: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Vec<u8>> {
+        self.authorize()?;
+        lock(&self.card)?.key(slot, algorithm)?.sign(input)
+    }
+
+    fn authenticate_management(&mut self, key: &[u8]) -> Result<()> {
+        self.management_authenticated = lock(&self.card)?.management_key == key;
+        self.authorize_management()
+    }
+
+    fn generate(&mut self, slot: SlotId, algorithm: AlgorithmId) -> Result<String> {
+        self.authorize_management()?;
+        let key = SlotKey::generate(algorithm)?;
+        let pem = key.public_key_pem()?;
+        let mut card = lock(&self.card)?;
+        card.keys.insert(slot, key);
+        // The old certificate is for the replaced key
+        card.certificates.remove(&slot);
+        card.imported.remove(&slot);
+        Ok(pem)
+    }
+
+    fn write_self_signed_certificate(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        public_key: &str,
+        _subject: &str,
+    ) -> Result<()> {
+        self.authorize_management()?;
+        self.authorize()?;
+        let mut card = lock(&self.card)?;
+        if card.key(slot, algorithm)?.public_key_pem()? != public_key {
+            return Err(Error::YubiKeyError("Certificate public key does not match the slot".to_string()));
+        }
+        card.certificates.insert(slot);
+        Ok(())
+    }
+
+    fn pin_retries(&mut self) -> Result<u8> {
+        Ok(lock(&self.card)?.pin_retries)
+    }
+
+    fn puk_retries(&mut self) -> Result<Option<u8>> {
+        Ok(Some(lock(&self.card)?.puk_retries))
+    }
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()> {
+        self.verify_pin(current_pin)?;
+        lock(&self.card)?.pin = String::from_utf8_lossy(new_pin).to_string();
+        Ok(())
+    }
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()> {
+        let mut card = lock(&self.card)?;
+        card.check_puk(puk)?;
+        card.pin = String::from_utf8_lossy(new_pin).to_string();
+        card.pin_retries = PIN_RETRIES;
+        Ok(())
+    }
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()> {
+        let mut card = lock(&self.card)?;
+        card.check_puk(current_puk)?;
+        card.puk = String::from_utf8_lossy(new_puk).to_string();
+        Ok(())
+    }
+
+    fn set_management_key(&mut self, new_key: &[u8]) -> Result<()> {
+        self.authorize_management()?;
+        if new_key.len() != DEFAULT_MANAGEMENT_KEY.len() {
+            return Err(yubikey::Error::SizeError.into());
+        }
+        lock(&self.card)?.management_key = new_key.to_vec();
+        Ok(())
+    }
+
+    fn can_move_keys(&self) -> bool {
+        lock(&self.card).map(|card| card.version >= (5, 7, 0)).unwrap_or(false)
+    }
+
+    fn move_key(&mut self, from: SlotId, to: SlotId) -> Result<()> {
+        self.authorize_management()?;
+        if !self.can_move_keys() {
+            return Err(yubikey::Error::NotSupported.into());
+        }
+        let mut card = lock(&self.card)?;
+        let key = card.keys.remove(&from).ok_or(Error::from(yubikey::Error::NotFound))?;
+        card.keys.insert(to, key);
+        if card.certificates.remove(&from) {
+            card.certificates.insert(to);
+        }
+        if card.imported.remove(&from) {
+            card.imported.insert(to);
+        }
+        Ok(())
+    }
+
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        lock(&self.card)?.attestation_statement(slot)
+    }
+
+    fn attestation_certificate(&mut self) -> Result<Vec<u8>> {
+        let invalid = |e: x509_cert::der::Error| Error::YubiKeyError(e.to_string());
+        Certificate::from_pem(ATTESTATION_CERTIFICATE).map_err(invalid)?.to_der().map_err(invalid)
+    }
+
+    fn resident_key(&mut self, _slot: SlotId) -> Result<Option<bool>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::{Encrypt, Keyring};
+    use crate::vaults::Vault;
+    use crate::yubikey::{enroll, list_yubikeys, YubiKeyDevice, YubiKeySettings};
+    use crate::AppState;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use p256::ecdsa::signature::Verifier;
+    use rsa::pkcs8::{DecodePublicKey, EncodePrivateKey};
+    use sha2::{Digest, Sha256};
+
+    const SERIAL: u32 = AppState::TEST_YUBIKEY_SERIAL;
+
+    fn fixture_card() -> VirtualBackend {
+        let backend = VirtualBackend::default();
+        backend.insert(VirtualCard::from_fixtures(SERIAL, "tests/fixtures/piv").unwrap());
+        backend
+    }
+
+    fn pem(key: &impl EncodePrivateKey) -> String {
+        key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_list_yubikeys() {
+        let backend = fixture_card();
+        let keys = list_yubikeys(&backend).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].serial, Some(SERIAL));
+        let pub_key = keys[0].pub_key.as_deref().unwrap();
+        assert!(pub_key.starts_with("-----BEGIN PUBLIC KEY-----"));
+        assert!(RsaPublicKey::from_public_key_pem(pub_key).is_ok());
+        assert!(keys[0].generated_on_device);
+        assert_eq!(keys[0].pin_policy, Some(crate::yubikey::PinPolicy::Once));
+    }
+
+    #[test]
+    fn test_open_unknown_serial() {
+        assert!(YubiKeyDevice::open(&fixture_card(), 1).is_err());
+    }
+
+    #[test]
+    fn test_rsa_decrypt_round_trip() {
+        let backend = fixture_card();
+        let mut device = YubiKeyDevice::open(&backend, SERIAL).unwrap();
+        let data = vec![42u8; 4096];
+        let encrypted = device.encrypt_data(data.clone()).unwrap();
+        assert_eq!(device.decrypt_data(DEFAULT_PIN.to_string(), encrypted.into_bytes()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_ecc_decrypt_round_trip() {
+        for pem in [pem(&p256::SecretKey::random(&mut OsRng)), pem(&p384::SecretKey::random(&mut OsRng))] {
+            let backend = VirtualBackend::default();
+            backend.insert(VirtualCard::new(SERIAL).with_key(SlotId::KeyManagement, &pem).unwrap());
+            let device = YubiKeyDevice::open(&backend, SERIAL).unwrap().with_pin(DEFAULT_PIN);
+            let cipher: &dyn Encrypt = &device;
+            let encrypted = cipher.encrypt("ecc secret").unwrap();
+            assert_eq!(cipher.decrypt(&encrypted).unwrap(), "ecc secret");
+        }
+    }
+
+    #[test]
+    fn test_decrypt_needs_the_pin() {
+        let backend = fixture_card();
+        let mut card = PivBackend::open(&backend, SERIAL).unwrap();
+        let block = vec![1u8; 256];
+        assert!(card.decrypt(&block, AlgorithmId::Rsa2048, SlotId::KeyManagement).is_err());
+        card.verify_pin(DEFAULT_PIN.as_bytes()).unwrap();
+        assert!(card.decrypt(&block, AlgorithmId::Rsa2048, SlotId::KeyManagement).is_ok());
+        // Input sizes are checked like on the card
+        assert!(card.decrypt(&block[..32], AlgorithmId::Rsa2048, SlotId::KeyManagement).is_err());
+        // A new session starts without the PIN
+        let mut card = PivBackend::open(&backend, SERIAL).unwrap();
+        assert!(card.decrypt(&block, AlgorithmId::Rsa2048, SlotId::KeyManagement).is_err());
+    }
+
+    #[test]
+    fn test_wrong_pin_blocks_the_card() {
+        let backend = fixture_card();
+        let mut device = YubiKeyDevice::open(&backend, SERIAL).unwrap();
+        let encrypted = device.encrypt_data(b"data".to_vec()).unwrap();
+
+        let wrong = device.decrypt_data("000000".to_string(), encrypted.clone().into_bytes());
+        assert!(matches!(wrong, Err(crate::Error::WrongPin(tries)) if tries == PIN_RETRIES - 1));
+        assert_eq!(backend.pin_retries(SERIAL).unwrap(), PIN_RETRIES - 1);
+        // The right PIN resets the counter
+        device.decrypt_data(DEFAULT_PIN.to_string(), encrypted.clone().into_bytes()).unwrap();
+        assert_eq!(backend.pin_retries(SERIAL).unwrap(), PIN_RETRIES);
+
+        for _ in 0..PIN_RETRIES {
+            assert!(device.decrypt_data("000000".to_string(), encrypted.clone().into_bytes()).is_err());
+        }
+        assert_eq!(backend.pin_retries(SERIAL).unwrap(), 0);
+        let blocked = device.decrypt_data(DEFAULT_PIN.to_string(), encrypted.into_bytes());
+        assert!(matches!(blocked, Err(crate::Error::PinBlocked(_))));
+    }
+
+    #[test]
+    fn test_authenticate_ecc() {
+        let backend = fixture_card();
+        let mut device = YubiKeyDevice::open(&backend, SERIAL).unwrap();
+        let challenge = device.generate_authentication_challenge().unwrap();
+        let signature = device.authenticate(DEFAULT_PIN.to_string(), &challenge).unwrap();
+
+        let public_pem = PivBackend::open(&backend, SERIAL).unwrap().read_certificate(SlotId::Authentication).unwrap();
+        let verifying_key = p256::ecdsa::VerifyingKey::from_public_key_pem(&public_pem).unwrap();
+        let signature = p256::ecdsa::Signature::from_der(&BASE64.decode(signature).unwrap()).unwrap();
+        use p256::ecdsa::signature::hazmat::PrehashVerifier;
+        verifying_key.verify_prehash(&BASE64.decode(challenge).unwrap(), &signature).unwrap();
+    }
+
+    #[test]
+    fn test_authenticate_rsa() {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let backend = VirtualBackend::default();
+        backend.insert(VirtualCard::new(SERIAL).with_key(SlotId::Authentication, &pem(&key)).unwrap());
+        let mut device = YubiKeyDevice::open(&backend, SERIAL).unwrap();
+        let challenge = device.generate_authentication_challenge().unwrap();
+        let signature = device.authenticate(DEFAULT_PIN.to_string(), &challenge).unwrap();
+
+        RsaPublicKey::from(&key)
+            .verify(
+                rsa::Pkcs1v15Sign::new::<Sha256>(),
+                &BASE64.decode(challenge).unwrap(),
+                &BASE64.decode(signature).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_authenticate_wrong_pin() {
+        let backend = fixture_card();
+        let mut device = YubiKeyDevice::open(&backend, SERIAL).unwrap();
+        let challenge = device.generate_authentication_challenge().unwrap();
+        let result = device.authenticate("000000".to_string(), &challenge);
+        assert!(matches!(result, Err(crate::Error::WrongPin(2))));
+    }
+
+    #[test]
+    fn test_sign_verifies_with_p256() {
+        let backend = fixture_card();
+        let mut card = PivBackend::open(&backend, SERIAL).unwrap();
+        card.verify_pin(DEFAULT_PIN.as_bytes()).unwrap();
+        let digest = Sha256::digest(b"message");
+        let der = card.sign(&digest, AlgorithmId::EccP256, SlotId::Authentication).unwrap();
+        let public_pem = card.read_certificate(SlotId::Authentication).unwrap();
+        let verifying_key = p256::ecdsa::VerifyingKey::from_public_key_pem(&public_pem).unwrap();
+        let signature = p256::ecdsa::Signature::from_der(&der).unwrap();
+        assert!(verifying_key.verify(b"message", &signature).is_ok());
+        // The slot holds a P-256 key
+        assert!(card.sign(&digest, AlgorithmId::EccP384, SlotId::Authentication).is_err());
+    }
+
+    #[test]
+    fn test_enroll_wraps_every_vault_key() {
+        let state = AppState::new_test("password");
+        Vault::create(&state, "work").unwrap();
+        let pub_key = list_yubikeys(state.piv()).unwrap()[0].pub_key.clone().unwrap();
+
+        let key = enroll(&state, SERIAL, Some("Primary".to_string()), pub_key.clone()).unwrap();
+        assert_eq!(key.pub_key, pub_key);
+        assert_eq!(key.algorithm, crate::yubikey::KeyAlgorithm::Rsa2048);
+        assert_eq!(key.attestation.as_ref().and_then(|attestation| attestation.serial), Some(SERIAL));
+        assert_eq!(YubiKeySettings::load(&state).unwrap().keys(), &[key]);
+
+        let device = YubiKeyDevice::open(state.piv(), SERIAL).unwrap().with_pin(DEFAULT_PIN);
+        for vault in Vault::all(&state).unwrap() {
+            let keyring: Keyring = vault.keyring(&state).unwrap();
+            let data_key = keyring.unlock_with_yubikey(SERIAL, &device).unwrap();
+            let sealed = vault.data_key(&state).unwrap().cipher().encrypt(b"vault data").unwrap();
+            assert_eq!(data_key.cipher().decrypt(&sealed).unwrap(), b"vault data");
+        }
+    }
+
+    #[test]
+    fn test_failed_enroll_writes_nothing() {
+        let state = AppState::new_test("password");
+        Vault::create(&state, "work").unwrap();
+        let fs = state.file_system();
+        for vault in Vault::all(&state).unwrap() {
+            vault.ensure_keyring(&state).unwrap();
+        }
+        // The default vault is done first, the work vault key can not be unwrapped
+        let work = fs.vault_keyring("work");
+        let broken = std::fs::read_to_string(&work).unwrap().replacen(r#""method": "password""#, r#""method": "rsa_master""#, 1);
+        std::fs::write(&work, broken).unwrap();
+        let files = [fs.vault_keyring(crate::vaults::DEFAULT_VAULT), work, fs.yubikey_settings()];
+        let before: Vec<_> = files.iter().map(|path| std::fs::read(path).ok()).collect();
+
+        let pub_key = list_yubikeys(state.piv()).unwrap()[0].pub_key.clone().unwrap();
+        assert!(enroll(&state, SERIAL, None, pub_key).is_err());
+        let after: Vec<_> = files.iter().map(|path| std::fs::read(path).ok()).collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_enroll_refuses_imported_keys() {
+        let state = AppState::new_test("password");
+        let pem = std::fs::read_to_string("tests/fixtures/piv/9d.pem").unwrap();
+        state.virtual_piv().insert(VirtualCard::new(20_000_002).with_imported_key(SlotId::KeyManagement, &pem).unwrap());
+        let info = list_yubikeys(state.piv()).unwrap().into_iter().find(|key| key.serial == Some(20_000_002)).unwrap();
+        assert!(!info.generated_on_device);
+        let result = enroll(&state, 20_000_002, None, info.pub_key.unwrap());
+        assert!(matches!(result, Err(crate::Error::Attestation(_))));
+    }
+
+    #[test]
+    fn test_enroll_unknown_yubikey() {
+        let state = AppState::new_test("password");
+        assert!(enroll(&state, 1, None, "pem".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_unlock_with_enrolled_yubikey() {
+        let mut state = AppState::new_test("password");
+        state.log_out();
+        // The test state enrolls the YubiKey without a copy of any vault key
+        assert!(crate::yubikey::unlock(&mut state, DEFAULT_PIN, None).is_err());
+        assert!(!state.is_yubikey_authenticated());
+
+        crate::MasterPassword::verify(&mut state, "password").unwrap();
+        let pub_key = list_yubikeys(state.piv()).unwrap()[0].pub_key.clone().unwrap();
+        enroll(&state, SERIAL, None, pub_key).unwrap();
+        state.log_out();
+        assert!(crate::yubikey::unlock(&mut state, "000000", None).is_err());
+        assert!(!state.is_authenticated());
+
+        // No vault opens with the YubiKey alone, the master password is still needed
+        crate::yubikey::unlock(&mut state, DEFAULT_PIN, None).unwrap();
+        assert!(!state.is_authenticated());
+        assert!(state.is_yubikey_authenticated());
+        assert!(!state.has_master_key());
+    }
+
+    #[test]
+    fn test_unlock_sends_the_pin_to_one_card() {
+        const SECOND: u32 = 10_000_002;
+        let mut state = AppState::new_test("password");
+        state.virtual_piv().insert(VirtualCard::from_fixtures(SECOND, "tests/fixtures/piv").unwrap());
+        crate::yubikey::change_pin(state.piv(), SECOND, DEFAULT_PIN, "654321").unwrap();
+        for serial in [SERIAL, SECOND] {
+            let info = list_yubikeys(state.piv()).unwrap().into_iter().find(|key| key.serial == Some(serial)).unwrap();
+            enroll(&state, serial, None, info.pub_key.unwrap()).unwrap();
+        }
+        state.log_out();
+
+        // The chosen card gets the PIN, the other one keeps its retries
+        crate::yubikey::unlock(&mut state, "654321", Some(SECOND)).unwrap();
+        assert!(state.is_yubikey_authenticated());
+        assert_eq!(state.virtual_piv().pin_retries(SERIAL).unwrap(), PIN_RETRIES);
+        state.log_out();
+        // Without a choice a wrong PIN stops at the first card
+        assert!(matches!(crate::yubikey::unlock(&mut state, "654321", None), Err(crate::Error::WrongPin(_))));
+        assert_eq!(state.virtual_piv().pin_retries(SECOND).unwrap(), PIN_RETRIES);
+        crate::yubikey::unlock(&mut state, DEFAULT_PIN, None).unwrap();
+        state.log_out();
+
+        // A card that can not vouch for its key is skipped without the PIN
+        let path = state.file_system().yubikey_settings();
+        let mut value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        value["payload"]["anchors"].as_object_mut().unwrap().remove(&SERIAL.to_string());
+        std::fs::write(&path, value.to_string()).unwrap();
+        crate::yubikey::unlock(&mut state, "654321", None).unwrap();
+        assert!(state.is_yubikey_authenticated());
+    }
+
+    #[test]
+    fn test_unlock_rejects_another_card() {
+        let mut state = AppState::new_test("password");
+        // Settings pointing at an authentication key the card does not hold
+        let mut settings = YubiKeySettings::load(&state).unwrap();
+        let mut key = settings.find(SERIAL).unwrap().clone();
+        let other = p256::SecretKey::random(&mut OsRng).public_key();
+        key.auth_pub_key = Some(other.to_public_key_pem(LineEnding::LF).unwrap());
+        settings.add(key);
+        settings.save(&state).unwrap();
+        state.log_out();
+
+        let result = crate::yubikey::unlock(&mut state, DEFAULT_PIN, None);
+        assert!(matches!(result, Err(crate::Error::YubiKeyError(msg)) if msg.contains("not signed by the enrolled YubiKey")));
+        assert!(!state.is_yubikey_authenticated());
+    }
+}
@@ -1,4 +1,5 @@
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 
 interface AppStateType {
     authenticated: boolean;
@@ -10,6 +11,10 @@ class AppStateManager {
     });
 
     async initialize(): Promise<void> {
+        // The backend locks idle or expired sessions on its own
+        await listen('vault-locked', () => {
+            this.state.authenticated = false;
+        });
         await this.refreshAuthState();
     }
 
@@ -0,0 +1,17 @@
+// Throttling errors from verify_master_password, e.g. `{ TooManyAttempts: 40 }`
+export function lockoutMessage(error: unknown): string | null {
+  if (typeof error !== "object" || error === null) {
+    return null;
+  }
+  const e = error as Record<string, unknown>;
+  if ("TooManyAttempts" in e) {
+    const seconds = Number(e.TooManyAttempts);
+    return seconds < 60
+      ? `Too many wrong passwords, try again in ${seconds} seconds`
+      : `Too many wrong passwords, try again in ${Math.ceil(seconds / 60)} minutes`;
+  }
+  if ("YubiKeyRequired" in e) {
+    return "Too many wrong passwords, unlock with the YubiKey first";
+  }
+  return null;
+}
@@ -0,0 +1,19 @@
+// PIN errors from the YubiKey commands, e.g. `{ WrongPin: 2 }`
+export function pinErrorMessage(error: unknown): string | null {
+  if (typeof error !== "object" || error === null) {
+    return null;
+  }
+  const e = error as Record<string, unknown>;
+  if ("WrongPin" in e) {
+    return e.WrongPin === 1
+      ? "Wrong PIN, 1 try left before the YubiKey blocks the PIN"
+      : `Wrong PIN, ${e.WrongPin} tries left`;
+  }
+  if ("WrongPuk" in e) {
+    return `Wrong PUK, ${e.WrongPuk} tries left`;
+  }
+  if ("PinBlocked" in e) {
+    return `${e.PinBlocked}`;
+  }
+  return null;
+}
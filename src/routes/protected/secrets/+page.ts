@@ -1,7 +1,7 @@
 import { invoke } from '@tauri-apps/api/core';
 export async function load() {
   try {
-    const secrets = await invoke('get_secrets');
+    const secrets = await invoke('get_secrets', { vault: 'default' });
     return { secrets };
   } catch (e) { 
     console.error(e); 
@@ -1,5 +1,5 @@
 import { invoke } from '@tauri-apps/api/core';
 export async function load({params}) {
   console.log(params);
-  return await invoke('get_secret', params);
+  return await invoke('get_secret', { vault: 'default', ...params });
 } 
\ No newline at end of file